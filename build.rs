@@ -1,6 +1,10 @@
-use anyhow::bail;
+use anyhow::{bail, Context};
+#[cfg(windows)]
 use eiz::com::ComPtr;
-use std::{env, ffi::OsStr, fs, os::windows::prelude::OsStrExt, path::Path, ptr};
+use std::fs;
+#[cfg(windows)]
+use std::{env, ffi::OsStr, os::windows::prelude::OsStrExt, path::Path, ptr};
+#[cfg(windows)]
 use winapi::um::{
     d3dcommon::ID3DBlob,
     d3dcompiler::{
@@ -9,6 +13,7 @@ use winapi::um::{
     },
 };
 
+#[cfg(windows)]
 fn osstr_to_wide<S: AsRef<OsStr>>(str: S) -> Vec<u16> {
     str.as_ref()
         .encode_wide()
@@ -16,6 +21,7 @@ fn osstr_to_wide<S: AsRef<OsStr>>(str: S) -> Vec<u16> {
         .collect::<Vec<u16>>()
 }
 
+#[cfg(windows)]
 fn compile_shader<P: AsRef<Path>>(path: P, target: &str, entry: &str) -> anyhow::Result<()> {
     let path = path.as_ref();
     let target = format!["{}\0", target];
@@ -89,15 +95,322 @@ fn compile_shader<P: AsRef<Path>>(path: P, target: &str, entry: &str) -> anyhow:
     Ok(())
 }
 
+// HLSL equivalents of the Rust types `gpu_struct!` accepts; matches the
+// `HlslType` impls in src/layout.rs.
+const RUST_TO_HLSL: &[(&str, &str)] = &[
+    ("f32", "float"),
+    ("u32", "uint"),
+    ("i32", "int"),
+    ("Vec2", "float2"),
+    ("Vec4", "float4"),
+];
+
+// A cbuffer or struct declaration, from either side.
+struct Declaration {
+    kind: String,
+    name: String,
+    // (type, name); the type is the HLSL one on both sides.
+    fields: Vec<(String, String)>,
+    source: String,
+}
+
+// Drops `//` and `/* */` comments. Block comments become a space, keeping
+// their line breaks.
+fn strip_comments(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(slash) = rest.find('/') {
+        stripped.push_str(&rest[..slash]);
+        rest = &rest[slash..];
+
+        if rest.starts_with("//") {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if rest.starts_with("/*") {
+            let end = rest.find("*/").map_or(rest.len(), |i| i + 2);
+            stripped.push(' ');
+            stripped.extend(rest[..end].chars().filter(|&c| c == '\n'));
+            rest = &rest[end..];
+        } else {
+            stripped.push('/');
+            rest = &rest[1..];
+        }
+    }
+
+    stripped.push_str(rest);
+    stripped
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn next_ident(text: &str) -> &str {
+    let text = text.trim_start();
+    &text[..text.find(|c| !is_ident(c)).unwrap_or(text.len())]
+}
+
+// The text between the first `{` after `start` and its matching `}`, and
+// where that `}` is.
+fn braced(text: &str, start: usize) -> anyhow::Result<(&str, usize)> {
+    let open = start + text[start..].find('{').context("expected `{`")?;
+    let mut depth = 0;
+
+    for (i, c) in text[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => continue,
+        }
+
+        if depth == 0 {
+            return Ok((&text[open + 1..open + i], open + i));
+        }
+    }
+
+    bail!["unbalanced `{{`"]
+}
+
+// Positions of `word` that aren't part of a longer identifier.
+fn find_word<'a>(text: &'a str, word: &'a str) -> impl Iterator<Item = usize> + 'a {
+    text.match_indices(word).map(|(i, _)| i).filter(move |&i| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+    })
+}
+
+fn hlsl_declarations(path: &str) -> anyhow::Result<Vec<Declaration>> {
+    let text = strip_comments(&fs::read_to_string(path)?);
+    let mut declarations = vec![];
+
+    for kind in ["cbuffer", "struct"] {
+        for start in find_word(&text, kind) {
+            let name = next_ident(&text[start + kind.len()..]).to_string();
+            let (body, _) = braced(&text, start)
+                .with_context(|| format!["in {} {} in {}", kind, name, path])?;
+            let fields = body
+                .split(';')
+                .map(str::trim)
+                .filter(|decl| !decl.is_empty())
+                .map(|decl| {
+                    let words: Vec<&str> = decl.split_whitespace().collect();
+
+                    match words[..] {
+                        [ty, field] if field.chars().all(is_ident) => {
+                            Ok((ty.to_string(), field.to_string()))
+                        }
+                        _ => bail![
+                            "unsupported declaration {:?} in {} {} in {}",
+                            decl,
+                            kind,
+                            name,
+                            path
+                        ],
+                    }
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            declarations.push(Declaration {
+                kind: kind.to_string(),
+                name,
+                fields,
+                source: path.to_string(),
+            });
+        }
+    }
+
+    Ok(declarations)
+}
+
+fn rust_declarations(path: &str) -> anyhow::Result<Vec<Declaration>> {
+    let text = strip_comments(&fs::read_to_string(path)?);
+    let mut declarations = vec![];
+
+    for start in text.match_indices("gpu_struct! {").map(|(i, _)| i) {
+        let (block, _) = braced(&text, start)?;
+        let header = block.trim_start().strip_prefix("hlsl").with_context(|| {
+            format![
+                "gpu_struct! in {} should start with `hlsl cbuffer NAME;` or `hlsl struct;`",
+                path
+            ]
+        })?;
+        let (header, rest) = header.split_once(';').context("expected `;`")?;
+        let struct_start = find_word(rest, "struct")
+            .next()
+            .context("expected `struct`")?;
+        let name = next_ident(&rest[struct_start + "struct".len()..]).to_string();
+        let (kind, hlsl_name) = match header.split_whitespace().collect::<Vec<_>>()[..] {
+            ["cbuffer", hlsl_name] => ("cbuffer", hlsl_name.to_string()),
+            ["struct"] => ("struct", name.clone()),
+            _ => bail![
+                "unexpected gpu_struct! header {:?} in {}",
+                header.trim(),
+                path
+            ],
+        };
+        let (body, _) = braced(rest, struct_start)?;
+        let fields = body
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(|field| {
+                let (field_name, ty) = field.split_once(':').with_context(|| {
+                    format!["malformed field {:?} of {} in {}", field, name, path]
+                })?;
+                let field_name = field_name.split_whitespace().last().unwrap_or("");
+                let ty = ty.trim();
+                let hlsl_ty = RUST_TO_HLSL
+                    .iter()
+                    .find(|(rust, _)| *rust == ty)
+                    .map(|(_, hlsl)| hlsl.to_string())
+                    .with_context(|| {
+                        format![
+                            "{}::{} has type {} with no HLSL equivalent",
+                            name, field_name, ty
+                        ]
+                    })?;
+                Ok((hlsl_ty, field_name.to_string()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        declarations.push(Declaration {
+            kind: kind.to_string(),
+            name: hlsl_name,
+            fields,
+            source: format!["{} in {}", name, path],
+        });
+    }
+
+    Ok(declarations)
+}
+
+// Sizes of the scalar and vector types.
+fn hlsl_size(ty: &str) -> Option<usize> {
+    let components = match ty.trim_start_matches(|c: char| c.is_ascii_alphabetic()) {
+        "" => 1,
+        n => n.parse().ok().filter(|n| (1..=4).contains(n))?,
+    };
+
+    match ty.trim_end_matches(|c: char| c.is_ascii_digit()) {
+        "float" | "int" | "uint" => Some(4 * components),
+        _ => None,
+    }
+}
+
+// Field offsets under the packing rules `gpu_struct!` checks the Rust side
+// against: constant buffer fields don't straddle 16-byte registers,
+// structured buffer elements are packed tightly.
+fn offsets(declaration: &Declaration) -> anyhow::Result<Vec<usize>> {
+    let mut end = 0;
+
+    declaration
+        .fields
+        .iter()
+        .map(|(ty, name)| {
+            let size = hlsl_size(ty).with_context(|| {
+                format![
+                    "unsupported type {} of {}::{} in {}",
+                    ty, declaration.name, name, declaration.source
+                ]
+            })?;
+            let offset = if declaration.kind == "cbuffer" && end / 16 != (end + size - 1) / 16 {
+                end.div_ceil(16) * 16
+            } else {
+                end
+            };
+            end = offset + size;
+            Ok(offset)
+        })
+        .collect()
+}
+
+// Fails the build when a struct declared with `gpu_struct!` doesn't match
+// its HLSL declaration field for field.
+fn check_gpu_structs() -> anyhow::Result<()> {
+    let mut hlsl = vec![];
+    let mut rust = vec![];
+    let mut errors = vec![];
+
+    for path in [
+        "shader/slime.hlsl",
+        "shader/scrgb_to_hdr10.hlsl",
+        "shader/common.inc",
+    ] {
+        hlsl.extend(hlsl_declarations(path)?);
+    }
+
+    for entry in fs::read_dir("src")? {
+        let path = entry?.path();
+
+        if path.extension().is_some_and(|e| e == "rs") {
+            rust.extend(rust_declarations(&path.to_string_lossy())?);
+        }
+    }
+
+    for declaration in &rust {
+        let shader = match hlsl
+            .iter()
+            .find(|d| d.kind == declaration.kind && d.name == declaration.name)
+        {
+            Some(shader) => shader,
+            None => {
+                errors.push(format![
+                    "{} has no {} {} in the shaders",
+                    declaration.source, declaration.kind, declaration.name
+                ]);
+                continue;
+            }
+        };
+        let rust_offsets = offsets(declaration)?;
+        let hlsl_offsets = offsets(shader)?;
+
+        for i in 0..declaration.fields.len().max(shader.fields.len()) {
+            let describe = |fields: &[(String, String)], offsets: &[usize]| match fields.get(i) {
+                Some((ty, name)) => format!["{} {} at offset {}", ty, name, offsets[i]],
+                None => "nothing".to_string(),
+            };
+            let rust_field = describe(&declaration.fields, &rust_offsets);
+            let hlsl_field = describe(&shader.fields, &hlsl_offsets);
+
+            if rust_field != hlsl_field {
+                errors.push(format![
+                    "{}: field {} is {} in Rust but {} in {}",
+                    declaration.source, i, rust_field, hlsl_field, shader.source
+                ]);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail![
+            "GPU struct layouts don't match the shaders:\n{}",
+            errors.join("\n")
+        ];
+    }
+
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
-    compile_shader("shader/slime.hlsl", "cs_5_0", "advance_agents")?;
-    compile_shader("shader/slime.hlsl", "cs_5_0", "decay_and_diffuse")?;
-    compile_shader("shader/scrgb_to_hdr10.hlsl", "cs_5_0", "convert")?;
+    check_gpu_structs()?;
+
+    // The compute shaders are only used by the Direct3D 11 backend.
+    #[cfg(windows)]
+    {
+        compile_shader("shader/slime.hlsl", "cs_5_0", "advance_agents")?;
+        compile_shader("shader/slime.hlsl", "cs_5_0", "resolve_deposits")?;
+        compile_shader("shader/slime.hlsl", "cs_5_0", "decay_and_diffuse")?;
+        compile_shader("shader/slime.hlsl", "cs_5_0", "compose")?;
+        compile_shader("shader/slime.hlsl", "cs_5_0", "move_agents")?;
+        compile_shader("shader/scrgb_to_hdr10.hlsl", "cs_5_0", "convert")?;
+    }
 
     let build_files = &[
         "shader/common.inc",
-        "scrgb_to_hdr10.hlsl",
+        "shader/scrgb_to_hdr10.hlsl",
         "shader/slime.hlsl",
+        "src",
     ];
 
     for file in build_files {
@@ -9,6 +9,9 @@ cbuffer SETTINGS : register(b0) {
     float sensor_angle_rad;
     float sensor_offset;
     int sensor_size;
+    uint num_channels;
+    uint interaction_mode;
+    uint boundary;
     float4 agent_color;
     float same_color_weight;
     float different_color_weight;
@@ -22,34 +25,207 @@ cbuffer SETTINGS : register(b0) {
     // Time
     float time;
     float delta_time;
+    float dish_radius;
+    float obstacle_weight;
+    float nutrient_weight;
+    float4 nutrient_color;
+    float nutrient_consume_rate;
+    float nutrient_regrow_rate;
+    float nutrient_diffuse_rate;
+    uint population;
+    float move_cost;
+    float trail_gain;
+    float nutrient_gain;
+    uint diffusion;
+    uint kernel_radius;
+    uint max_substeps;
+    uint step;
+    uint steering;
+    float4 steering_params;
+    uint sampling;
+    uint deposition;
+    float deposit_scale;
 }
 
+// Per-pass parameters of decay_and_diffuse.
+cbuffer PASS : register(b1) {
+    uint pass_index;
+    uint pass_count;
+}
+
+// A push or pull stroke of move_agents; a negative step pulls.
+cbuffer BRUSH : register(b2) {
+    float2 brush_center;
+    float brush_radius;
+    float brush_step;
+}
+
+#define INTERACTION_COLOR 0
+#define INTERACTION_MATRIX 1
+
+#define BOUNDARY_TORUS 0
+#define BOUNDARY_REFLECT 1
+#define BOUNDARY_ABSORB 2
+#define BOUNDARY_DISH 3
+
+#define DIFFUSION_BOX 0
+#define DIFFUSION_SUBSTEP 1
+#define DIFFUSION_GAUSSIAN 2
+#define DIFFUSION_ANISOTROPIC 3
+
+#define SAMPLING_NEAREST 0
+#define SAMPLING_ACCURATE 1
+
+#define DEPOSITION_DIRECT 0
+#define DEPOSITION_DETERMINISTIC 1
+
+#define STEERING_SENSORS 0
+#define STEERING_PROBABILISTIC 1
+#define STEERING_GRADIENT 2
+#define STEERING_RUN_AND_TUMBLE 3
+#define STEERING_LEVY 4
+
+#define PI 3.14159265358979
+
 // Data
-RWTexture2D<float4> trail: register(u0);
-RWTexture2D<float4> diffused_trail: register(u1);
+//
+// The trail field holds `num_channels` floats per texel, row-major with the
+// channels of a texel next to each other.
+RWStructuredBuffer<float> trail: register(u0);
+RWStructuredBuffer<float> diffused_trail: register(u1);
 
 struct Agent
 {
     float4 color;
     float2 position;
     float heading;
+    uint species;
+    float energy;
+    uint rng_key;
+    float steer_state;
+    uint id;
 };
 RWStructuredBuffer<Agent> agents: register(u2);
+RWTexture2D<float4> display: register(u3);
+
+// Flags for parameters that come from the cbuffer instead of the table,
+// matching the INHERIT_* constants in src/species.rs and src/channels.rs.
+#define INHERIT_SPEED 1
+#define INHERIT_TURN_RATE 2
+#define INHERIT_SENSOR_ANGLE 4
+#define INHERIT_SENSOR_OFFSET 8
+#define INHERIT_EXPONENTIAL_DECAY 1
+#define INHERIT_LINEAR_DECAY 2
+#define INHERIT_DIFFUSE 4
+#define INHERIT_SAME_COLOR_WEIGHT 1
+#define INHERIT_DIFFERENT_COLOR_WEIGHT 2
 
-uint rand_uint(uint state)
+struct Species
 {
-    state ^= 2747636419u;
-    state *= 2654435769u;
-    state ^= state >> 16;
-    state *= 2654435769u;
-    state ^= state >> 16;
-    state *= 2654435769u;
-    return state;
+    float speed;
+    float turn_rate_rad;
+    float sensor_angle_rad;
+    float sensor_offset;
+    int sensor_size;
+    uint inherit;
+};
+StructuredBuffer<Species> species_table: register(t0);
+
+Species live_species(uint index)
+{
+    Species species = species_table[index];
+    if (species.inherit & INHERIT_SPEED)
+        species.speed = agent_speed;
+    if (species.inherit & INHERIT_TURN_RATE)
+        species.turn_rate_rad = agent_turn_rate_rad;
+    if (species.inherit & INHERIT_SENSOR_ANGLE)
+        species.sensor_angle_rad = sensor_angle_rad;
+    if (species.inherit & INHERIT_SENSOR_OFFSET)
+        species.sensor_offset = sensor_offset;
+    return species;
 }
 
-float rand_float(uint state)
+struct Channel
 {
-    return state / 4294967295.0;
+    float4 color;
+    float exponential_decay_rate;
+    float linear_decay_rate;
+    float diffuse_rate;
+    uint inherit;
+};
+StructuredBuffer<Channel> channels: register(t1);
+
+Channel live_channel(uint index)
+{
+    Channel channel = channels[index];
+    if (channel.inherit & INHERIT_EXPONENTIAL_DECAY)
+        channel.exponential_decay_rate = exponential_decay_rate;
+    if (channel.inherit & INHERIT_LINEAR_DECAY)
+        channel.linear_decay_rate = linear_decay_rate;
+    if (channel.inherit & INHERIT_DIFFUSE)
+        channel.diffuse_rate = diffuse_rate;
+    return channel;
+}
+
+struct Interaction
+{
+    float deposit;
+    float attraction;
+    uint inherit;
+};
+// species_table x channels, row-major
+StructuredBuffer<Interaction> interactions: register(t2);
+
+float attraction(uint species, uint channel)
+{
+    Interaction interaction = interactions[species * num_channels + channel];
+    if (interaction.inherit & INHERIT_SAME_COLOR_WEIGHT)
+        return same_color_weight;
+    if (interaction.inherit & INHERIT_DIFFERENT_COLOR_WEIGHT)
+        return different_color_weight;
+    return interaction.attraction;
+}
+// One entry per texel, nonzero for walls; unbound without an obstacle map.
+StructuredBuffer<uint> obstacles: register(t3);
+
+#define OBSTACLE_COLOR float4(0.25, 0.25, 0.25, 1)
+
+// One value per texel, double buffered like the trail; unbound without food.
+RWStructuredBuffer<float> nutrient: register(u4);
+RWStructuredBuffer<float> diffused_nutrient: register(u5);
+StructuredBuffer<float> nutrient_capacity: register(t4);
+// Normalized weights, 2r + 1 for the gaussian and (2r + 1)^2 row-major for
+// the anisotropic kernel.
+StructuredBuffer<float> diffusion_kernel: register(t5);
+// Fixed-point deposits of deterministic mode: one per trail value, followed
+// by one per texel for the nutrient. Cleared again by resolve_deposits.
+RWStructuredBuffer<int> deposits: register(u6);
+
+// Integer hash with good avalanche ("lowbias32" by Chris Wellons).
+uint hash(uint x)
+{
+    x ^= x >> 16;
+    x *= 0x7feb352du;
+    x ^= x >> 15;
+    x *= 0x846ca68bu;
+    x ^= x >> 16;
+    return x;
+}
+
+// Draws of agent_random within one step.
+#define STREAM_TURN 0
+#define STREAM_RESPAWN_X 1
+#define STREAM_RESPAWN_Y 2
+#define STREAM_RESPAWN_HEADING 3
+#define STREAM_STEER 4
+#define STREAM_STEER_HEADING 5
+
+// Counter-based generator: a uniform value in [0, 1) that only depends on the
+// agent's key, the step and which draw of the step this is.
+float agent_random(Agent agent, uint stream)
+{
+    uint bits = hash(agent.rng_key ^ hash(step ^ hash(stream)));
+    return (bits >> 8) / 16777216.0;
 }
 
 float mod(const float x, const float y)
@@ -62,39 +238,302 @@ float2 mod2(const float2 x, const float2 y)
     return float2(mod(x.x, y.x), mod(x.y, y.y));
 }
 
-float sense(Agent agent, float angle_offset, float sensor_offset)
+// Structured buffers are flat, so texels outside the field have to be
+// rejected here to read as zero and drop writes, like the texture they
+// replace.
+bool in_field(uint2 pos)
 {
-    float sensor_angle = agent.heading + angle_offset;
-    float2 sensor_dir;
-    sincos(sensor_angle, sensor_dir.y, sensor_dir.x);
+    return pos.x < (uint) resolution.x && pos.y < (uint) resolution.y;
+}
+
+uint trail_index(uint2 pos, uint channel)
+{
+    return (pos.y * (uint) resolution.x + pos.x) * num_channels + channel;
+}
+
+float load_trail(uint2 pos, uint channel)
+{
+    return in_field(pos) ? trail[trail_index(pos, channel)] : 0;
+}
+
+uint texel_index(uint2 pos)
+{
+    return pos.y * (uint) resolution.x + pos.x;
+}
+
+float load_nutrient(uint2 pos)
+{
+    return in_field(pos) ? nutrient[texel_index(pos)] : 0;
+}
+
+void store_nutrient(uint2 pos, float value)
+{
+    if (in_field(pos))
+        nutrient[texel_index(pos)] = value;
+}
+
+int to_fixed(float amount)
+{
+    return (int)floor(amount * deposit_scale + 0.5);
+}
+
+// Adds amount to the trail, or in deterministic mode queues it as fixed point,
+// where the sum doesn't depend on the order agents run in.
+void add_trail(uint2 pos, uint channel, float amount)
+{
+    if (!in_field(pos))
+        return;
+
+    if (deposition == DEPOSITION_DETERMINISTIC)
+        InterlockedAdd(deposits[trail_index(pos, channel)], to_fixed(amount));
+    else
+        trail[trail_index(pos, channel)] += amount;
+}
+
+void add_nutrient(uint2 pos, float amount)
+{
+    if (!in_field(pos))
+        return;
+
+    if (deposition == DEPOSITION_DETERMINISTIC)
+        InterlockedAdd(deposits[(uint) resolution.x * (uint) resolution.y * num_channels + texel_index(pos)], to_fixed(amount));
+    else
+        nutrient[texel_index(pos)] += amount;
+}
+
+float4 load_trail4(uint2 pos)
+{
+    return float4(load_trail(pos, 0), load_trail(pos, 1), load_trail(pos, 2), load_trail(pos, 3));
+}
 
+bool is_obstacle(float2 pos)
+{
+    uint2 texel = pos;
+    return all(pos >= 0) && in_field(texel) && obstacles[texel_index(texel)] != 0;
+}
+
+// Largest position still inside the field on each axis.
+float2 field_max()
+{
+    return resolution - 0.001;
+}
+
+bool in_domain(float2 pos)
+{
+    bool inside = all(pos >= 0) && all(pos < resolution);
+
+    if (boundary == BOUNDARY_DISH)
+    {
+        float2 d = pos - resolution * 0.5;
+        return inside && dot(d, d) <= dish_radius * dish_radius;
+    }
+    return inside;
+}
+
+// Brings an agent that moved from `old_position` back into the domain.
+void apply_boundary(inout Agent agent, float2 old_position)
+{
+    float2 pos = agent.position;
+
+    if (boundary == BOUNDARY_REFLECT)
+    {
+        if (pos.x < 0)
+        {
+            pos.x = -pos.x;
+            agent.heading = PI - agent.heading;
+        }
+        else if (pos.x >= resolution.x)
+        {
+            pos.x = 2 * resolution.x - pos.x;
+            agent.heading = PI - agent.heading;
+        }
+
+        if (pos.y < 0)
+        {
+            pos.y = -pos.y;
+            agent.heading = -agent.heading;
+        }
+        else if (pos.y >= resolution.y)
+        {
+            pos.y = 2 * resolution.y - pos.y;
+            agent.heading = -agent.heading;
+        }
+
+        pos = clamp(pos, 0, field_max());
+    }
+    else if (boundary == BOUNDARY_ABSORB)
+    {
+        if (!in_domain(pos))
+        {
+            pos.x = agent_random(agent, STREAM_RESPAWN_X) * resolution.x;
+            pos.y = agent_random(agent, STREAM_RESPAWN_Y) * resolution.y;
+            agent.heading = agent_random(agent, STREAM_RESPAWN_HEADING) * 2 * PI;
+            pos = clamp(pos, 0, field_max());
+        }
+    }
+    else if (boundary == BOUNDARY_DISH)
+    {
+        if (!in_domain(pos))
+        {
+            // Mirror the heading about the rim normal and stay put.
+            float2 center = resolution * 0.5;
+            float2 d = pos - center;
+            float2 normal = d * (1 / length(d));
+            float2 dir;
+            sincos(agent.heading, dir.y, dir.x);
+            float2 reflected = dir + normal * (-2 * dot(dir, normal));
+            agent.heading = atan2(reflected.y, reflected.x);
+
+            float2 back = old_position - center;
+            float scale = min(dish_radius * 0.999 / length(back), 1);
+            pos = clamp(center + back * scale, 0, field_max());
+        }
+    }
+    else
+    {
+        pos = mod2(pos, resolution);
+    }
+
+    agent.position = pos;
+}
+
+// Applies the boundary and the walls to an agent that moved from
+// `old_position`.
+void settle(inout Agent agent, float2 old_position)
+{
+    apply_boundary(agent, old_position);
+
+    // Walls turn agents around; agents that start inside one may leave.
+    if (is_obstacle(agent.position) && !is_obstacle(old_position))
+    {
+        agent.position = old_position;
+        agent.heading += PI;
+    }
+}
+
+float deposit_amount(Agent agent, uint channel)
+{
+    if (interaction_mode == INTERACTION_COLOR)
+        return channel < 4 ? agent.color[channel] : 0;
+    return interactions[agent.species * num_channels + channel].deposit;
+}
+
+// Maps a sample position into the domain, or returns false if it is outside.
+bool sensor_position(inout float2 pos)
+{
+    if (boundary == BOUNDARY_TORUS)
+        pos = mod2(pos, resolution);
+    else if (!in_domain(pos))
+        return false;
+    return true;
+}
+
+// Adds weight times what the texel at pos contributes to a sensor.
+void sense_texel(Agent agent, float2 pos, float weight, inout float sum)
+{
+    if (!sensor_position(pos))
+        return;
+
+    if (is_obstacle(pos))
+    {
+        sum += weight * obstacle_weight;
+        return;
+    }
+
+    if (interaction_mode == INTERACTION_COLOR)
+    {
+        float4 value = load_trail4(pos);
+        sum += weight * (same_color_weight * dot(value, float4(agent.color.xyz, 0)));
+        float4 inv_color = 1 - agent.color;
+        sum += weight * (different_color_weight * dot(value, inv_color));
+    }
+    else
+    {
+        for (uint c = 0; c < num_channels; c++)
+            sum += weight * (attraction(agent.species, c) * load_trail(pos, c));
+    }
+
+    sum += weight * (nutrient_weight * load_nutrient(pos));
+}
+
+// The centre of the texel at corner i (0-3) of the bilinear footprint of pos,
+// and its weight.
+float2 bilinear_texel(float2 pos, uint i, out float weight)
+{
+    float2 p = pos - 0.5;
+    float2 base = floor(p);
+    float2 f = p - base;
+    float2 corner = float2(i & 1, i >> 1);
+    float2 w = corner * f + (1 - corner) * (1 - f);
+    weight = w.x * w.y;
+    return base + corner + 0.5;
+}
+
+float sense(Agent agent, Species species, float2 center)
+{
     float sum = 0;
     
-    for (int offset_x = -sensor_size; offset_x <= sensor_size; offset_x++)
+    for (int offset_x = -species.sensor_size; offset_x <= species.sensor_size; offset_x++)
     {
-        for (int offset_y = -sensor_size; offset_y <= sensor_size; offset_y++)
+        for (int offset_y = -species.sensor_size; offset_y <= species.sensor_size; offset_y++)
         {
-            float2 sensor_pos = mod2(agent.position + sensor_dir * sensor_offset + float2(offset_x, offset_y), resolution);
-            sum += same_color_weight * dot(trail[sensor_pos], float4(agent.color.xyz, 0));
-            float4 inv_color = 1 - agent.color;
-            sum += different_color_weight * dot(trail[sensor_pos], inv_color);
+            float2 sample_pos = center + float2(offset_x, offset_y);
+
+            if (sampling == SAMPLING_ACCURATE)
+            {
+                for (uint i = 0; i < 4; i++)
+                {
+                    float weight;
+                    float2 texel_pos = bilinear_texel(sample_pos, i, weight);
+                    sense_texel(agent, texel_pos, weight, sum);
+                }
+            }
+            else
+            {
+                sense_texel(agent, sample_pos, 1, sum);
+            }
         }
     }
     return sum;
 }
 
-[numthreads(32, 1, 1)]
-void advance_agents (uint3 id : SV_DispatchThreadID)
+// Adds the given share of this step's deposit at pos, splatted over the
+// neighbouring texels in accurate mode.
+void deposit(Agent agent, float2 pos, float share)
 {
-    if (id.x >= num_agents)
+    uint c;
+
+    if (sampling != SAMPLING_ACCURATE)
+    {
+        for (c = 0; c < num_channels; c++)
+            add_trail(pos, c, deposit_amount(agent, c) * trail_weight * delta_time * share);
         return;
-    
-    Agent agent = agents[id.x];
-    
-    // Adjust direction
-    float weightF = sense(agent, 0, sensor_offset);
-    float weightL = sense(agent, sensor_angle_rad, sensor_offset);
-    float weightR = sense(agent, -sensor_angle_rad, sensor_offset);
+    }
+
+    for (uint i = 0; i < 4; i++)
+    {
+        float weight;
+        float2 texel_pos = bilinear_texel(pos, i, weight);
+
+        if (!sensor_position(texel_pos) || is_obstacle(texel_pos))
+            continue;
+
+        for (c = 0; c < num_channels; c++)
+            add_trail(texel_pos, c, deposit_amount(agent, c) * trail_weight * delta_time * (share * weight));
+    }
+}
+
+float sensor(Agent agent, Species species, float angle_offset)
+{
+    float2 sensor_dir;
+    sincos(agent.heading + angle_offset, sensor_dir.y, sensor_dir.x);
+    return sense(agent, species, agent.position + sensor_dir * species.sensor_offset);
+}
+
+// The classic rule: turn towards the strongest of the front, left and right
+// sensors, or randomly when the front is the weakest.
+float sensor_turn(Agent agent, float weightF, float weightL, float weightR)
+{
     float turn_dir = 0;
 
     if (weightL < weightF && weightF < weightR)
@@ -111,47 +550,325 @@ void advance_agents (uint3 id : SV_DispatchThreadID)
     }
     else if (weightL > weightF && weightF < weightR)
     {
-        turn_dir = sign(rand_float(time + id.x) - 0.5);
+        turn_dir = sign(agent_random(agent, STREAM_TURN) - 0.5);
+    }
+
+    return turn_dir;
+}
+
+void steer(inout Agent agent, Species species)
+{
+    float turn_rate = species.turn_rate_rad;
+
+    if (steering == STEERING_PROBABILISTIC)
+    {
+        float weightF = sensor(agent, species, 0);
+        float weightL = sensor(agent, species, species.sensor_angle_rad);
+        float weightR = sensor(agent, species, -species.sensor_angle_rad);
+        float difference = (weightL - weightR) / (abs(weightF) + abs(weightL) + abs(weightR) + 1e-6);
+
+        if (weightF < max(weightL, weightR)
+            && agent_random(agent, STREAM_STEER) < saturate(steering_params.x * abs(difference)))
+            agent.heading += sign(difference) * turn_rate;
+    }
+    else if (steering == STEERING_GRADIENT)
+    {
+        float2 dir;
+        sincos(agent.heading, dir.y, dir.x);
+        float2 side = float2(-dir.y, dir.x);
+        float2 center = agent.position + dir * species.sensor_offset;
+        float h = steering_params.y;
+        float forward = sense(agent, species, center + dir * h) - sense(agent, species, center - dir * h);
+        float lateral = sense(agent, species, center + side * h) - sense(agent, species, center - side * h);
+
+        if (forward != 0 || lateral != 0)
+            agent.heading += clamp(steering_params.x * atan2(lateral, forward), -turn_rate, turn_rate);
     }
+    else if (steering == STEERING_RUN_AND_TUMBLE)
+    {
+        float value = sense(agent, species, agent.position);
+        float rate = value > agent.steer_state ? steering_params.x * steering_params.y : steering_params.x;
+        agent.steer_state += (value - agent.steer_state) * saturate(delta_time / steering_params.z);
 
-    // float2 gradient = float2(0, 1);
-    agent.heading += turn_dir * agent_turn_rate_rad; // * delta_time;
+        if (agent_random(agent, STREAM_STEER) < saturate(rate * delta_time))
+            agent.heading = agent_random(agent, STREAM_STEER_HEADING) * 2 * PI;
+    }
+    else if (steering == STEERING_LEVY)
+    {
+        float weightF = sensor(agent, species, 0);
+        float weightL = sensor(agent, species, species.sensor_angle_rad);
+        float weightR = sensor(agent, species, -species.sensor_angle_rad);
+
+        if (max(max(weightF, weightL), weightR) > steering_params.z)
+        {
+            agent.steer_state = 0;
+            agent.heading += sensor_turn(agent, weightF, weightL, weightR) * turn_rate;
+        }
+        else
+        {
+            if (agent.steer_state <= 0)
+            {
+                // Pareto run length, capped at crossing the field.
+                float u = agent_random(agent, STREAM_STEER);
+                agent.heading = agent_random(agent, STREAM_STEER_HEADING) * 2 * PI;
+                agent.steer_state = min(steering_params.y * pow(1 - u, -1 / steering_params.x),
+                                        resolution.x + resolution.y);
+            }
+
+            agent.steer_state -= species.speed * delta_time;
+        }
+    }
+    else
+    {
+        float weightF = sensor(agent, species, 0);
+        float weightL = sensor(agent, species, species.sensor_angle_rad);
+        float weightR = sensor(agent, species, -species.sensor_angle_rad);
+        agent.heading += sensor_turn(agent, weightF, weightL, weightR) * turn_rate;
+    }
+}
+
+[numthreads(32, 1, 1)]
+void advance_agents (uint3 id : SV_DispatchThreadID)
+{
+    if (id.x >= num_agents)
+        return;
+    
+    Agent agent = agents[id.x];
+
+    // Dead agents wait for the host to compact them away.
+    if (population != 0 && agent.energy <= 0)
+        return;
+
+    Species species = live_species(agent.species);
+    
+    // Adjust direction
+    steer(agent, species);
      
     // Eat
-    trail[agent.position] = trail[agent.position] - agent.color * eat_weight * delta_time;
+    float energy_gain = 0;
+    uint c;
+    for (c = 0; c < num_channels; c++)
+    {
+        float available = load_trail(agent.position, c);
+        float amount = deposit_amount(agent, c) * eat_weight * delta_time;
+        energy_gain += trail_gain * max(0, min(amount, available));
+        add_trail(agent.position, c, -amount);
+    }
+
+    float available = load_nutrient(agent.position);
+    float eaten = min(available, nutrient_consume_rate * delta_time);
+    energy_gain += nutrient_gain * max(0, eaten);
+    add_nutrient(agent.position, -eaten);
 
     // Move in direction
+    float2 old_position = agent.position;
     float2 dir_vec;
     sincos(agent.heading, dir_vec.y, dir_vec.x);
-    agent.position += agent_speed * dir_vec * delta_time;
-    agent.position = mod2(agent.position, resolution);
+    float2 step_vec = species.speed * dir_vec * delta_time;
+    agent.position += step_vec;
+    settle(agent, old_position);
+
+    float2 target = old_position + step_vec;
+
+    if (sampling == SAMPLING_ACCURATE && sensor_position(target) && all(target == agent.position))
+    {
+        // Spread the deposit over unit steps along the move when the agent
+        // moved freely; agents that respawned, bounced or hit a wall deposit
+        // where they ended up.
+        float samples = max(ceil(length(step_vec)), 1);
+
+        for (uint i = 1; i <= (uint)samples; i++)
+        {
+            float2 pos = old_position + step_vec * (i / samples);
 
-    // trail[agent.position] += agent.color * trail_weight * delta_time;
-    trail[agent.position] = trail[agent.position] + agent.color * trail_weight * delta_time;
+            if (sensor_position(pos))
+                deposit(agent, pos, 1 / samples);
+        }
+    }
+    else
+    {
+        deposit(agent, agent.position, 1);
+    }
+
+    if (population != 0)
+        agent.energy = max(0, agent.energy + energy_gain - move_cost * species.speed * delta_time);
     agents[id.x] = agent;
 }
 
-[numthreads(8, 8, 1)]
-void decay_and_diffuse (uint3 id : SV_DispatchThreadID)
+// Moves the agents under a push or pull brush away from or towards its
+// center, less towards the edge, and turns them that way.
+[numthreads(32, 1, 1)]
+void move_agents (uint3 id : SV_DispatchThreadID)
 {
-    if (id.x > (uint) resolution.x || id.y > (uint) resolution.y)
+    if (id.x >= num_agents)
         return;
-    
-    const float diffuse_weight = saturate(diffuse_rate * delta_time);
-    const float exp_decay_weight = saturate(exponential_decay_rate * delta_time);
-    const float lin_decay_weight = max(0, linear_decay_rate * delta_time);
 
-    float4 sum = 0;
-    
+    Agent agent = agents[id.x];
+    float2 offset = agent.position - brush_center;
+    float center_distance = length(offset);
+
+    if (center_distance >= brush_radius || center_distance == 0)
+        return;
+
+    bool pull = brush_step < 0;
+    float2 direction = offset * ((pull ? -1 : 1) / center_distance);
+    float step_length = abs(brush_step) * (1 - center_distance / brush_radius);
+
+    // Pulled agents stop at the cursor rather than overshoot it.
+    if (pull)
+        step_length = min(step_length, center_distance);
+
+    float2 old_position = agent.position;
+    agent.position = old_position + direction * step_length;
+    agent.heading = atan2(direction.y, direction.x);
+    settle(agent, old_position);
+    agents[id.x] = agent;
+}
+
+#define NUTRIENT_CHANNEL 0xffffffff
+
+float load_field(uint2 pos, uint channel)
+{
+    return channel == NUTRIENT_CHANNEL ? load_nutrient(pos) : load_trail(pos, channel);
+}
+
+// One sample at `pos + offset`, applying the boundary and obstacle rules to
+// samples outside the domain.
+float neighbour(uint2 pos, int2 offset, uint channel)
+{
+    float2 sampleidx = pos + float2(offset);
+
+    if (boundary == BOUNDARY_TORUS)
+        sampleidx = mod2(sampleidx, resolution);
+
+    // Zero flux means the wall mirrors the center texel.
+    if (is_obstacle(sampleidx))
+        return load_field(pos, channel);
+    else if (boundary == BOUNDARY_TORUS || in_domain(sampleidx + 0.5))
+        return load_field(sampleidx, channel);
+    else if (boundary != BOUNDARY_ABSORB)
+        return load_field(pos, channel);
+    return 0;
+}
+
+// The value `pos` is blended towards in this pass of the diffusion operator.
+float blurred(uint2 pos, uint channel)
+{
+    int r = kernel_radius;
+    float sum = 0;
+
+    if (diffusion == DIFFUSION_GAUSSIAN)
+    {
+        for (int i = -r; i <= r; i++)
+            sum += diffusion_kernel[i + r] * neighbour(pos, pass_index == 0 ? int2(i, 0) : int2(0, i), channel);
+        return sum;
+    }
+
+    if (diffusion == DIFFUSION_ANISOTROPIC)
+    {
+        for (int y = -r; y <= r; y++)
+        {
+            for (int x = -r; x <= r; x++)
+                sum += diffusion_kernel[(y + r) * (2 * r + 1) + x + r] * neighbour(pos, int2(x, y), channel);
+        }
+        return sum;
+    }
+
     for (int offsetX = -1; offsetX <= 1; offsetX++)
     {
         for (int offsetY = -1; offsetY <= 1; offsetY++)
+            sum += neighbour(pos, int2(offsetX, offsetY), channel);
+    }
+    return sum / 9;
+}
+
+float diffusion_weight(float rate)
+{
+    if (diffusion == DIFFUSION_SUBSTEP)
+        return saturate(rate * delta_time / pass_count);
+    return saturate(rate * delta_time);
+}
+
+// Folds the deposits queued in deterministic mode into the fields and clears
+// them; runs right before the first decay_and_diffuse pass. Agents can eat
+// the same nutrient at once here, so it is kept from going negative.
+[numthreads(8, 8, 1)]
+void resolve_deposits (uint3 id : SV_DispatchThreadID)
+{
+    if (!in_field(id.xy))
+        return;
+
+    for (uint c = 0; c < num_channels; c++)
+    {
+        uint i = trail_index(id.xy, c);
+        trail[i] += deposits[i] / deposit_scale;
+        deposits[i] = 0;
+    }
+
+    uint n = (uint) resolution.x * (uint) resolution.y * num_channels + texel_index(id.xy);
+    store_nutrient(id.xy, max(0, load_nutrient(id.xy) + deposits[n] / deposit_scale));
+    deposits[n] = 0;
+}
+
+// One pass of the diffusion operator. Decay and regrowth are applied in the
+// last pass only.
+[numthreads(8, 8, 1)]
+void decay_and_diffuse (uint3 id : SV_DispatchThreadID)
+{
+    if (!in_field(id.xy))
+        return;
+
+    bool outside = !in_domain(id.xy + 0.5) || is_obstacle(id.xy);
+    bool last_pass = pass_index + 1 >= pass_count;
+
+    for (uint c = 0; c < num_channels; c++)
+    {
+        if (outside)
         {
-            float2 sampleidx = mod2(id.xy + float2(offsetX, offsetY), resolution);
-            sum += trail[sampleidx];
+            diffused_trail[trail_index(id.xy, c)] = 0;
+            continue;
         }
+
+        Channel channel = live_channel(c);
+        const float diffuse_weight = diffusion_weight(channel.diffuse_rate);
+        const float exp_decay_weight = saturate(channel.exponential_decay_rate * delta_time);
+        const float lin_decay_weight = max(0, channel.linear_decay_rate * delta_time);
+
+        float v = load_trail(id.xy, c) * (1 - diffuse_weight) + blurred(id.xy, c) * diffuse_weight;
+        diffused_trail[trail_index(id.xy, c)] = last_pass ? v*(1 - exp_decay_weight) - lin_decay_weight : v;
+    }
+
+    // The nutrient diffuses like a trail channel and regrows towards its
+    // capacity instead of decaying.
+    if (outside)
+    {
+        diffused_nutrient[texel_index(id.xy)] = 0;
+        return;
+    }
+
+    const float diffuse_weight = diffusion_weight(nutrient_diffuse_rate);
+    const float regrow_weight = saturate(nutrient_regrow_rate * delta_time);
+    float v = load_nutrient(id.xy) * (1 - diffuse_weight) + blurred(id.xy, NUTRIENT_CHANNEL) * diffuse_weight;
+    diffused_nutrient[texel_index(id.xy)] = last_pass ? v + (nutrient_capacity[texel_index(id.xy)] - v) * regrow_weight : v;
+}
+
+[numthreads(8, 8, 1)]
+void compose (uint3 id : SV_DispatchThreadID)
+{
+    if (!in_field(id.xy))
+        return;
+
+    if (is_obstacle(id.xy))
+    {
+        display[id.xy] = OBSTACLE_COLOR;
+        return;
     }
 
-    float4 v = trail[id.xy] * (1 - diffuse_weight) + sum / 9 * diffuse_weight;
-    diffused_trail[id.xy] = v*(1 - exp_decay_weight) - lin_decay_weight;
-}
\ No newline at end of file
+    float4 color = 0;
+
+    for (uint c = 0; c < num_channels; c++)
+        color += load_trail(id.xy, c) * channels[c].color;
+    color += load_nutrient(id.xy) * nutrient_color;
+
+    display[id.xy] = color;
+}
@@ -0,0 +1,104 @@
+use crate::{brush::AgentBrush, channels::SimulationTables, Agent, Constants, Vec4};
+use anyhow::{bail, Result};
+use std::{ops::Range, str::FromStr};
+
+// The trail field is double buffered: each step diffuses `Current` into
+// `Scratch` and then swaps the two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailBuffer {
+    Current,
+    Scratch,
+}
+
+// The trail values of `rows` in a field of `width` by `height` texels.
+pub fn trail_rows(
+    width: u32,
+    height: u32,
+    channels: usize,
+    rows: Range<u32>,
+) -> Result<Range<usize>> {
+    if rows.start > rows.end || rows.end > height {
+        bail![
+            "rows {:?} aren't within the {} rows of the trail field",
+            rows,
+            height
+        ];
+    }
+
+    let row = width as usize * channels;
+    Ok(rows.start as usize * row..rows.end as usize * row)
+}
+
+// Fails when `added` agents don't fit the `capacity` reserved next to the
+// `len` there are.
+pub fn check_agent_capacity(len: usize, added: usize, capacity: usize) -> Result<()> {
+    if added > capacity.saturating_sub(len) {
+        bail![
+            "{} more agents don't fit, {} of the {} reserved are taken",
+            added,
+            len,
+            capacity
+        ];
+    }
+
+    Ok(())
+}
+
+// The operations a simulation implementation has to provide. The trail field
+// is exchanged as row-major texels of `width * height`, each holding one f32
+// per channel. Uploading tables with a different channel count clears both
+// trail buffers. The nutrient field holds one f32 per texel and is empty
+// when the tables have no nutrient capacity; uploading a capacity of a
+// different size refills it to capacity. `reserve_agents` sizes the agent
+// storage for the most agents the scene can have, so uploads up to that
+// reuse it and `append_agents` fails beyond it. The `_rows` methods only
+// move the given rows of the trail field, and `move_agents` edits the agents
+// where they are, so brushes don't copy the whole state each frame.
+// `read_display` returns the current field composited through the channel
+// display colors; after writing the field between steps, call
+// `refresh_display` for it to show the change.
+pub trait SimulationBackend {
+    fn upload_tables(&mut self, tables: &SimulationTables) -> Result<()>;
+    fn reserve_agents(&mut self, capacity: usize) -> Result<()>;
+    fn upload_agents(&mut self, agents: &[Agent]) -> Result<()>;
+    fn step(&mut self, constants: &Constants, count: u32) -> Result<()>;
+    fn read_trail_buffer(&self, buffer: TrailBuffer) -> Result<Vec<f32>>;
+    fn write_trail_buffer(&mut self, buffer: TrailBuffer, values: &[f32]) -> Result<()>;
+    fn read_trail_rows(&self, buffer: TrailBuffer, rows: Range<u32>) -> Result<Vec<f32>>;
+    fn write_trail_rows(
+        &mut self,
+        buffer: TrailBuffer,
+        rows: Range<u32>,
+        values: &[f32],
+    ) -> Result<()>;
+    fn read_nutrient(&self) -> Result<Vec<f32>>;
+    fn write_nutrient(&mut self, values: &[f32]) -> Result<()>;
+    fn read_agents(&self) -> Result<Vec<Agent>>;
+    fn append_agents(&mut self, agents: &[Agent]) -> Result<()>;
+    fn move_agents(&mut self, constants: &Constants, brush: &AgentBrush) -> Result<()>;
+    fn read_display(&self) -> Result<Vec<Vec4>>;
+    fn refresh_display(&mut self, constants: &Constants) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Dx11,
+    Cpu,
+    CpuParallel,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dx11" => Ok(Self::Dx11),
+            "cpu" => Ok(Self::Cpu),
+            "cpu-parallel" => Ok(Self::CpuParallel),
+            _ => bail![
+                "unknown backend {:?}, expected one of: dx11, cpu, cpu-parallel",
+                s
+            ],
+        }
+    }
+}
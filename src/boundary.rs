@@ -0,0 +1,95 @@
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+pub const BOUNDARY_TORUS: u32 = 0;
+pub const BOUNDARY_REFLECT: u32 = 1;
+pub const BOUNDARY_ABSORB: u32 = 2;
+pub const BOUNDARY_DISH: u32 = 3;
+
+// What happens at the edge of the world, e.g. `dish,radius=100`.
+//
+// - torus: agents, sensors and the diffusion stencil wrap around.
+// - reflect: agents bounce off the field edges with their heading mirrored;
+//   diffusion is zero-flux.
+// - absorb: agents that leave the field respawn at a random position; the
+//   trail outside counts as zero, so it drains through the walls.
+// - dish: a circular domain centered in the field (radius defaults to half
+//   the shorter side). Agents bounce off the rim, diffusion is zero-flux
+//   across it and the trail outside is held at zero.
+//
+// Outside of torus mode, sensors that land outside the domain read zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boundary {
+    Torus,
+    Reflect,
+    Absorb,
+    Dish { radius: Option<f32> },
+}
+
+impl Boundary {
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Torus => BOUNDARY_TORUS,
+            Self::Reflect => BOUNDARY_REFLECT,
+            Self::Absorb => BOUNDARY_ABSORB,
+            Self::Dish { .. } => BOUNDARY_DISH,
+        }
+    }
+
+    pub fn dish_radius(self, width: u32, height: u32) -> f32 {
+        match self {
+            Self::Dish {
+                radius: Some(radius),
+            } => radius,
+            _ => width.min(height) as f32 / 2.0,
+        }
+    }
+}
+
+impl FromStr for Boundary {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let mut radius = None;
+
+        for field in fields {
+            match (mode, field.split_once('=')) {
+                ("dish", Some(("radius", value))) => {
+                    radius = Some(
+                        value
+                            .parse()
+                            .context("invalid value for boundary field \"radius\"")?,
+                    )
+                }
+                _ => bail!["unexpected field {:?} for boundary {:?}", field, mode],
+            }
+        }
+
+        match mode {
+            "torus" => Ok(Self::Torus),
+            "reflect" => Ok(Self::Reflect),
+            "absorb" => Ok(Self::Absorb),
+            "dish" => Ok(Self::Dish { radius }),
+            _ => bail![
+                "unknown boundary {:?}, expected one of: torus, reflect, absorb, dish",
+                mode
+            ],
+        }
+    }
+}
+
+impl fmt::Display for Boundary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Torus => f.write_str("torus"),
+            Self::Reflect => f.write_str("reflect"),
+            Self::Absorb => f.write_str("absorb"),
+            Self::Dish { radius: None } => f.write_str("dish"),
+            Self::Dish {
+                radius: Some(radius),
+            } => write!(f, "dish,radius={}", radius),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use crate::{
+    backend::{SimulationBackend, TrailBuffer},
+    cpu::settle_moved_agent,
+    layout::gpu_struct,
+    random_hue,
+    species::{format_color, parse_color, species_table},
+    Agent, Constants, Scene, Vec2, Vec4,
+};
+use anyhow::{bail, Context, Result};
+use rand::{prelude::StdRng, Rng, SeedableRng};
+use std::{f32::consts::PI, fmt, ops::Range, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrushMode {
+    Paint,
+    Erase,
+    Spawn,
+    Push,
+    Pull,
+}
+
+// What dragging with a binding does to the texels and agents under the
+// cursor, e.g. `brush,mode=paint,strength=2,color=0:12:0` as an action.
+//
+// - paint: adds `color` times `strength` per second to the trail, fading
+//   towards the edge of the brush. With channels, the color's components are
+//   the amounts for the first four channels.
+// - erase: clears the trail.
+// - spawn: adds `strength` agents per second of random species.
+// - push / pull: moves agents away from or towards the cursor at `strength`
+//   texels per second and turns them that way.
+//
+// Only the rows under the brush and the agents it adds go through the host;
+// push and pull move the agents on the backend.
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub mode: BrushMode,
+    pub strength: Option<f32>,
+    pub color: Option<Vec4>,
+}
+
+impl Brush {
+    fn strength(&self) -> f32 {
+        self.strength.unwrap_or(match self.mode {
+            BrushMode::Paint => 1.0,
+            BrushMode::Erase => 0.0,
+            BrushMode::Spawn => 500.0,
+            BrushMode::Push | BrushMode::Pull => 60.0,
+        })
+    }
+
+    // Applies `delta_time` seconds of the brush around `center`, in texels.
+    pub fn apply<B: SimulationBackend>(
+        &self,
+        scene: &mut Scene<B>,
+        center: Vec2,
+        radius: f32,
+        delta_time: f32,
+    ) -> Result<()> {
+        match self.mode {
+            BrushMode::Paint | BrushMode::Erase => {
+                self.stroke_trail(scene, center, radius, delta_time)?
+            }
+            BrushMode::Spawn => {
+                let count = (self.strength() * delta_time).round().max(1.0) as u32;
+                inject_agents(scene, center, radius, count, None)?
+            }
+            BrushMode::Push | BrushMode::Pull => {
+                self.move_agents(scene, center, radius, delta_time)?
+            }
+        }
+
+        // Show the edit while paused too.
+        let constants = scene.constants(0.0);
+        scene.backend.refresh_display(&constants)
+    }
+
+    fn stroke_trail<B: SimulationBackend>(
+        &self,
+        scene: &mut Scene<B>,
+        center: Vec2,
+        radius: f32,
+        delta_time: f32,
+    ) -> Result<()> {
+        let (width, height) = (scene.settings.width, scene.settings.height);
+        let channels = scene.settings.channel_count() as usize;
+        let color = self.color.unwrap_or(Vec4::splat(12.0)).to_array();
+        let amount = self.strength() * delta_time;
+        let rows = covered_rows(center, radius, height);
+        let first = rows.start as usize * width as usize;
+        let mut trail = scene
+            .backend
+            .read_trail_rows(TrailBuffer::Current, rows.clone())?;
+
+        for (texel, falloff) in covered_texels(center, radius, width, height) {
+            let texel = texel - first;
+            let values = &mut trail[texel * channels..(texel + 1) * channels];
+
+            for (channel, value) in values.iter_mut().enumerate() {
+                match self.mode {
+                    BrushMode::Erase => *value = 0.0,
+                    _ => *value += color.get(channel).copied().unwrap_or(0.0) * amount * falloff,
+                }
+            }
+        }
+
+        scene
+            .backend
+            .write_trail_rows(TrailBuffer::Current, rows, &trail)
+    }
+
+    fn move_agents<B: SimulationBackend>(
+        &self,
+        scene: &mut Scene<B>,
+        center: Vec2,
+        radius: f32,
+        delta_time: f32,
+    ) -> Result<()> {
+        let step = self.strength() * delta_time;
+        let brush = AgentBrush {
+            brush_center: center,
+            brush_radius: radius,
+            brush_step: if self.mode == BrushMode::Push {
+                step
+            } else {
+                -step
+            },
+            ..Default::default()
+        };
+
+        let constants = scene.constants(0.0);
+        scene.backend.move_agents(&constants, &brush)
+    }
+}
+
+// A push or pull stroke as the backends apply it: agents within the radius
+// of the center move `brush_step` texels away from it, less towards the edge,
+// or towards it when the step is negative.
+gpu_struct! {
+    hlsl cbuffer BRUSH;
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct AgentBrush {
+        pub brush_center: Vec2,
+        pub brush_radius: f32,
+        pub brush_step: f32,
+    }
+}
+
+impl AgentBrush {
+    // Follows `move_agents` in shader/slime.hlsl.
+    pub fn move_agent(&self, constants: &Constants, obstacles: &[u32], agent: &mut Agent) {
+        let offset = agent.position - self.brush_center;
+        let distance = offset.length();
+
+        if distance >= self.brush_radius || distance == 0.0 {
+            return;
+        }
+
+        let pull = self.brush_step < 0.0;
+        let direction = offset * (if pull { -1.0 } else { 1.0 } / distance);
+        let mut step = self.brush_step.abs() * (1.0 - distance / self.brush_radius);
+
+        // Pulled agents stop at the cursor rather than overshoot it.
+        if pull {
+            step = step.min(distance);
+        }
+
+        let old = agent.position;
+        agent.position = old + direction * step;
+        agent.heading = direction.y.atan2(direction.x);
+        settle_moved_agent(constants, obstacles, agent, old);
+    }
+}
+
+// Adds `count` agents within `radius` of `center`, of `species` or else of
+// random species, as far as the agent storage reserved for the scene allows.
+// Agents spawn as if they had moved out from the center, so the boundary
+// applies to them; those that would end up inside a wall are left out.
+pub fn inject_agents<B: SimulationBackend>(
+    scene: &mut Scene<B>,
+    center: Vec2,
+    radius: f32,
+    count: u32,
+    species: Option<u32>,
+) -> Result<()> {
+    let settings = &scene.settings;
+    let constants = scene.constants(0.0);
+    let room = scene
+        .agent_capacity
+        .saturating_sub(scene.num_agents as usize);
+    let table = species_table(settings);
+    let mut agents = vec![];
+    let mut rng = StdRng::seed_from_u64(
+        ((settings.seed as u64) << 32) ^ scene.clock.steps ^ ((scene.next_id as u64) << 40),
+    );
+
+    for _ in 0..(count as usize).min(room) {
+        let angle = rng.gen::<f32>() * PI * 2.0;
+        let distance = radius * rng.gen::<f32>().sqrt();
+        let species = species.map_or_else(|| rng.gen_range(0..table.len()), |s| s as usize);
+        let hue = random_hue(&mut rng);
+
+        let mut agent = Agent {
+            color: table[species].color.unwrap_or(hue),
+            position: center
+                + Vec2 {
+                    x: angle.cos() * distance,
+                    y: angle.sin() * distance,
+                },
+            heading: rng.gen::<f32>() * PI * 2.0,
+            species: species as u32,
+            energy: settings.population.map_or(0.0, |p| p.energy),
+            rng_key: rng.gen(),
+            steer_state: 0.0,
+            id: scene.next_id,
+        };
+        scene.next_id = scene.next_id.wrapping_add(1);
+
+        if settle_moved_agent(&constants, &scene.tables.obstacles, &mut agent, center) {
+            agents.push(agent);
+        }
+    }
+
+    scene.backend.append_agents(&agents)?;
+    scene.num_agents += agents.len() as u32;
+    Ok(())
+}
+
+// The rows a brush of `radius` around `center` touches.
+fn covered_rows(center: Vec2, radius: f32, height: u32) -> Range<u32> {
+    let y0 = ((center.y - radius).floor().max(0.0) as u32).min(height);
+    let y1 = ((center.y + radius).ceil().max(0.0) as u32).min(height);
+
+    y0..y1.max(y0)
+}
+
+// Texels whose centers lie within `radius` of `center`, with a weight that
+// falls from 1 at the center to 0 at the edge.
+fn covered_texels(
+    center: Vec2,
+    radius: f32,
+    width: u32,
+    height: u32,
+) -> impl Iterator<Item = (usize, f32)> {
+    let x0 = (center.x - radius).floor().max(0.0) as u32;
+    let x1 = ((center.x + radius).ceil().max(0.0) as u32).min(width);
+
+    covered_rows(center, radius, height).flat_map(move |y| {
+        (x0..x1).filter_map(move |x| {
+            let texel_center = Vec2 {
+                x: x as f32 + 0.5,
+                y: y as f32 + 0.5,
+            };
+            let distance = (texel_center - center).length() / radius;
+
+            (distance < 1.0).then(|| ((y * width + x) as usize, 1.0 - distance * distance))
+        })
+    })
+}
+
+impl FromStr for Brush {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut brush = Self {
+            mode: BrushMode::Paint,
+            strength: None,
+            color: None,
+        };
+        let mut mode = None;
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["brush field {:?} should be key=value", field])?;
+
+            match key {
+                "mode" => {
+                    mode = Some(match value {
+                        "paint" => BrushMode::Paint,
+                        "erase" => BrushMode::Erase,
+                        "spawn" => BrushMode::Spawn,
+                        "push" => BrushMode::Push,
+                        "pull" => BrushMode::Pull,
+                        _ => bail![
+                            "unknown brush mode {:?}, expected one of: paint, erase, spawn, push, \
+                             pull",
+                            value
+                        ],
+                    })
+                }
+                "strength" => {
+                    brush.strength = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!["invalid value for brush field {:?}", key])?,
+                    )
+                }
+                "color" => brush.color = Some(parse_color(value)?),
+                _ => bail![
+                    "unknown brush field {:?}, expected one of: mode, strength, color",
+                    key
+                ],
+            }
+        }
+
+        brush.mode = mode.context("brush needs a mode")?;
+
+        if brush.color.is_some() && brush.mode != BrushMode::Paint {
+            bail!["only the paint brush takes a color"];
+        }
+
+        Ok(brush)
+    }
+}
+
+impl fmt::Display for Brush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.mode {
+            BrushMode::Paint => "mode=paint",
+            BrushMode::Erase => "mode=erase",
+            BrushMode::Spawn => "mode=spawn",
+            BrushMode::Push => "mode=push",
+            BrushMode::Pull => "mode=pull",
+        })?;
+
+        if let Some(strength) = self.strength {
+            write!(f, ",strength={}", strength)?;
+        }
+
+        if let Some(color) = self.color {
+            write!(f, ",color={}", format_color(color))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::CpuBackend, Settings, MIN_ADDED_AGENTS};
+    use structopt::StructOpt;
+
+    fn scene(args: &[&str]) -> Scene<CpuBackend> {
+        let settings =
+            Settings::from_iter_safe(["trails", "--width=64", "--height=48"].iter().chain(args))
+                .unwrap();
+        Scene::new(CpuBackend::new(64, 48), settings).unwrap()
+    }
+
+    #[test]
+    fn injection_stops_at_the_reserved_capacity() {
+        let center = Vec2 { x: 32.0, y: 24.0 };
+
+        for (args, capacity) in [
+            (&["--num-agents=100"][..], 100 + MIN_ADDED_AGENTS),
+            (&["--num-agents=100", "--population=capacity=150"][..], 150),
+        ] {
+            let mut scene = scene(args);
+
+            inject_agents(&mut scene, center, 8.0, u32::MAX, None).unwrap();
+            inject_agents(&mut scene, center, 8.0, 10, None).unwrap();
+
+            assert_eq!(scene.num_agents as usize, capacity);
+            assert_eq!(scene.backend.read_agents().unwrap().len(), capacity);
+        }
+    }
+
+    #[test]
+    fn strokes_only_touch_the_rows_under_the_brush() {
+        let mut scene = scene(&["--num-agents=1"]);
+        let brush: Brush = "mode=paint,strength=2".parse().unwrap();
+
+        brush
+            .apply(&mut scene, Vec2 { x: 10.0, y: 20.0 }, 4.0, 0.5)
+            .unwrap();
+
+        let trail = scene
+            .backend
+            .read_trail_buffer(TrailBuffer::Current)
+            .unwrap();
+        let row = trail.len() / 48;
+        let painted = |y: usize| trail[y * row..(y + 1) * row].iter().any(|&v| v != 0.0);
+
+        assert!((16..24).all(painted));
+        assert!(!(0..16).chain(24..48).any(painted));
+    }
+}
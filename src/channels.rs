@@ -0,0 +1,337 @@
+use crate::{
+    hsv_to_rgb,
+    layout::gpu_struct,
+    nutrient::nutrient_capacity,
+    obstacles::load_obstacles,
+    species::{format_color, parse_color, species_table, Species, SpeciesEntry},
+    Constants, Settings, Vec4,
+};
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+// How agents sense and deposit. In color mode the trail has four channels
+// that agents treat as RGBA: they deposit their own color and sense with
+// `same_color_weight` / `different_color_weight`. In matrix mode every
+// species deposits into and is attracted to each channel according to its
+// row of the interaction matrix.
+pub const INTERACTION_COLOR: u32 = 0;
+pub const INTERACTION_MATRIX: u32 = 1;
+
+pub const COLOR_MODE_CHANNELS: u32 = 4;
+
+// Per-channel decay and diffusion, and the color the channel is displayed
+// with. Matches `struct Channel` in shader/slime.hlsl. Rates flagged in
+// `inherit` come from the global settings in the constants.
+gpu_struct! {
+    hlsl struct;
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct Channel {
+        pub color: Vec4,
+        pub exponential_decay_rate: f32,
+        pub linear_decay_rate: f32,
+        pub diffuse_rate: f32,
+        pub inherit: u32,
+    }
+}
+
+pub const INHERIT_EXPONENTIAL_DECAY: u32 = 1;
+pub const INHERIT_LINEAR_DECAY: u32 = 2;
+pub const INHERIT_DIFFUSE: u32 = 4;
+
+impl Channel {
+    pub fn live(self, constants: &Constants) -> Channel {
+        let pick = |flag: u32, own: f32, global: f32| {
+            if self.inherit & flag != 0 {
+                global
+            } else {
+                own
+            }
+        };
+
+        Channel {
+            exponential_decay_rate: pick(
+                INHERIT_EXPONENTIAL_DECAY,
+                self.exponential_decay_rate,
+                constants.exponential_decay_rate,
+            ),
+            linear_decay_rate: pick(
+                INHERIT_LINEAR_DECAY,
+                self.linear_decay_rate,
+                constants.linear_decay_rate,
+            ),
+            diffuse_rate: pick(INHERIT_DIFFUSE, self.diffuse_rate, constants.diffuse_rate),
+            ..self
+        }
+    }
+}
+
+// One entry of the species x channel interaction matrix. Matches
+// `struct Interaction` in shader/slime.hlsl. Without an explicit `attract`
+// row the attraction is one of the color weights, flagged in `inherit`.
+gpu_struct! {
+    hlsl struct;
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct Interaction {
+        pub deposit: f32,
+        pub attraction: f32,
+        pub inherit: u32,
+    }
+}
+
+pub const INHERIT_SAME_COLOR_WEIGHT: u32 = 1;
+pub const INHERIT_DIFFERENT_COLOR_WEIGHT: u32 = 2;
+
+impl Interaction {
+    pub fn live(self, constants: &Constants) -> Interaction {
+        let attraction = if self.inherit & INHERIT_SAME_COLOR_WEIGHT != 0 {
+            constants.same_color_weight
+        } else if self.inherit & INHERIT_DIFFERENT_COLOR_WEIGHT != 0 {
+            constants.different_color_weight
+        } else {
+            self.attraction
+        };
+
+        Interaction { attraction, ..self }
+    }
+}
+
+// One `--channel` entry, e.g. `decay=0.5,diffuse=2,color=0:0:12`. Rates that
+// are left out use the global decay and diffusion settings; the display
+// color defaults to evenly spaced hues.
+#[derive(Debug, Default, Clone)]
+pub struct ChannelSpec {
+    pub exponential_decay_rate: Option<f32>,
+    pub linear_decay_rate: Option<f32>,
+    pub diffuse_rate: Option<f32>,
+    pub color: Option<Vec4>,
+}
+
+impl FromStr for ChannelSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut spec = Self::default();
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["channel field {:?} should be key=value", field])?;
+            let bad_value = || format!["invalid value for channel field {:?}", key];
+
+            match key {
+                "decay" => {
+                    spec.exponential_decay_rate = Some(value.parse().with_context(bad_value)?)
+                }
+                "linear-decay" => {
+                    spec.linear_decay_rate = Some(value.parse().with_context(bad_value)?)
+                }
+                "diffuse" => spec.diffuse_rate = Some(value.parse().with_context(bad_value)?),
+                "color" => spec.color = Some(parse_color(value)?),
+                _ => bail![
+                    "unknown channel field {:?}, expected one of: decay, linear-decay, diffuse, color",
+                    key
+                ],
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for ChannelSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = vec![];
+
+        if let Some(v) = self.exponential_decay_rate {
+            fields.push(format!["decay={}", v]);
+        }
+        if let Some(v) = self.linear_decay_rate {
+            fields.push(format!["linear-decay={}", v]);
+        }
+        if let Some(v) = self.diffuse_rate {
+            fields.push(format!["diffuse={}", v]);
+        }
+        if let Some(c) = self.color {
+            fields.push(format!["color={}", format_color(c)]);
+        }
+
+        f.write_str(&fields.join(","))
+    }
+}
+
+// Everything a backend needs besides the agents and the per-step constants.
+#[derive(Debug, Clone, Default)]
+pub struct SimulationTables {
+    pub species: Vec<Species>,
+    pub channels: Vec<Channel>,
+    // Row-major, `species.len() * channels.len()` entries.
+    pub interactions: Vec<Interaction>,
+    // One entry per texel, nonzero for walls. Empty without an obstacle map.
+    pub obstacles: Vec<u32>,
+    // One entry per texel. Empty without food, which disables the nutrient
+    // field.
+    pub nutrient_capacity: Vec<f32>,
+    // Weights of the diffusion kernel, see `Diffusion::kernel`.
+    pub diffusion_kernel: Vec<f32>,
+}
+
+impl SimulationTables {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let mut tables = Self {
+            obstacles: load_obstacles(settings)?,
+            nutrient_capacity: nutrient_capacity(settings)?,
+            ..Self::default()
+        };
+        tables.rebuild(settings)?;
+        Ok(tables)
+    }
+
+    // Recomputes the tables that come from the settings alone and keeps the
+    // ones loaded from images. Returns whether anything changed, that is
+    // whether the backend needs the tables again.
+    pub fn rebuild(&mut self, settings: &Settings) -> Result<bool> {
+        check_rows(settings)?;
+
+        let species = species_table(settings);
+        let (channels, interactions) = if settings.channels.is_empty() {
+            (color_channels(), vec![])
+        } else {
+            matrix_tables(settings, &species)
+        };
+        let species: Vec<_> = species.iter().map(|s| s.params).collect();
+        let diffusion_kernel = settings.diffusion.kernel();
+        let changed = species != self.species
+            || channels != self.channels
+            || interactions != self.interactions
+            || diffusion_kernel != self.diffusion_kernel;
+
+        self.species = species;
+        self.channels = channels;
+        self.interactions = interactions;
+        self.diffusion_kernel = diffusion_kernel;
+        Ok(changed)
+    }
+}
+
+// Checks the `deposit` and `attract` rows of every species against the
+// channels.
+pub fn check_rows(settings: &Settings) -> Result<()> {
+    for (i, entry) in species_table(settings).iter().enumerate() {
+        for (name, row) in &[("deposit", &entry.deposit), ("attract", &entry.attraction)] {
+            match row {
+                Some(_) if settings.channels.is_empty() => bail![
+                    "species {} sets `{}`, which needs at least one --channel",
+                    i,
+                    name
+                ],
+                Some(row) if row.len() > settings.channels.len() => bail![
+                    "species {} has {} `{}` values but there are only {} channels",
+                    i,
+                    row.len(),
+                    name,
+                    settings.channels.len()
+                ],
+                _ => (),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Color mode: RGBA channels displayed as themselves, with the global rates.
+fn color_channels() -> Vec<Channel> {
+    (0..COLOR_MODE_CHANNELS as usize)
+        .map(|i| {
+            let mut c = [0.0; 4];
+            c[i] = 1.0;
+            Channel {
+                color: Vec4 {
+                    x: c[0],
+                    y: c[1],
+                    z: c[2],
+                    w: c[3],
+                },
+                inherit: INHERIT_EXPONENTIAL_DECAY | INHERIT_LINEAR_DECAY | INHERIT_DIFFUSE,
+                ..Channel::default()
+            }
+        })
+        .collect()
+}
+
+fn matrix_tables(
+    settings: &Settings,
+    species: &[SpeciesEntry],
+) -> (Vec<Channel>, Vec<Interaction>) {
+    let num_channels = settings.channels.len();
+    let rate = |value: Option<f32>, flag: u32| (value.unwrap_or(0.0), value.map_or(flag, |_| 0));
+    let channels = settings
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(i, spec)| {
+            let (exponential_decay_rate, exponential) =
+                rate(spec.exponential_decay_rate, INHERIT_EXPONENTIAL_DECAY);
+            let (linear_decay_rate, linear) = rate(spec.linear_decay_rate, INHERIT_LINEAR_DECAY);
+            let (diffuse_rate, diffuse) = rate(spec.diffuse_rate, INHERIT_DIFFUSE);
+
+            Channel {
+                color: spec.color.unwrap_or_else(|| {
+                    let (r, g, b) = hsv_to_rgb(i as f32 / num_channels as f32, 1.0, 1.0);
+                    Vec4 {
+                        x: r * 12.0,
+                        y: g * 12.0,
+                        z: b * 12.0,
+                        w: 1.0,
+                    }
+                }),
+                exponential_decay_rate,
+                linear_decay_rate,
+                diffuse_rate,
+                inherit: exponential | linear | diffuse,
+            }
+        })
+        .collect();
+
+    // Without an explicit row, species i deposits into channel i (mod N),
+    // is drawn to it with `same_color_weight` and to the other channels
+    // with `different_color_weight`.
+    let mut interactions = Vec::with_capacity(species.len() * num_channels);
+    for (i, entry) in species.iter().enumerate() {
+        let own = i % num_channels;
+        for c in 0..num_channels {
+            let value =
+                |row: &Option<Vec<f32>>| row.as_ref().map(|row| row.get(c).copied().unwrap_or(0.0));
+            let (attraction, inherit) = match value(&entry.attraction) {
+                Some(attraction) => (attraction, 0),
+                None if c == own => (0.0, INHERIT_SAME_COLOR_WEIGHT),
+                None => (0.0, INHERIT_DIFFERENT_COLOR_WEIGHT),
+            };
+            interactions.push(Interaction {
+                deposit: value(&entry.deposit).unwrap_or(if c == own { 1.0 } else { 0.0 }),
+                attraction,
+                inherit,
+            });
+        }
+    }
+
+    (channels, interactions)
+}
+
+impl Settings {
+    pub fn channel_count(&self) -> u32 {
+        if self.channels.is_empty() {
+            COLOR_MODE_CHANNELS
+        } else {
+            self.channels.len() as u32
+        }
+    }
+
+    pub fn interaction_mode(&self) -> u32 {
+        if self.channels.is_empty() {
+            INTERACTION_COLOR
+        } else {
+            INTERACTION_MATRIX
+        }
+    }
+}
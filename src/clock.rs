@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+// Simulated time. With a fixed step the clock only moves when the simulation
+// steps, so a run depends on nothing but its settings and seed. Otherwise
+// each tick advances by the (scaled) wall-clock time since the previous one.
+#[derive(Debug, Clone, Copy)]
+pub struct SimClock {
+    pub time: f64,
+    pub steps: u64,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    last_tick: Instant,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            time: 0.0,
+            steps: 0,
+            last_tick: Instant::now(),
+        }
+    }
+
+    pub fn restore(time: f64, steps: u64) -> Self {
+        Self {
+            time,
+            steps,
+            ..Self::new()
+        }
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn wall_delta(&mut self, time_scale: f32) -> f32 {
+        let now = Instant::now();
+        let delta = now.duration_since(self.last_tick).as_secs_f32();
+
+        self.last_tick = now;
+        delta * time_scale
+    }
+
+    // Forgets the wall-clock time since the last tick, e.g. while paused.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn skip_wall_time(&mut self) {
+        self.last_tick = Instant::now();
+    }
+
+    pub fn advance(&mut self, delta_time: f32, steps: u32) {
+        self.time += delta_time as f64;
+        self.steps += steps as u64;
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
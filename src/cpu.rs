@@ -0,0 +1,1874 @@
+// CPU reference implementation of the kernels in shader/slime.hlsl. The math
+// follows the shaders step for step, including the D3D11 rules for
+// out-of-bounds UAV access (reads return zero, writes are dropped) and
+// float-to-uint conversion (saturating, NaN becomes zero). Agents are
+// advanced sequentially, so later agents observe the deposits of earlier ones
+// within a step.
+use crate::{
+    backend::{check_agent_capacity, trail_rows, SimulationBackend, TrailBuffer},
+    boundary::{BOUNDARY_ABSORB, BOUNDARY_DISH, BOUNDARY_REFLECT, BOUNDARY_TORUS},
+    brush::AgentBrush,
+    channels::{Channel, Interaction, SimulationTables, INTERACTION_COLOR},
+    deposition::DEPOSITION_DETERMINISTIC,
+    diffusion::{
+        max_diffuse_rate, pass_count, PassConstants, DIFFUSION_ANISOTROPIC, DIFFUSION_GAUSSIAN,
+        DIFFUSION_SUBSTEP,
+    },
+    sampling::SAMPLING_ACCURATE,
+    species::Species,
+    steering::{STEERING_GRADIENT, STEERING_LEVY, STEERING_PROBABILISTIC, STEERING_RUN_AND_TUMBLE},
+    Agent, Constants, Vec2, Vec4,
+};
+use anyhow::{bail, Result};
+use rayon::{prelude::*, ThreadPool};
+use std::{
+    f32::consts::PI,
+    ops::Range,
+    slice,
+    sync::atomic::{AtomicI32, AtomicU32, Ordering},
+};
+
+// Rows per band of the decay pass, the unit of work handed to a pool thread.
+const BAND_ROWS: u32 = 16;
+
+// Everything but the agents: the fields, the tables and the deposit buffer.
+// Shared with the multithreaded backend in parallel.rs.
+#[derive(Clone)]
+pub struct FieldState {
+    width: u32,
+    height: u32,
+    trail: Vec<f32>,
+    diffused_trail: Vec<f32>,
+    species: Vec<Species>,
+    channels: Vec<Channel>,
+    interactions: Vec<Interaction>,
+    obstacles: Vec<u32>,
+    nutrient: Vec<f32>,
+    diffused_nutrient: Vec<f32>,
+    nutrient_capacity: Vec<f32>,
+    diffusion_kernel: Vec<f32>,
+    // Fixed-point deposits of deterministic mode: one per trail value,
+    // followed by one per texel for the nutrient.
+    deposits: Vec<i32>,
+    // Taken from the constants of the last step, like the display texture of
+    // the GPU backend.
+    nutrient_color: Vec4,
+}
+
+impl FieldState {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            trail: vec![],
+            diffused_trail: vec![],
+            species: vec![],
+            channels: vec![],
+            interactions: vec![],
+            obstacles: vec![],
+            nutrient: vec![],
+            diffused_nutrient: vec![],
+            nutrient_capacity: vec![],
+            diffusion_kernel: vec![],
+            deposits: vec![],
+            nutrient_color: Vec4::default(),
+        }
+    }
+
+    pub fn grid(&self) -> Grid {
+        Grid {
+            width: self.width,
+            height: self.height,
+            channels: self.channels.len(),
+        }
+    }
+
+    // The tables and the fields as the agent update sees them.
+    pub fn agent_view(&mut self) -> (Tables<'_>, AgentFields<'_>) {
+        (
+            Tables {
+                species: &self.species,
+                channels: &self.channels,
+                interactions: &self.interactions,
+                obstacles: &self.obstacles,
+                nutrient_capacity: &self.nutrient_capacity,
+                diffusion_kernel: &self.diffusion_kernel,
+            },
+            AgentFields {
+                trail: SharedField::new(&mut self.trail),
+                nutrient: SharedField::new(&mut self.nutrient),
+                deposits: shared_deposits(&mut self.deposits),
+            },
+        )
+    }
+
+    // Everything after the agent update: resolving the deposits and the
+    // diffusion passes, with the bands of rows spread over `pool` if there is
+    // one.
+    pub fn decay(&mut self, constants: &Constants, pool: Option<&ThreadPool>) {
+        let grid = self.grid();
+
+        if constants.deposition == DEPOSITION_DETERMINISTIC {
+            resolve_deposits(
+                constants,
+                &mut self.trail,
+                &mut self.nutrient,
+                &mut self.deposits,
+            );
+        }
+
+        let passes = pass_count(
+            constants,
+            max_diffuse_rate(&self.channels, constants),
+            !self.nutrient.is_empty(),
+        );
+
+        for pass in 0..passes {
+            let tables = Tables {
+                species: &self.species,
+                channels: &self.channels,
+                interactions: &self.interactions,
+                obstacles: &self.obstacles,
+                nutrient_capacity: &self.nutrient_capacity,
+                diffusion_kernel: &self.diffusion_kernel,
+            };
+            let pass = PassConstants::new(pass, passes);
+            let (trail, nutrient) = (&self.trail[..], &self.nutrient[..]);
+            let bands = bands(grid, &mut self.diffused_trail, &mut self.diffused_nutrient);
+            let run = |(rows, diffused_trail, diffused_nutrient): (
+                Range<u32>,
+                &mut [f32],
+                &mut [f32],
+            )| {
+                decay_and_diffuse(
+                    constants,
+                    &tables,
+                    grid,
+                    pass,
+                    rows,
+                    (trail, diffused_trail),
+                    (nutrient, diffused_nutrient),
+                )
+            };
+
+            match pool {
+                Some(pool) => pool.install(|| bands.into_par_iter().for_each(run)),
+                None => bands.into_iter().for_each(run),
+            }
+
+            std::mem::swap(&mut self.trail, &mut self.diffused_trail);
+            std::mem::swap(&mut self.nutrient, &mut self.diffused_nutrient);
+        }
+
+        self.nutrient_color = constants.nutrient_color;
+    }
+
+    pub fn upload_tables(&mut self, tables: &SimulationTables) {
+        let len = self.width as usize * self.height as usize * tables.channels.len();
+
+        if self.trail.len() != len {
+            self.trail = vec![0.0; len];
+            self.diffused_trail = vec![0.0; len];
+            self.deposits = vec![0; len + self.width as usize * self.height as usize];
+        }
+
+        self.species = tables.species.clone();
+        self.channels = tables.channels.clone();
+        self.interactions = tables.interactions.clone();
+        self.obstacles = tables.obstacles.clone();
+
+        if self.nutrient_capacity.len() != tables.nutrient_capacity.len() {
+            self.nutrient = tables.nutrient_capacity.clone();
+            self.diffused_nutrient = tables.nutrient_capacity.clone();
+        }
+
+        self.nutrient_capacity = tables.nutrient_capacity.clone();
+        self.diffusion_kernel = tables.diffusion_kernel.clone();
+    }
+
+    pub fn read_trail_buffer(&self, buffer: TrailBuffer) -> Vec<f32> {
+        match buffer {
+            TrailBuffer::Current => self.trail.clone(),
+            TrailBuffer::Scratch => self.diffused_trail.clone(),
+        }
+    }
+
+    pub fn write_trail_buffer(&mut self, buffer: TrailBuffer, values: &[f32]) -> Result<()> {
+        let target = match buffer {
+            TrailBuffer::Current => &mut self.trail,
+            TrailBuffer::Scratch => &mut self.diffused_trail,
+        };
+
+        if values.len() != target.len() {
+            bail![
+                "trail buffer has {} values, expected {}",
+                values.len(),
+                target.len()
+            ];
+        }
+
+        target.copy_from_slice(values);
+        Ok(())
+    }
+
+    pub fn read_trail_rows(&self, buffer: TrailBuffer, rows: Range<u32>) -> Result<Vec<f32>> {
+        let source = match buffer {
+            TrailBuffer::Current => &self.trail,
+            TrailBuffer::Scratch => &self.diffused_trail,
+        };
+        let range = trail_rows(self.width, self.height, self.channels.len(), rows)?;
+
+        Ok(source[range].to_vec())
+    }
+
+    pub fn write_trail_rows(
+        &mut self,
+        buffer: TrailBuffer,
+        rows: Range<u32>,
+        values: &[f32],
+    ) -> Result<()> {
+        let range = trail_rows(self.width, self.height, self.channels.len(), rows)?;
+        let target = match buffer {
+            TrailBuffer::Current => &mut self.trail,
+            TrailBuffer::Scratch => &mut self.diffused_trail,
+        };
+
+        if values.len() != range.len() {
+            bail![
+                "trail rows have {} values, expected {}",
+                values.len(),
+                range.len()
+            ];
+        }
+
+        target[range].copy_from_slice(values);
+        Ok(())
+    }
+
+    pub fn obstacles(&self) -> &[u32] {
+        &self.obstacles
+    }
+
+    pub fn read_nutrient(&self) -> Vec<f32> {
+        self.nutrient.clone()
+    }
+
+    pub fn write_nutrient(&mut self, values: &[f32]) -> Result<()> {
+        if values.len() != self.nutrient.len() {
+            bail![
+                "nutrient field has {} values, expected {}",
+                values.len(),
+                self.nutrient.len()
+            ];
+        }
+
+        self.nutrient.copy_from_slice(values);
+        Ok(())
+    }
+
+    pub fn read_display(&self) -> Vec<Vec4> {
+        compose(
+            &self.channels,
+            &self.obstacles,
+            self.grid(),
+            &self.trail,
+            (&self.nutrient, self.nutrient_color),
+        )
+    }
+}
+
+// Splits the diffused fields into bands of `BAND_ROWS` rows.
+fn bands<'a>(
+    grid: Grid,
+    mut trail: &'a mut [f32],
+    mut nutrient: &'a mut [f32],
+) -> Vec<(Range<u32>, &'a mut [f32], &'a mut [f32])> {
+    let row = grid.width as usize;
+    let mut bands = vec![];
+
+    for y in (0..grid.height).step_by(BAND_ROWS as usize) {
+        let rows = BAND_ROWS.min(grid.height - y);
+        let (trail_band, trail_rest) = trail.split_at_mut(rows as usize * row * grid.channels);
+        let nutrient_len = if nutrient.is_empty() {
+            0
+        } else {
+            rows as usize * row
+        };
+        let (nutrient_band, nutrient_rest) = nutrient.split_at_mut(nutrient_len);
+        bands.push((y..y + rows, trail_band, nutrient_band));
+        trail = trail_rest;
+        nutrient = nutrient_rest;
+    }
+
+    bands
+}
+
+#[derive(Clone)]
+pub struct CpuBackend {
+    fields: FieldState,
+    agents: Vec<Agent>,
+    agent_capacity: usize,
+}
+
+impl CpuBackend {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            fields: FieldState::new(width, height),
+            agents: vec![],
+            agent_capacity: 0,
+        }
+    }
+
+    pub fn step_once(&mut self, constants: &Constants) {
+        let grid = self.fields.grid();
+        let num_agents = (constants.num_agents as usize).min(self.agents.len());
+        let (tables, fields) = self.fields.agent_view();
+        let live = LiveTables::new(constants, &tables);
+
+        for agent in self.agents.iter_mut().take(num_agents) {
+            advance_agents(
+                constants,
+                &tables,
+                &live,
+                grid,
+                fields,
+                AgentTile::single(agent),
+            );
+        }
+
+        self.fields.decay(constants, None);
+    }
+}
+
+impl SimulationBackend for CpuBackend {
+    fn upload_tables(&mut self, tables: &SimulationTables) -> Result<()> {
+        self.fields.upload_tables(tables);
+        Ok(())
+    }
+
+    fn reserve_agents(&mut self, capacity: usize) -> Result<()> {
+        self.agents
+            .reserve(capacity.saturating_sub(self.agents.len()));
+        self.agent_capacity = capacity;
+        Ok(())
+    }
+
+    fn upload_agents(&mut self, agents: &[Agent]) -> Result<()> {
+        self.agents.clear();
+        self.agents.extend_from_slice(agents);
+        Ok(())
+    }
+
+    fn step(&mut self, constants: &Constants, count: u32) -> Result<()> {
+        for i in 0..count {
+            self.step_once(&Constants {
+                step: constants.step.wrapping_add(i),
+                ..*constants
+            });
+        }
+        Ok(())
+    }
+
+    fn read_trail_buffer(&self, buffer: TrailBuffer) -> Result<Vec<f32>> {
+        Ok(self.fields.read_trail_buffer(buffer))
+    }
+
+    fn write_trail_buffer(&mut self, buffer: TrailBuffer, values: &[f32]) -> Result<()> {
+        self.fields.write_trail_buffer(buffer, values)
+    }
+
+    fn read_trail_rows(&self, buffer: TrailBuffer, rows: Range<u32>) -> Result<Vec<f32>> {
+        self.fields.read_trail_rows(buffer, rows)
+    }
+
+    fn write_trail_rows(
+        &mut self,
+        buffer: TrailBuffer,
+        rows: Range<u32>,
+        values: &[f32],
+    ) -> Result<()> {
+        self.fields.write_trail_rows(buffer, rows, values)
+    }
+
+    fn read_nutrient(&self) -> Result<Vec<f32>> {
+        Ok(self.fields.read_nutrient())
+    }
+
+    fn write_nutrient(&mut self, values: &[f32]) -> Result<()> {
+        self.fields.write_nutrient(values)
+    }
+
+    fn read_agents(&self) -> Result<Vec<Agent>> {
+        Ok(self.agents.clone())
+    }
+
+    fn append_agents(&mut self, agents: &[Agent]) -> Result<()> {
+        check_agent_capacity(self.agents.len(), agents.len(), self.agent_capacity)?;
+        self.agents.extend_from_slice(agents);
+        Ok(())
+    }
+
+    fn move_agents(&mut self, constants: &Constants, brush: &AgentBrush) -> Result<()> {
+        for agent in &mut self.agents {
+            brush.move_agent(constants, self.fields.obstacles(), agent);
+        }
+        Ok(())
+    }
+
+    fn read_display(&self) -> Result<Vec<Vec4>> {
+        Ok(self.fields.read_display())
+    }
+
+    // The display is composited when read.
+    fn refresh_display(&mut self, _constants: &Constants) -> Result<()> {
+        Ok(())
+    }
+}
+
+// A field that agents read and write during the agent update, possibly from
+// several threads. The values are f32 bits in relaxed atomics, so concurrent
+// agents behave like the GPU's unsynchronized buffers: a read-modify-write
+// can lose another agent's write, but nothing tears.
+#[derive(Clone, Copy)]
+pub struct SharedField<'a>(&'a [AtomicU32]);
+
+impl<'a> SharedField<'a> {
+    fn new(field: &'a mut [f32]) -> Self {
+        // AtomicU32 has the same in-memory representation as u32, and the
+        // exclusive borrow keeps anything else from touching the field.
+        Self(unsafe { &*(field as *mut [f32] as *const [AtomicU32]) })
+    }
+
+    fn is_empty(self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn len(self) -> usize {
+        self.0.len()
+    }
+
+    fn add(self, index: usize, amount: f32) {
+        let value = f32::from_bits(self.0[index].load(Ordering::Relaxed)) + amount;
+        self.0[index].store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+fn shared_deposits(deposits: &mut [i32]) -> &[AtomicI32] {
+    // Same representation argument as for SharedField.
+    unsafe { &*(deposits as *mut [i32] as *const [AtomicI32]) }
+}
+
+#[derive(Clone, Copy)]
+pub struct AgentFields<'a> {
+    trail: SharedField<'a>,
+    nutrient: SharedField<'a>,
+    deposits: &'a [AtomicI32],
+}
+
+// Read access to a field, either a plain slice or a SharedField.
+trait Field {
+    fn value(&self, index: usize) -> f32;
+}
+
+impl Field for [f32] {
+    fn value(&self, index: usize) -> f32 {
+        self[index]
+    }
+}
+
+impl Field for SharedField<'_> {
+    fn value(&self, index: usize) -> f32 {
+        f32::from_bits(self.0[index].load(Ordering::Relaxed))
+    }
+}
+
+pub struct Tables<'a> {
+    species: &'a [Species],
+    channels: &'a [Channel],
+    interactions: &'a [Interaction],
+    obstacles: &'a [u32],
+    nutrient_capacity: &'a [f32],
+    diffusion_kernel: &'a [f32],
+}
+
+// Draws of `agent_random` within one step.
+const STREAM_TURN: u32 = 0;
+const STREAM_RESPAWN_X: u32 = 1;
+const STREAM_RESPAWN_Y: u32 = 2;
+const STREAM_RESPAWN_HEADING: u32 = 3;
+const STREAM_STEER: u32 = 4;
+const STREAM_STEER_HEADING: u32 = 5;
+
+const OBSTACLE_COLOR: Vec4 = Vec4 {
+    x: 0.25,
+    y: 0.25,
+    z: 0.25,
+    w: 1.0,
+};
+
+// Dimensions of a trail field stored texel-major, `channels` values per texel.
+#[derive(Clone, Copy)]
+pub struct Grid {
+    width: u32,
+    height: u32,
+    channels: usize,
+}
+
+impl Grid {
+    // The same field with one value per texel, for the nutrient.
+    fn single(self) -> Self {
+        Self {
+            channels: 1,
+            ..self
+        }
+    }
+
+    fn texel(self, pos: Vec2) -> Option<usize> {
+        let (x, y) = (pos.x as u32, pos.y as u32);
+
+        if x < self.width && y < self.height {
+            Some((y as usize * self.width as usize + x as usize) * self.channels)
+        } else {
+            None
+        }
+    }
+
+    fn index(self, pos: Vec2, channel: usize) -> Option<usize> {
+        self.texel(pos)
+            .filter(|_| channel < self.channels)
+            .map(|base| base + channel)
+    }
+
+    fn load<F: Field + ?Sized>(self, field: &F, pos: Vec2, channel: usize) -> f32 {
+        self.index(pos, channel).map_or(0.0, |i| field.value(i))
+    }
+
+    fn is_obstacle(self, obstacles: &[u32], pos: Vec2) -> bool {
+        !obstacles.is_empty()
+            && pos.x >= 0.0
+            && pos.y >= 0.0
+            && self
+                .texel(pos)
+                .and_then(|base| obstacles.get(base / self.channels.max(1)))
+                .is_some_and(|&o| o != 0)
+    }
+}
+
+fn saturate(x: f32) -> f32 {
+    if x > 0.0 {
+        x.min(1.0)
+    } else {
+        0.0
+    }
+}
+
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+// Integer hash with good avalanche ("lowbias32" by Chris Wellons).
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+// Counter-based generator: a uniform value in [0, 1) that only depends on the
+// agent's key, the step and which draw of the step this is.
+fn agent_random(constants: &Constants, rng_key: u32, stream: u32) -> f32 {
+    let bits = hash(rng_key ^ hash(constants.step ^ hash(stream)));
+    (bits >> 8) as f32 / 16777216.0
+}
+
+fn modf(x: f32, y: f32) -> f32 {
+    let q = x / y;
+
+    // Most positions are inside the field already, where the floor is zero
+    // and the result is `x`.
+    if (0.0..1.0).contains(&q) {
+        x
+    } else {
+        x - y * q.floor()
+    }
+}
+
+fn mod2(x: Vec2, y: Vec2) -> Vec2 {
+    Vec2 {
+        x: modf(x.x, y.x),
+        y: modf(x.y, y.y),
+    }
+}
+
+fn sincos(angle: f32) -> Vec2 {
+    let (y, x) = angle.sin_cos();
+    Vec2 { x, y }
+}
+
+fn clamp2(x: Vec2, lo: f32, hi: Vec2) -> Vec2 {
+    Vec2 {
+        x: x.x.max(lo).min(hi.x),
+        y: x.y.max(lo).min(hi.y),
+    }
+}
+
+// Largest position still inside the field on each axis.
+fn field_max(constants: &Constants) -> Vec2 {
+    Vec2 {
+        x: constants.resolution.x - 0.001,
+        y: constants.resolution.y - 0.001,
+    }
+}
+
+fn dish_center(constants: &Constants) -> Vec2 {
+    constants.resolution * 0.5
+}
+
+fn in_domain(constants: &Constants, pos: Vec2) -> bool {
+    let in_field = pos.x >= 0.0
+        && pos.y >= 0.0
+        && pos.x < constants.resolution.x
+        && pos.y < constants.resolution.y;
+
+    if constants.boundary == BOUNDARY_DISH {
+        let d = pos - dish_center(constants);
+        in_field && d.dot(d) <= constants.dish_radius * constants.dish_radius
+    } else {
+        in_field
+    }
+}
+
+// Where a sensor at `pos` samples, or None if it is outside the domain.
+fn sensor_position(constants: &Constants, pos: Vec2) -> Option<Vec2> {
+    if constants.boundary == BOUNDARY_TORUS {
+        Some(mod2(pos, constants.resolution))
+    } else if in_domain(constants, pos) {
+        Some(pos)
+    } else {
+        None
+    }
+}
+
+// Brings an agent that moved from `old` back into the domain.
+fn apply_boundary(
+    constants: &Constants,
+    rng_key: u32,
+    (position, heading): (&mut Vec2, &mut f32),
+    old: Vec2,
+) {
+    let resolution = constants.resolution;
+    let mut pos = *position;
+
+    match constants.boundary {
+        BOUNDARY_REFLECT => {
+            if pos.x < 0.0 {
+                pos.x = -pos.x;
+                *heading = PI - *heading;
+            } else if pos.x >= resolution.x {
+                pos.x = 2.0 * resolution.x - pos.x;
+                *heading = PI - *heading;
+            }
+
+            if pos.y < 0.0 {
+                pos.y = -pos.y;
+                *heading = -*heading;
+            } else if pos.y >= resolution.y {
+                pos.y = 2.0 * resolution.y - pos.y;
+                *heading = -*heading;
+            }
+
+            pos = clamp2(pos, 0.0, field_max(constants));
+        }
+        BOUNDARY_ABSORB => {
+            if !in_domain(constants, pos) {
+                pos.x = agent_random(constants, rng_key, STREAM_RESPAWN_X) * resolution.x;
+                pos.y = agent_random(constants, rng_key, STREAM_RESPAWN_Y) * resolution.y;
+                *heading = agent_random(constants, rng_key, STREAM_RESPAWN_HEADING) * 2.0 * PI;
+                pos = clamp2(pos, 0.0, field_max(constants));
+            }
+        }
+        BOUNDARY_DISH => {
+            if !in_domain(constants, pos) {
+                // Mirror the heading about the rim normal and stay put.
+                let center = dish_center(constants);
+                let d = pos - center;
+                let normal = d * (1.0 / d.length());
+                let dir = sincos(*heading);
+                let reflected = dir + normal * (-2.0 * dir.dot(normal));
+                *heading = reflected.y.atan2(reflected.x);
+
+                let back = old - center;
+                let scale = (constants.dish_radius * 0.999 / back.length()).min(1.0);
+                pos = clamp2(center + back * scale, 0.0, field_max(constants));
+            }
+        }
+        _ => pos = mod2(pos, resolution),
+    }
+
+    *position = pos;
+}
+
+// Resolves a move from `old` against the boundary and the walls.
+fn settle(
+    constants: &Constants,
+    grid: Grid,
+    obstacles: &[u32],
+    rng_key: u32,
+    (position, heading): (&mut Vec2, &mut f32),
+    old: Vec2,
+) {
+    apply_boundary(constants, rng_key, (position, heading), old);
+
+    // Walls turn agents around; agents that start inside one may leave.
+    if grid.is_obstacle(obstacles, *position) && !grid.is_obstacle(obstacles, old) {
+        *position = old;
+        *heading += PI;
+    }
+}
+
+// `settle` for agents moved on the host, so they end up where a step would
+// have put them. Returns whether the agent is clear of the walls.
+pub fn settle_moved_agent(
+    constants: &Constants,
+    obstacles: &[u32],
+    agent: &mut Agent,
+    old: Vec2,
+) -> bool {
+    let grid = Grid {
+        width: constants.resolution.x as u32,
+        height: constants.resolution.y as u32,
+        channels: 1,
+    };
+
+    settle(
+        constants,
+        grid,
+        obstacles,
+        agent.rng_key,
+        (&mut agent.position, &mut agent.heading),
+        old,
+    );
+    !grid.is_obstacle(obstacles, agent.position)
+}
+
+// The species and interaction tables with the live settings applied, worked
+// out once per step instead of for every agent and sample.
+pub struct LiveTables {
+    species: Vec<Species>,
+    interactions: Vec<Interaction>,
+    channels: usize,
+    // Out-of-range entries read as zero, like an out-of-bounds structured
+    // buffer load.
+    missing_species: Species,
+    missing_interaction: Interaction,
+}
+
+impl LiveTables {
+    pub fn new(constants: &Constants, tables: &Tables) -> Self {
+        Self {
+            species: tables.species.iter().map(|s| s.live(constants)).collect(),
+            interactions: tables
+                .interactions
+                .iter()
+                .map(|i| i.live(constants))
+                .collect(),
+            channels: tables.channels.len(),
+            missing_species: Species::default().live(constants),
+            missing_interaction: Interaction::default().live(constants),
+        }
+    }
+
+    fn species(&self, species: u32) -> Species {
+        self.species
+            .get(species as usize)
+            .copied()
+            .unwrap_or(self.missing_species)
+    }
+
+    fn interaction(&self, index: usize) -> Interaction {
+        self.interactions
+            .get(index)
+            .copied()
+            .unwrap_or(self.missing_interaction)
+    }
+}
+
+// What an agent senses and deposits with, looked up once per agent.
+struct Traits<'a> {
+    live: &'a LiveTables,
+    species: Species,
+    color: Vec4,
+    same_color: Vec4,
+    inv_color: Vec4,
+    // Start of the agent's row of the interaction matrix.
+    row: usize,
+}
+
+impl<'a> Traits<'a> {
+    fn new(live: &'a LiveTables, color: Vec4, species: u32) -> Self {
+        Self {
+            live,
+            species: live.species(species),
+            color,
+            same_color: Vec4 { w: 0.0, ..color },
+            inv_color: Vec4::splat(1.0) - color,
+            row: species as usize * live.channels,
+        }
+    }
+
+    fn attraction(&self, channel: usize) -> f32 {
+        self.live.interaction(self.row + channel).attraction
+    }
+
+    fn deposit_amount(&self, constants: &Constants, channel: usize) -> f32 {
+        if constants.interaction_mode == INTERACTION_COLOR {
+            self.color.to_array().get(channel).copied().unwrap_or(0.0)
+        } else {
+            self.live.interaction(self.row + channel).deposit
+        }
+    }
+}
+
+// A run of agents as columns, the unit the agent update works on. The
+// parallel backend hands its columns over a tile at a time, the reference
+// backend each agent as a tile of one.
+pub struct AgentTile<'a> {
+    pub color: &'a [Vec4],
+    pub position: &'a mut [Vec2],
+    pub heading: &'a mut [f32],
+    pub species: &'a [u32],
+    pub energy: &'a mut [f32],
+    pub rng_key: &'a [u32],
+    pub steer_state: &'a mut [f32],
+}
+
+impl<'a> AgentTile<'a> {
+    pub fn single(agent: &'a mut Agent) -> Self {
+        Self {
+            color: slice::from_ref(&agent.color),
+            position: slice::from_mut(&mut agent.position),
+            heading: slice::from_mut(&mut agent.heading),
+            species: slice::from_ref(&agent.species),
+            energy: slice::from_mut(&mut agent.energy),
+            rng_key: slice::from_ref(&agent.rng_key),
+            steer_state: slice::from_mut(&mut agent.steer_state),
+        }
+    }
+}
+
+fn to_fixed(constants: &Constants, amount: f32) -> i32 {
+    (amount * constants.deposit_scale + 0.5).floor() as i32
+}
+
+// Adds `amount` to `field[index]`, or in deterministic mode queues it as
+// fixed point at `deposits[offset + index]`, where the sum doesn't depend on
+// the order agents run in.
+fn accumulate(
+    constants: &Constants,
+    (field, deposits): (SharedField, &[AtomicI32]),
+    index: usize,
+    offset: usize,
+    amount: f32,
+) {
+    if constants.deposition == DEPOSITION_DETERMINISTIC {
+        deposits[offset + index].fetch_add(to_fixed(constants, amount), Ordering::Relaxed);
+    } else {
+        field.add(index, amount);
+    }
+}
+
+// Folds the queued deposits into the fields and clears them. Agents can eat
+// the same nutrient at once here, so it is kept from going negative.
+fn resolve_deposits(
+    constants: &Constants,
+    trail: &mut [f32],
+    nutrient: &mut [f32],
+    deposits: &mut [i32],
+) {
+    let (trail_deposits, nutrient_deposits) = deposits.split_at_mut(trail.len());
+
+    for (value, deposit) in trail.iter_mut().zip(trail_deposits.iter_mut()) {
+        *value += *deposit as f32 / constants.deposit_scale;
+        *deposit = 0;
+    }
+
+    for (value, deposit) in nutrient.iter_mut().zip(nutrient_deposits.iter_mut()) {
+        *value = (*value + *deposit as f32 / constants.deposit_scale).max(0.0);
+        *deposit = 0;
+    }
+}
+
+// Calls `f` with the centres of the four texels around `pos` and their
+// bilinear weights.
+fn bilinear(pos: Vec2, mut f: impl FnMut(Vec2, f32)) {
+    let x = pos.x - 0.5;
+    let y = pos.y - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    for (dx, dy, weight) in [
+        (0.0, 0.0, (1.0 - fx) * (1.0 - fy)),
+        (1.0, 0.0, fx * (1.0 - fy)),
+        (0.0, 1.0, (1.0 - fx) * fy),
+        (1.0, 1.0, fx * fy),
+    ] {
+        f(
+            Vec2 {
+                x: x0 + dx + 0.5,
+                y: y0 + dy + 0.5,
+            },
+            weight,
+        );
+    }
+}
+
+// Adds `weight` times what the texel at `pos`, already mapped into the
+// domain, contributes to a sensor.
+#[allow(clippy::too_many_arguments)]
+fn sense_texel(
+    constants: &Constants,
+    tables: &Tables,
+    grid: Grid,
+    (trail, nutrient): (SharedField, SharedField),
+    traits: &Traits,
+    pos: Vec2,
+    weight: f32,
+    sum: &mut f32,
+) {
+    if grid.is_obstacle(tables.obstacles, pos) {
+        *sum += weight * constants.obstacle_weight;
+        return;
+    }
+
+    let texel = grid.texel(pos);
+    let load = |c: usize| match texel {
+        Some(base) if c < grid.channels => trail.value(base + c),
+        _ => 0.0,
+    };
+
+    if constants.interaction_mode == INTERACTION_COLOR {
+        let value = Vec4 {
+            x: load(0),
+            y: load(1),
+            z: load(2),
+            w: load(3),
+        };
+        *sum += weight * (constants.same_color_weight * value.dot(traits.same_color));
+        *sum += weight * (constants.different_color_weight * value.dot(traits.inv_color));
+    } else {
+        for c in 0..grid.channels {
+            *sum += weight * (traits.attraction(c) * load(c));
+        }
+    }
+
+    if !nutrient.is_empty() {
+        *sum += weight * (constants.nutrient_weight * grid.single().load(&nutrient, pos, 0));
+    }
+}
+
+fn sense(
+    constants: &Constants,
+    tables: &Tables,
+    grid: Grid,
+    fields: AgentFields,
+    traits: &Traits,
+    center: Vec2,
+) -> f32 {
+    let sensor_size = traits.species.sensor_size;
+    let mut sum = 0.0;
+    let mut texel = |pos: Vec2, weight: f32| {
+        if let Some(pos) = sensor_position(constants, pos) {
+            sense_texel(
+                constants,
+                tables,
+                grid,
+                (fields.trail, fields.nutrient),
+                traits,
+                pos,
+                weight,
+                &mut sum,
+            );
+        }
+    };
+
+    for offset_x in -sensor_size..=sensor_size {
+        for offset_y in -sensor_size..=sensor_size {
+            let sample = center
+                + Vec2 {
+                    x: offset_x as f32,
+                    y: offset_y as f32,
+                };
+
+            if constants.sampling == SAMPLING_ACCURATE {
+                bilinear(sample, &mut texel);
+            } else {
+                texel(sample, 1.0);
+            }
+        }
+    }
+
+    sum
+}
+
+// Adds the `share` of this step's deposit at `pos`, splatted over the
+// neighbouring texels in accurate mode.
+fn deposit(
+    constants: &Constants,
+    tables: &Tables,
+    grid: Grid,
+    fields: AgentFields,
+    traits: &Traits,
+    pos: Vec2,
+    share: f32,
+) {
+    let add = |pos: Vec2, weight: f32| {
+        for c in 0..grid.channels {
+            if let Some(i) = grid.index(pos, c) {
+                let amount = traits.deposit_amount(constants, c)
+                    * constants.trail_weight
+                    * constants.delta_time
+                    * (share * weight);
+                accumulate(constants, (fields.trail, fields.deposits), i, 0, amount);
+            }
+        }
+    };
+
+    if constants.sampling != SAMPLING_ACCURATE {
+        add(pos, 1.0);
+        return;
+    }
+
+    bilinear(pos, |texel, weight| {
+        if let Some(texel) = sensor_position(constants, texel) {
+            if !grid.is_obstacle(tables.obstacles, texel) {
+                add(texel, weight);
+            }
+        }
+    });
+}
+
+// The classic rule: turn towards the strongest of the front, left and right
+// sensors, or randomly when the front is the weakest.
+fn sensor_turn(
+    constants: &Constants,
+    rng_key: u32,
+    weight_f: f32,
+    weight_l: f32,
+    weight_r: f32,
+) -> f32 {
+    let mut turn_dir = 0.0;
+
+    if weight_l < weight_f && weight_f < weight_r {
+        turn_dir = -1.0;
+    } else if weight_l > weight_f && weight_f > weight_r {
+        turn_dir = 1.0;
+    } else if weight_l < weight_f && weight_f > weight_r {
+        turn_dir = 0.0;
+    } else if weight_l > weight_f && weight_f < weight_r {
+        turn_dir = sign(agent_random(constants, rng_key, STREAM_TURN) - 0.5);
+    }
+
+    turn_dir
+}
+
+// Turns the agent at `position` according to the steering model. `sense_at`
+// reads the field around a point and `sensor` around the sensor at an angle
+// from the heading.
+fn steer(
+    constants: &Constants,
+    species: &Species,
+    rng_key: u32,
+    position: Vec2,
+    (heading, steer_state): (&mut f32, &mut f32),
+    sense_at: impl Fn(Vec2) -> f32,
+    sensor: impl Fn(f32) -> f32,
+) {
+    let params = constants.steering_params;
+    let turn_rate = species.turn_rate_rad;
+
+    match constants.steering {
+        STEERING_PROBABILISTIC => {
+            let weight_f = sensor(0.0);
+            let weight_l = sensor(species.sensor_angle_rad);
+            let weight_r = sensor(-species.sensor_angle_rad);
+            let difference =
+                (weight_l - weight_r) / (weight_f.abs() + weight_l.abs() + weight_r.abs() + 1e-6);
+
+            if weight_f < weight_l.max(weight_r)
+                && agent_random(constants, rng_key, STREAM_STEER)
+                    < saturate(params.x * difference.abs())
+            {
+                *heading += sign(difference) * turn_rate;
+            }
+        }
+        STEERING_GRADIENT => {
+            let dir = sincos(*heading);
+            let side = Vec2 {
+                x: -dir.y,
+                y: dir.x,
+            };
+            let center = position + dir * species.sensor_offset;
+            let forward = sense_at(center + dir * params.y) - sense_at(center - dir * params.y);
+            let lateral = sense_at(center + side * params.y) - sense_at(center - side * params.y);
+
+            if forward != 0.0 || lateral != 0.0 {
+                let angle = lateral.atan2(forward);
+                *heading += (params.x * angle).max(-turn_rate).min(turn_rate);
+            }
+        }
+        STEERING_RUN_AND_TUMBLE => {
+            let value = sense_at(position);
+            let rate = if value > *steer_state {
+                params.x * params.y
+            } else {
+                params.x
+            };
+            *steer_state += (value - *steer_state) * saturate(constants.delta_time / params.z);
+
+            if agent_random(constants, rng_key, STREAM_STEER)
+                < saturate(rate * constants.delta_time)
+            {
+                *heading = agent_random(constants, rng_key, STREAM_STEER_HEADING) * 2.0 * PI;
+            }
+        }
+        STEERING_LEVY => {
+            let weight_f = sensor(0.0);
+            let weight_l = sensor(species.sensor_angle_rad);
+            let weight_r = sensor(-species.sensor_angle_rad);
+
+            if weight_f.max(weight_l).max(weight_r) > params.z {
+                *steer_state = 0.0;
+                *heading +=
+                    sensor_turn(constants, rng_key, weight_f, weight_l, weight_r) * turn_rate;
+            } else {
+                if *steer_state <= 0.0 {
+                    // Pareto run length, capped at crossing the field.
+                    let u = agent_random(constants, rng_key, STREAM_STEER);
+                    let longest = constants.resolution.x + constants.resolution.y;
+                    *heading = agent_random(constants, rng_key, STREAM_STEER_HEADING) * 2.0 * PI;
+                    *steer_state = (params.y * (1.0 - u).powf(-1.0 / params.x)).min(longest);
+                }
+
+                *steer_state -= species.speed * constants.delta_time;
+            }
+        }
+        _ => {
+            let weight_f = sensor(0.0);
+            let weight_l = sensor(species.sensor_angle_rad);
+            let weight_r = sensor(-species.sensor_angle_rad);
+            *heading += sensor_turn(constants, rng_key, weight_f, weight_l, weight_r) * turn_rate;
+        }
+    }
+}
+
+// Advances the agents of `tile`: all of them sense and turn first, then all
+// of them eat, move and deposit, each in a straight loop over the columns.
+// What agents write during a step is only read back in the same step with
+// direct deposition, where concurrent agents race like on the GPU anyway.
+pub fn advance_agents(
+    constants: &Constants,
+    tables: &Tables,
+    live: &LiveTables,
+    grid: Grid,
+    fields: AgentFields,
+    tile: AgentTile,
+) {
+    // Dead agents wait for the host to compact them away.
+    let alive = |energy: f32| constants.population == 0 || energy > 0.0;
+
+    // Adjust direction
+    for i in 0..tile.position.len() {
+        if !alive(tile.energy[i]) {
+            continue;
+        }
+
+        let traits = Traits::new(live, tile.color[i], tile.species[i]);
+        let (position, heading) = (tile.position[i], tile.heading[i]);
+        let sense_at = |center: Vec2| sense(constants, tables, grid, fields, &traits, center);
+        let sensor = |angle_offset: f32| {
+            sense_at(position + sincos(heading + angle_offset) * traits.species.sensor_offset)
+        };
+        steer(
+            constants,
+            &traits.species,
+            tile.rng_key[i],
+            position,
+            (&mut tile.heading[i], &mut tile.steer_state[i]),
+            sense_at,
+            sensor,
+        );
+    }
+
+    for i in 0..tile.position.len() {
+        if !alive(tile.energy[i]) {
+            continue;
+        }
+
+        let traits = Traits::new(live, tile.color[i], tile.species[i]);
+        let species = traits.species;
+        let position = &mut tile.position[i];
+        let heading = &mut tile.heading[i];
+
+        // Eat
+        let mut energy_gain = 0.0;
+
+        for c in 0..grid.channels {
+            let available = grid.load(&fields.trail, *position, c);
+            let amount =
+                traits.deposit_amount(constants, c) * constants.eat_weight * constants.delta_time;
+            energy_gain += constants.trail_gain * amount.min(available).max(0.0);
+
+            if let Some(index) = grid.index(*position, c) {
+                accumulate(
+                    constants,
+                    (fields.trail, fields.deposits),
+                    index,
+                    0,
+                    -amount,
+                );
+            }
+        }
+
+        if !fields.nutrient.is_empty() {
+            let available = grid.single().load(&fields.nutrient, *position, 0);
+            let eaten = available.min(constants.nutrient_consume_rate * constants.delta_time);
+            energy_gain += constants.nutrient_gain * eaten.max(0.0);
+
+            if let Some(index) = grid.single().index(*position, 0) {
+                accumulate(
+                    constants,
+                    (fields.nutrient, fields.deposits),
+                    index,
+                    fields.trail.len(),
+                    -eaten,
+                );
+            }
+        }
+
+        // Move in direction
+        let old_position = *position;
+        let dir_vec = sincos(*heading);
+        let step_vec = dir_vec * species.speed * constants.delta_time;
+        *position = *position + step_vec;
+        settle(
+            constants,
+            grid,
+            tables.obstacles,
+            tile.rng_key[i],
+            (position, heading),
+            old_position,
+        );
+        let position = *position;
+
+        if constants.sampling == SAMPLING_ACCURATE {
+            // Spread the deposit over unit steps along the move when the agent
+            // moved freely; agents that respawned, bounced or hit a wall
+            // deposit where they ended up.
+            let target = sensor_position(constants, old_position + step_vec);
+
+            if target.is_some_and(|target| target == position) {
+                let samples = step_vec.length().ceil().max(1.0);
+
+                for n in 1..=samples as u32 {
+                    let pos = old_position + step_vec * (n as f32 / samples);
+
+                    if let Some(pos) = sensor_position(constants, pos) {
+                        deposit(constants, tables, grid, fields, &traits, pos, 1.0 / samples);
+                    }
+                }
+            } else {
+                deposit(constants, tables, grid, fields, &traits, position, 1.0);
+            }
+        } else {
+            deposit(constants, tables, grid, fields, &traits, position, 1.0);
+        }
+
+        if constants.population != 0 {
+            let move_cost = constants.move_cost * species.speed * constants.delta_time;
+            tile.energy[i] = (tile.energy[i] + energy_gain - move_cost).max(0.0);
+        }
+    }
+}
+
+// One sample of `field` at `texel + offset`, applying the boundary and
+// obstacle rules to samples outside the domain.
+fn neighbour(
+    constants: &Constants,
+    tables: &Tables,
+    grid: Grid,
+    field: &[f32],
+    texel: Vec2,
+    offset: Vec2,
+    channel: usize,
+) -> f32 {
+    let half = Vec2 { x: 0.5, y: 0.5 };
+    let mut sample = texel + offset;
+
+    if constants.boundary == BOUNDARY_TORUS {
+        sample = mod2(sample, constants.resolution);
+    }
+
+    // Zero flux means the wall mirrors the center texel.
+    if grid.is_obstacle(tables.obstacles, sample) {
+        grid.load(field, texel, channel)
+    } else if constants.boundary == BOUNDARY_TORUS || in_domain(constants, sample + half) {
+        grid.load(field, sample, channel)
+    } else if constants.boundary == BOUNDARY_ABSORB {
+        0.0
+    } else {
+        grid.load(field, texel, channel)
+    }
+}
+
+// The taps of the diffusion operator in one pass: offsets and weights in the
+// order they are summed, and what the sum is divided by.
+struct Kernel {
+    taps: Vec<(i32, i32, f32)>,
+    divisor: f32,
+    // The largest offset on each axis.
+    reach: (u32, u32),
+}
+
+impl Kernel {
+    fn new(constants: &Constants, tables: &Tables, pass: PassConstants) -> Self {
+        let r = constants.kernel_radius as i32;
+        let weight = |i: i32| {
+            tables
+                .diffusion_kernel
+                .get(i as usize)
+                .copied()
+                .unwrap_or(0.0)
+        };
+
+        match constants.diffusion {
+            DIFFUSION_GAUSSIAN if pass.pass_index == 0 => Self {
+                taps: (-r..=r).map(|i| (i, 0, weight(i + r))).collect(),
+                divisor: 1.0,
+                reach: (r as u32, 0),
+            },
+            DIFFUSION_GAUSSIAN => Self {
+                taps: (-r..=r).map(|i| (0, i, weight(i + r))).collect(),
+                divisor: 1.0,
+                reach: (0, r as u32),
+            },
+            DIFFUSION_ANISOTROPIC => Self {
+                taps: (-r..=r)
+                    .flat_map(|y| (-r..=r).map(move |x| (x, y)))
+                    .map(|(x, y)| (x, y, weight((y + r) * (2 * r + 1) + x + r)))
+                    .collect(),
+                divisor: 1.0,
+                reach: (r as u32, r as u32),
+            },
+            _ => Self {
+                taps: (-1..=1)
+                    .flat_map(|x| (-1..=1).map(move |y| (x, y, 1.0)))
+                    .collect(),
+                divisor: 9.0,
+                reach: (1, 1),
+            },
+        }
+    }
+
+    // Whether the texels of row `y` at least the reach away from the left and
+    // right edge can take the kernel without the boundary and obstacle rules:
+    // with no walls, no dish and every tap inside the field, `neighbour` is a
+    // plain load.
+    fn is_interior(&self, constants: &Constants, tables: &Tables, grid: Grid, y: u32) -> bool {
+        let (rx, ry) = self.reach;
+
+        constants.boundary != BOUNDARY_DISH
+            && tables.obstacles.is_empty()
+            && y >= ry
+            && y + ry < grid.height
+            && grid.width > 2 * rx
+    }
+}
+
+// The value `texel` is blended towards in this pass of the diffusion
+// operator.
+fn blurred(
+    constants: &Constants,
+    tables: &Tables,
+    grid: Grid,
+    field: &[f32],
+    texel: Vec2,
+    channel: usize,
+    kernel: &Kernel,
+) -> f32 {
+    let mut sum = 0.0;
+
+    for &(x, y, weight) in &kernel.taps {
+        let offset = Vec2 {
+            x: x as f32,
+            y: y as f32,
+        };
+        sum += weight * neighbour(constants, tables, grid, field, texel, offset, channel);
+    }
+
+    sum / kernel.divisor
+}
+
+fn diffusion_weight(constants: &Constants, rate: f32, pass: PassConstants) -> f32 {
+    if constants.diffusion == DIFFUSION_SUBSTEP {
+        saturate(rate * constants.delta_time / pass.pass_count as f32)
+    } else {
+        saturate(rate * constants.delta_time)
+    }
+}
+
+// Texels outside the domain or inside a wall hold nothing.
+fn is_outside(constants: &Constants, tables: &Tables, grid: Grid, texel: Vec2) -> bool {
+    !in_domain(constants, texel + Vec2 { x: 0.5, y: 0.5 })
+        || grid.is_obstacle(tables.obstacles, texel)
+}
+
+// The blur of row `y` of `field` for every channel of the texels at least
+// the kernel's reach away from the left and right edge, written to the same
+// places of `out`, which holds the row. Adds in the same order as `blurred`,
+// so the results are identical, but tap by tap over whole rows in loops the
+// compiler can vectorize.
+fn blurred_row(grid: Grid, kernel: &Kernel, field: &[f32], y: u32, out: &mut [f32]) {
+    let stride = grid.channels as isize;
+    let row = grid.width as usize * grid.channels;
+    let margin = kernel.reach.0 as usize * grid.channels;
+    let inner = margin..row - margin;
+    let out = &mut out[inner.clone()];
+    out.fill(0.0);
+
+    for &(x, dy, weight) in &kernel.taps {
+        let source = &field[(y as i32 + dy) as usize * row..][..row];
+        let start = (inner.start as isize + x as isize * stride) as usize;
+
+        for (sum, &value) in out.iter_mut().zip(&source[start..][..inner.len()]) {
+            *sum += weight * value;
+        }
+    }
+
+    for sum in out {
+        *sum /= kernel.divisor;
+    }
+}
+
+// One pass of the diffusion operator over `rows`, writing the matching rows
+// of the diffused fields. Decay and regrowth are applied in the last pass
+// only.
+fn decay_and_diffuse(
+    constants: &Constants,
+    tables: &Tables,
+    grid: Grid,
+    pass: PassConstants,
+    rows: Range<u32>,
+    (trail, diffused_trail): (&[f32], &mut [f32]),
+    (nutrient, diffused_nutrient): (&[f32], &mut [f32]),
+) {
+    let last_pass = pass.pass_index + 1 >= pass.pass_count;
+    let first_texel = rows.start as usize * grid.width as usize;
+    let kernel = Kernel::new(constants, tables, pass);
+    let (rx, _) = kernel.reach;
+    // Per channel, the diffusion weight and the exponential and linear
+    // decay.
+    let weights: Vec<(f32, f32, f32)> = tables
+        .channels
+        .iter()
+        .map(|channel| {
+            let channel = channel.live(constants);
+            (
+                diffusion_weight(constants, channel.diffuse_rate, pass),
+                saturate(channel.exponential_decay_rate * constants.delta_time),
+                (channel.linear_decay_rate * constants.delta_time).max(0.0),
+            )
+        })
+        .collect();
+    let decay = |c: usize, v: f32| {
+        let (_, exp_decay_weight, lin_decay_weight) = weights[c];
+
+        if last_pass {
+            v * (1.0 - exp_decay_weight) - lin_decay_weight
+        } else {
+            v
+        }
+    };
+    let row_len = grid.width as usize * grid.channels;
+
+    for y in rows.clone() {
+        let interior = kernel.is_interior(constants, tables, grid, y);
+        let row_start = (y as usize * grid.width as usize - first_texel) * grid.channels;
+        let out = &mut diffused_trail[row_start..][..row_len];
+
+        if interior {
+            let mid = &trail[y as usize * row_len..][..row_len];
+            let inner = rx as usize * grid.channels..row_len - rx as usize * grid.channels;
+            blurred_row(grid, &kernel, trail, y, out);
+
+            for (c, &(diffuse_weight, ..)) in weights.iter().enumerate() {
+                for i in (inner.start + c..inner.end).step_by(grid.channels) {
+                    out[i] = decay(c, mid[i] * (1.0 - diffuse_weight) + out[i] * diffuse_weight);
+                }
+            }
+        }
+
+        for x in 0..grid.width {
+            if interior && x >= rx && x + rx < grid.width {
+                continue;
+            }
+
+            let texel = Vec2 {
+                x: x as f32,
+                y: y as f32,
+            };
+            let base = x as usize * grid.channels;
+
+            if is_outside(constants, tables, grid, texel) {
+                out[base..base + grid.channels].fill(0.0);
+                continue;
+            }
+
+            for (c, &(diffuse_weight, ..)) in weights.iter().enumerate() {
+                let idx = (y as usize * grid.width as usize + x as usize) * grid.channels + c;
+                let target = blurred(constants, tables, grid, trail, texel, c, &kernel);
+                let v = trail[idx] * (1.0 - diffuse_weight) + target * diffuse_weight;
+                out[base + c] = decay(c, v);
+            }
+        }
+    }
+
+    if nutrient.is_empty() {
+        return;
+    }
+
+    // The nutrient diffuses like a trail channel and regrows towards its
+    // capacity instead of decaying.
+    let nutrient_grid = grid.single();
+    let diffuse_weight = diffusion_weight(constants, constants.nutrient_diffuse_rate, pass);
+    let regrow_weight = saturate(constants.nutrient_regrow_rate * constants.delta_time);
+
+    for y in rows {
+        let interior = kernel.is_interior(constants, tables, grid, y);
+        let row_start = y as usize * grid.width as usize;
+        let mid = &nutrient[row_start..][..grid.width as usize];
+        let out = &mut diffused_nutrient[row_start - first_texel..][..grid.width as usize];
+        let regrow = |x: usize, v: f32| {
+            let capacity = tables
+                .nutrient_capacity
+                .get(row_start + x)
+                .copied()
+                .unwrap_or(0.0);
+
+            if last_pass {
+                v + (capacity - v) * regrow_weight
+            } else {
+                v
+            }
+        };
+
+        if interior {
+            blurred_row(nutrient_grid, &kernel, nutrient, y, out);
+
+            for x in rx as usize..(grid.width - rx) as usize {
+                out[x] = regrow(x, mid[x] * (1.0 - diffuse_weight) + out[x] * diffuse_weight);
+            }
+        }
+
+        for x in 0..grid.width {
+            if interior && x >= rx && x + rx < grid.width {
+                continue;
+            }
+
+            let texel = Vec2 {
+                x: x as f32,
+                y: y as f32,
+            };
+
+            if is_outside(constants, tables, grid, texel) {
+                out[x as usize] = 0.0;
+                continue;
+            }
+
+            let target = blurred(
+                constants,
+                tables,
+                nutrient_grid,
+                nutrient,
+                texel,
+                0,
+                &kernel,
+            );
+            let v = mid[x as usize] * (1.0 - diffuse_weight) + target * diffuse_weight;
+            out[x as usize] = regrow(x as usize, v);
+        }
+    }
+}
+
+fn compose(
+    channels: &[Channel],
+    obstacles: &[u32],
+    grid: Grid,
+    trail: &[f32],
+    (nutrient, nutrient_color): (&[f32], Vec4),
+) -> Vec<Vec4> {
+    trail
+        .chunks_exact(grid.channels.max(1))
+        .enumerate()
+        .map(|(i, texel)| {
+            if obstacles.get(i).is_some_and(|&o| o != 0) {
+                return OBSTACLE_COLOR;
+            }
+
+            let color = texel
+                .iter()
+                .zip(channels)
+                .fold(Vec4::default(), |color, (v, channel)| {
+                    color + channel.color * *v
+                });
+
+            match nutrient.get(i) {
+                Some(&n) => color + nutrient_color * n,
+                None => color,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Settings;
+    use std::f32::consts::PI;
+    use structopt::StructOpt;
+
+    // A 4x4 field in color mode.
+    const GRID: Grid = Grid {
+        width: 4,
+        height: 4,
+        channels: 4,
+    };
+
+    fn settings(args: &[&str]) -> Settings {
+        Settings::from_iter_safe(["trails", "--width=4", "--height=4"].iter().chain(args)).unwrap()
+    }
+
+    fn constants(settings: &Settings) -> Constants {
+        Constants::new(settings, 0.0, 1.0)
+    }
+
+    fn field() -> Vec<f32> {
+        vec![0.0; (GRID.width * GRID.height) as usize * GRID.channels]
+    }
+
+    fn set(field: &mut [f32], x: f32, y: f32, value: Vec4) {
+        let base = GRID.texel(Vec2 { x, y }).unwrap();
+        field[base..base + 4].copy_from_slice(&value.to_array());
+    }
+
+    fn get(field: &[f32], x: f32, y: f32) -> Vec4 {
+        let pos = Vec2 { x, y };
+        Vec4 {
+            x: GRID.load(field, pos, 0),
+            y: GRID.load(field, pos, 1),
+            z: GRID.load(field, pos, 2),
+            w: GRID.load(field, pos, 3),
+        }
+    }
+
+    fn rgba(x: f32, y: f32, z: f32, w: f32) -> Vec4 {
+        Vec4 { x, y, z, w }
+    }
+
+    fn red_agent(x: f32, y: f32, heading: f32) -> Agent {
+        Agent {
+            color: rgba(1.0, 0.0, 0.0, 1.0),
+            position: Vec2 { x, y },
+            heading,
+            ..Agent::default()
+        }
+    }
+
+    fn view(tables: &SimulationTables) -> Tables<'_> {
+        Tables {
+            species: &tables.species,
+            channels: &tables.channels,
+            interactions: &tables.interactions,
+            obstacles: &tables.obstacles,
+            nutrient_capacity: &tables.nutrient_capacity,
+            diffusion_kernel: &tables.diffusion_kernel,
+        }
+    }
+
+    // A trail field as the agent update sees it, without nutrient or
+    // deposit buffer.
+    fn fields(trail: &mut [f32]) -> AgentFields<'_> {
+        AgentFields {
+            trail: SharedField::new(trail),
+            nutrient: SharedField::new(&mut []),
+            deposits: shared_deposits(&mut []),
+        }
+    }
+
+    // Advances one agent with the tables built from `args`.
+    fn advance(args: &[&str], trail: &mut [f32], agent: &mut Agent) {
+        let settings = settings(args);
+        let constants = constants(&settings);
+        let tables = SimulationTables::new(&settings).unwrap();
+        let tables = view(&tables);
+        let live = LiveTables::new(&constants, &tables);
+        advance_agents(
+            &constants,
+            &tables,
+            &live,
+            GRID,
+            fields(trail),
+            AgentTile::single(agent),
+        );
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn mod2_wraps_into_the_field() {
+        let size = Vec2 { x: 4.0, y: 4.0 };
+        let wrapped = mod2(Vec2 { x: -0.5, y: 4.25 }, size);
+        assert_close(wrapped.x, 3.5);
+        assert_close(wrapped.y, 0.25);
+
+        let wrapped = mod2(Vec2 { x: 8.0, y: -4.0 }, size);
+        assert_close(wrapped.x, 0.0);
+        assert_close(wrapped.y, 0.0);
+    }
+
+    #[test]
+    fn sense_weights_same_and_different_colors() {
+        let settings = settings(&["--same-color-weight=2", "--different-color-weight=-0.5"]);
+        let constants = constants(&settings);
+        let tables = SimulationTables::default();
+        let live = LiveTables::new(&constants, &view(&tables));
+        let mut trail = field();
+        // Inside the 3x3 window around (1.5, 0.5), which wraps to row 3.
+        set(&mut trail, 1.5, 0.5, rgba(2.0, 3.0, 0.0, 1.0));
+        set(&mut trail, 0.5, 3.5, rgba(1.0, 0.0, 0.0, 0.0));
+        // Outside of it.
+        set(&mut trail, 3.5, 0.5, Vec4::splat(5.0));
+
+        let agent = red_agent(0.5, 0.5, 0.0);
+        let mut traits = Traits::new(&live, agent.color, agent.species);
+        traits.species.sensor_size = 1;
+        let sum = sense(
+            &constants,
+            &view(&tables),
+            GRID,
+            fields(&mut trail),
+            &traits,
+            Vec2 { x: 1.5, y: 0.5 },
+        );
+        // 2 * (2 + 1) for red, -0.5 * 3 for green.
+        assert_close(sum, 4.5);
+    }
+
+    fn turn(left: f32, forward: f32, right: f32) -> f32 {
+        let mut trail = field();
+        set(&mut trail, 1.5, 2.5, Vec4::splat(left));
+        set(&mut trail, 2.5, 1.5, Vec4::splat(forward));
+        set(&mut trail, 1.5, 0.5, Vec4::splat(right));
+
+        let mut agent = red_agent(1.5, 1.5, 0.0);
+        advance(
+            &[
+                "--agent-speed=0",
+                "--agent-turn-rate-deg=90",
+                "--sensor-angle-deg=90",
+                "--sensor-offset=1",
+                "--sensor-size=0",
+                "--different-color-weight=0",
+                "--trail-weight=0",
+            ],
+            &mut trail,
+            &mut agent,
+        );
+        agent.heading
+    }
+
+    #[test]
+    fn turns_towards_the_strongest_sensor() {
+        assert_close(turn(1.0, 2.0, 3.0), -PI / 2.0);
+        assert_close(turn(3.0, 2.0, 1.0), PI / 2.0);
+        assert_close(turn(1.0, 3.0, 2.0), 0.0);
+        assert_close(turn(2.0, 2.0, 2.0), 0.0);
+        assert_close(turn(3.0, 1.0, 2.0).abs(), PI / 2.0);
+    }
+
+    #[test]
+    fn eats_under_the_agent_and_deposits_ahead() {
+        let args = [
+            "--agent-speed=1",
+            "--sensor-offset=1",
+            "--sensor-size=0",
+            "--eat-weight=0.5",
+            "--trail-weight=2",
+        ];
+        let mut trail = field();
+        set(&mut trail, 1.5, 1.5, Vec4::splat(1.0));
+
+        let mut agent = red_agent(1.5, 1.5, 0.0);
+        advance(&args, &mut trail, &mut agent);
+        assert_close(agent.position.x, 2.5);
+        assert_close(agent.position.y, 1.5);
+        let eaten = get(&trail, 1.5, 1.5);
+        assert_close(eaten.x, 0.5);
+        assert_close(eaten.y, 1.0);
+        assert_close(eaten.w, 0.5);
+        let deposited = get(&trail, 2.5, 1.5);
+        assert_close(deposited.x, 2.0);
+        assert_close(deposited.y, 0.0);
+        assert_close(deposited.w, 2.0);
+
+        // Moving off the right edge deposits on the left one.
+        let mut agent = red_agent(3.5, 2.5, 0.0);
+        advance(&args, &mut trail, &mut agent);
+        assert_close(agent.position.x, 0.5);
+        assert_close(get(&trail, 0.5, 2.5).x, 2.0);
+    }
+
+    #[test]
+    fn diffuses_then_decays() {
+        let settings = settings(&[
+            "--diffuse-rate=0.5",
+            "--exponential-decay-rate=0.5",
+            "--linear-decay-rate=0.1",
+        ]);
+        let tables = SimulationTables::new(&settings).unwrap();
+        let mut trail = field();
+        set(&mut trail, 0.5, 0.5, Vec4::splat(9.0));
+        let mut diffused = field();
+
+        decay_and_diffuse(
+            &constants(&settings),
+            &view(&tables),
+            GRID,
+            PassConstants::new(0, 1),
+            0..GRID.height,
+            (&trail, &mut diffused),
+            (&[], &mut []),
+        );
+        // (9 * 0.5 + 9 / 9 * 0.5) * 0.5 - 0.1
+        assert_close(get(&diffused, 0.5, 0.5).x, 2.4);
+        // (9 / 9 * 0.5) * 0.5 - 0.1, including the wrapped neighbours.
+        assert_close(get(&diffused, 1.5, 1.5).x, 0.15);
+        assert_close(get(&diffused, 3.5, 3.5).y, 0.15);
+        assert_close(get(&diffused, 0.5, 3.5).w, 0.15);
+        assert_close(get(&diffused, 2.5, 2.5).x, -0.1);
+    }
+}
@@ -1,6 +1,6 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use eiz::com::{com_new, com_new_void, ComError, ComPtr};
-use std::{ffi::c_void, marker::PhantomData, ptr};
+use std::{ffi::c_void, marker::PhantomData, ops::Range, ptr};
 use winapi::{
     shared::{
         dxgi::{DXGI_SWAP_CHAIN_FLAG_FRAME_LATENCY_WAITABLE_OBJECT, DXGI_SWAP_EFFECT_FLIP_DISCARD},
@@ -17,10 +17,12 @@ use winapi::{
     um::{
         d3d11::{
             D3D11CreateDevice, ID3D11Buffer, ID3D11ComputeShader, ID3D11Device,
-            ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Resource, ID3D11Texture2D,
-            ID3D11UnorderedAccessView, D3D11_BIND_CONSTANT_BUFFER, D3D11_BIND_RENDER_TARGET,
-            D3D11_BIND_UNORDERED_ACCESS, D3D11_BUFFER_DESC, D3D11_RESOURCE_MISC_BUFFER_STRUCTURED,
-            D3D11_SDK_VERSION, D3D11_SUBRESOURCE_DATA, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+            ID3D11DeviceContext, ID3D11RenderTargetView, ID3D11Resource, ID3D11ShaderResourceView,
+            ID3D11Texture2D, ID3D11UnorderedAccessView, D3D11_BIND_CONSTANT_BUFFER,
+            D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BIND_UNORDERED_ACCESS,
+            D3D11_BOX, D3D11_BUFFER_DESC, D3D11_CPU_ACCESS_READ, D3D11_MAPPED_SUBRESOURCE,
+            D3D11_MAP_READ, D3D11_RESOURCE_MISC_BUFFER_STRUCTURED, D3D11_SDK_VERSION,
+            D3D11_SUBRESOURCE_DATA, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING,
         },
         d3dcommon::D3D_DRIVER_TYPE_HARDWARE,
         winnt::HANDLE,
@@ -65,7 +67,31 @@ pub struct Dx11Context {
 }
 
 impl Dx11Context {
-    //
+    // Maps a staging resource for reading and hands each row to `f`.
+    unsafe fn read_mapped(
+        &self,
+        resource: *mut ID3D11Resource,
+        rows: u32,
+        row_bytes: usize,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<()> {
+        let mut mapped: D3D11_MAPPED_SUBRESOURCE = std::mem::zeroed();
+        let hr = self.inner.Map(resource, 0, D3D11_MAP_READ, 0, &mut mapped);
+
+        if hr != 0 {
+            bail!["Failed to map staging resource with COM hr=0x{:08X}", hr];
+        }
+
+        for row in 0..rows as usize {
+            f(std::slice::from_raw_parts(
+                (mapped.pData as *const u8).add(row * mapped.RowPitch as usize),
+                row_bytes,
+            ));
+        }
+
+        self.inner.Unmap(resource, 0);
+        Ok(())
+    }
 }
 
 pub struct Dx11SwapChain {
@@ -128,6 +154,9 @@ pub struct Dx11Texture2D {
     pub inner: ComPtr<ID3D11Texture2D>,
     pub rtv: ComPtr<ID3D11RenderTargetView>,
     pub uav: ComPtr<ID3D11UnorderedAccessView>,
+    pub width: u32,
+    pub height: u32,
+    pub format: DXGI_FORMAT,
 }
 
 impl Dx11Texture2D {
@@ -165,7 +194,62 @@ impl Dx11Texture2D {
                 .inner
                 .ClearRenderTargetView(rtv.as_ptr(), &[0.0, 0.0, 0.0, 1.0]);
         }
-        Ok(Self { inner, rtv, uav })
+        Ok(Self {
+            inner,
+            rtv,
+            uav,
+            width,
+            height,
+            format,
+        })
+    }
+
+    // Copies the texture back to system memory as tightly packed rows of
+    // `bytes_per_texel` each.
+    pub fn read(&self, device: &Dx11Device, bytes_per_texel: usize) -> Result<Vec<u8>> {
+        let staging_desc = D3D11_TEXTURE2D_DESC {
+            Width: self.width,
+            Height: self.height,
+            MipLevels: 1,
+            ArraySize: 1,
+            Format: self.format,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+            MiscFlags: 0,
+        };
+        let staging: ComPtr<ID3D11Texture2D> =
+            com_new(|x| unsafe { device.inner.CreateTexture2D(&staging_desc, ptr::null(), x) })?;
+        let ctx = device.immediate_context();
+        let row_bytes = self.width as usize * bytes_per_texel;
+        let mut result = Vec::with_capacity(row_bytes * self.height as usize);
+
+        unsafe {
+            ctx.inner
+                .CopyResource(staging.as_ptr() as *mut _, self.inner.as_ptr() as *mut _);
+            ctx.read_mapped(staging.as_ptr() as *mut _, self.height, row_bytes, |row| {
+                result.extend_from_slice(row)
+            })?;
+        }
+
+        Ok(result)
+    }
+
+    pub fn write(&self, ctx: &Dx11Context, data: &[u8], bytes_per_texel: usize) {
+        unsafe {
+            ctx.inner.UpdateSubresource(
+                self.inner.as_ptr() as *mut _,
+                0,
+                ptr::null(),
+                data.as_ptr() as *const _,
+                (self.width as usize * bytes_per_texel) as UINT,
+                data.len() as UINT,
+            )
+        }
     }
 }
 
@@ -173,6 +257,7 @@ impl Dx11Texture2D {
 pub struct Dx11RWStructuredBuffer<T: Copy> {
     pub inner: ComPtr<ID3D11Buffer>,
     pub uav: ComPtr<ID3D11UnorderedAccessView>,
+    pub len: usize,
     _phantom: PhantomData<T>,
 }
 
@@ -205,6 +290,137 @@ impl<T: Copy> Dx11RWStructuredBuffer<T> {
         Ok(Self {
             inner,
             uav,
+            len: data.len(),
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn read(&self, device: &Dx11Device) -> Result<Vec<T>> {
+        self.read_range(device, 0..self.len)
+    }
+
+    // The elements in `range`, as far as the buffer goes.
+    pub fn read_range(&self, device: &Dx11Device, range: Range<usize>) -> Result<Vec<T>> {
+        let end = range.end.min(self.len);
+        let count = end.saturating_sub(range.start);
+
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        let size = std::mem::size_of::<T>();
+        let byte_width = count * size;
+        let desc = D3D11_BUFFER_DESC {
+            ByteWidth: byte_width as UINT,
+            Usage: D3D11_USAGE_STAGING,
+            BindFlags: 0,
+            CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+            MiscFlags: D3D11_RESOURCE_MISC_BUFFER_STRUCTURED,
+            StructureByteStride: size as UINT,
+        };
+        let staging: ComPtr<ID3D11Buffer> =
+            com_new(|x| unsafe { device.inner.CreateBuffer(&desc, ptr::null(), x) })?;
+        let ctx = device.immediate_context();
+        let mut result = Vec::<T>::with_capacity(count);
+
+        unsafe {
+            ctx.inner.CopySubresourceRegion(
+                staging.as_ptr() as *mut _,
+                0,
+                0,
+                0,
+                0,
+                self.inner.as_ptr() as *mut _,
+                0,
+                &buffer_box(range.start * size..end * size),
+            );
+            ctx.read_mapped(staging.as_ptr() as *mut _, 1, byte_width, |data| {
+                ptr::copy_nonoverlapping(data.as_ptr(), result.as_mut_ptr() as *mut u8, byte_width);
+            })?;
+            result.set_len(count);
+        }
+
+        Ok(result)
+    }
+
+    // Writes the start of the buffer when `data` is shorter.
+    pub fn write(&self, ctx: &Dx11Context, data: &[T]) {
+        self.write_at(ctx, 0, data)
+    }
+
+    // Writes `data` from element `offset` on, as far as the buffer goes.
+    pub fn write_at(&self, ctx: &Dx11Context, offset: usize, data: &[T]) {
+        let size = std::mem::size_of::<T>();
+        let count = data.len().min(self.len.saturating_sub(offset));
+
+        if count == 0 {
+            return;
+        }
+
+        unsafe {
+            ctx.inner.UpdateSubresource(
+                self.inner.as_ptr() as *mut _,
+                0,
+                &buffer_box(offset * size..(offset + count) * size),
+                data.as_ptr() as *const _,
+                (count * size) as UINT,
+                (count * size) as UINT,
+            )
+        }
+    }
+}
+
+// A range of bytes of a buffer.
+fn buffer_box(bytes: Range<usize>) -> D3D11_BOX {
+    D3D11_BOX {
+        left: bytes.start as UINT,
+        top: 0,
+        front: 0,
+        right: bytes.end as UINT,
+        bottom: 1,
+        back: 1,
+    }
+}
+
+// Read-only structured buffer, bound to the `t` registers.
+#[derive(Clone)]
+pub struct Dx11StructuredBuffer<T: Copy> {
+    pub inner: ComPtr<ID3D11Buffer>,
+    pub srv: ComPtr<ID3D11ShaderResourceView>,
+    pub len: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy> Dx11StructuredBuffer<T> {
+    pub fn new_with_data(device: &Dx11Device, data: &[T]) -> Result<Self> {
+        let desc = D3D11_BUFFER_DESC {
+            ByteWidth: (data.len() * std::mem::size_of::<T>()) as UINT,
+            Usage: D3D11_USAGE_DEFAULT,
+            BindFlags: D3D11_BIND_SHADER_RESOURCE,
+            CPUAccessFlags: 0,
+            MiscFlags: D3D11_RESOURCE_MISC_BUFFER_STRUCTURED,
+            StructureByteStride: std::mem::size_of::<T>() as UINT,
+        };
+        let inner = com_new(|x| unsafe {
+            device.inner.CreateBuffer(
+                &desc,
+                &D3D11_SUBRESOURCE_DATA {
+                    pSysMem: data.as_ptr() as *const _,
+                    SysMemPitch: 0,
+                    SysMemSlicePitch: 0,
+                },
+                x,
+            )
+        })?;
+        let srv = com_new(|x| unsafe {
+            device
+                .inner
+                .CreateShaderResourceView(inner.as_ptr() as *mut _, ptr::null(), x)
+        })?;
+        Ok(Self {
+            inner,
+            srv,
+            len: data.len(),
             _phantom: PhantomData,
         })
     }
@@ -0,0 +1,86 @@
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+pub const DEPOSITION_DIRECT: u32 = 0;
+pub const DEPOSITION_DETERMINISTIC: u32 = 1;
+
+// How agents write the trail and nutrient fields, e.g. `deterministic,bits=16`.
+//
+// - direct: agents read, modify and write the texel in place. Fast, but on the
+//   GPU agents that hit the same texel at once lose each other's writes.
+// - deterministic: agents add their deposits and what they eat, rounded to
+//   fixed point with `bits` fractional bits, to a separate integer buffer
+//   with atomic adds. The decay stage folds the buffer into the fields before
+//   diffusing. Integer addition doesn't depend on order, so every run and
+//   every backend gives the same fields. The total change of a value in one
+//   step must stay within +-2^(31 - bits).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deposition {
+    Direct,
+    Deterministic { bits: u32 },
+}
+
+impl Deposition {
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Direct => DEPOSITION_DIRECT,
+            Self::Deterministic { .. } => DEPOSITION_DETERMINISTIC,
+        }
+    }
+
+    // Fixed-point units per unit of trail.
+    pub fn scale(self) -> f32 {
+        match self {
+            Self::Direct => 1.0,
+            Self::Deterministic { bits } => (1u32 << bits) as f32,
+        }
+    }
+}
+
+impl FromStr for Deposition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let mut deposition = match mode {
+            "direct" => Self::Direct,
+            "deterministic" => Self::Deterministic { bits: 20 },
+            _ => bail![
+                "unknown deposition {:?}, expected one of: direct, deterministic",
+                mode
+            ],
+        };
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["deposition field {:?} should be key=value", field])?;
+
+            match (&mut deposition, key) {
+                (Self::Deterministic { bits }, "bits") => {
+                    *bits = value
+                        .parse()
+                        .with_context(|| format!["invalid value for deposition field {:?}", key])?
+                }
+                _ => bail!["unexpected field {:?} for deposition {:?}", field, mode],
+            }
+        }
+
+        match deposition {
+            Self::Deterministic { bits } if !(1..=30).contains(&bits) => {
+                bail!["deposition bits must be between 1 and 30"]
+            }
+            _ => Ok(deposition),
+        }
+    }
+}
+
+impl fmt::Display for Deposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct => f.write_str("direct"),
+            Self::Deterministic { bits } => write!(f, "deterministic,bits={}", bits),
+        }
+    }
+}
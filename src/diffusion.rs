@@ -0,0 +1,298 @@
+use crate::{channels::Channel, layout::gpu_struct, Constants};
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+pub const DIFFUSION_BOX: u32 = 0;
+pub const DIFFUSION_SUBSTEP: u32 = 1;
+pub const DIFFUSION_GAUSSIAN: u32 = 2;
+pub const DIFFUSION_ANISOTROPIC: u32 = 3;
+
+// Wider kernels cost more than they are worth; the 2D anisotropic kernel
+// gets a smaller limit since its cost grows with the square. Kernels reach
+// out to three standard deviations, so these bound `sigma` and `along` /
+// `across` to a third of the radius.
+const MAX_GAUSSIAN_RADIUS: u32 = 32;
+const MAX_ANISOTROPIC_RADIUS: u32 = 12;
+
+// How the trail and nutrient fields spread, e.g. `gaussian,sigma=3`. Every
+// operator blends a texel towards a blurred value with the weight
+// `saturate(diffuse_rate * dt)`.
+//
+// - box: one pass of the 3x3 box average.
+// - substep: the box blend split into `ceil(diffuse_rate * dt)` passes of at
+//   most full weight each (up to `max`), which stays accurate when the rate
+//   or the timestep is large.
+// - gaussian: a Gaussian with standard deviation `sigma` in texels, run as a
+//   horizontal and a vertical pass.
+// - anisotropic: a Gaussian stretched to `along` texels in the direction
+//   `angle` (degrees, turning from +x towards +y) and `across` texels
+//   perpendicular to it, run as a single 2D pass.
+//
+// Kernel weights are computed once on the host so that every backend blurs
+// with the same numbers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Diffusion {
+    Box,
+    Substep { max: u32 },
+    Gaussian { sigma: f32 },
+    Anisotropic { angle: f32, along: f32, across: f32 },
+}
+
+impl Diffusion {
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Box => DIFFUSION_BOX,
+            Self::Substep { .. } => DIFFUSION_SUBSTEP,
+            Self::Gaussian { .. } => DIFFUSION_GAUSSIAN,
+            Self::Anisotropic { .. } => DIFFUSION_ANISOTROPIC,
+        }
+    }
+
+    pub fn max_substeps(self) -> u32 {
+        match self {
+            Self::Substep { max } => max,
+            _ => 1,
+        }
+    }
+
+    pub fn kernel_radius(self) -> u32 {
+        match self {
+            Self::Box | Self::Substep { .. } => 0,
+            Self::Gaussian { sigma } => radius(sigma),
+            Self::Anisotropic { along, across, .. } => radius(along.max(across)),
+        }
+    }
+
+    // Normalized weights: `2r + 1` taps for the gaussian, `(2r + 1)^2`
+    // row-major taps for the anisotropic kernel and none for the box
+    // operators.
+    pub fn kernel(self) -> Vec<f32> {
+        let r = self.kernel_radius() as i32;
+
+        match self {
+            Self::Box | Self::Substep { .. } => vec![],
+            Self::Gaussian { sigma } => normalized(
+                (-r..=r)
+                    .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+                    .collect(),
+            ),
+            Self::Anisotropic {
+                angle,
+                along,
+                across,
+            } => {
+                let (sin, cos) = angle.to_radians().sin_cos();
+                let mut weights = vec![];
+
+                for y in -r..=r {
+                    for x in -r..=r {
+                        let u = x as f32 * cos + y as f32 * sin;
+                        let v = -(x as f32) * sin + y as f32 * cos;
+                        weights.push(
+                            (-(u * u) / (2.0 * along * along) - v * v / (2.0 * across * across))
+                                .exp(),
+                        );
+                    }
+                }
+
+                normalized(weights)
+            }
+        }
+    }
+}
+
+fn radius(sigma: f32) -> u32 {
+    ((3.0 * sigma).ceil() as u32).max(1)
+}
+
+// Checks that a kernel's standard deviation is a positive number whose
+// kernel fits in `max_radius` texels.
+fn check_sigma(name: &str, sigma: f32, max_radius: u32) -> Result<()> {
+    if !(sigma > 0.0 && sigma.is_finite()) {
+        bail![
+            "diffusion {} must be a positive number, got {}",
+            name,
+            sigma
+        ];
+    }
+
+    if radius(sigma) > max_radius {
+        bail![
+            "diffusion {} must be at most {}, got {}; the kernel is limited to a radius of {} texels",
+            name,
+            max_radius as f32 / 3.0,
+            sigma,
+            max_radius
+        ];
+    }
+
+    Ok(())
+}
+
+fn normalized(weights: Vec<f32>) -> Vec<f32> {
+    let total: f32 = weights.iter().sum();
+    weights.into_iter().map(|w| w / total).collect()
+}
+
+impl FromStr for Diffusion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let mut diffusion = match mode {
+            "box" => Self::Box,
+            "substep" => Self::Substep { max: 64 },
+            "gaussian" => Self::Gaussian { sigma: 1.0 },
+            "anisotropic" => Self::Anisotropic {
+                angle: 0.0,
+                along: 2.0,
+                across: 0.5,
+            },
+            _ => bail![
+                "unknown diffusion {:?}, expected one of: box, substep, gaussian, anisotropic",
+                mode
+            ],
+        };
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["diffusion field {:?} should be key=value", field])?;
+            let invalid = || format!["invalid value for diffusion field {:?}", key];
+
+            match (&mut diffusion, key) {
+                (Self::Substep { max }, "max") => *max = value.parse().with_context(invalid)?,
+                (Self::Gaussian { sigma }, "sigma") => {
+                    *sigma = value.parse().with_context(invalid)?
+                }
+                (Self::Anisotropic { angle, .. }, "angle") => {
+                    *angle = value.parse().with_context(invalid)?
+                }
+                (Self::Anisotropic { along, .. }, "along") => {
+                    *along = value.parse().with_context(invalid)?
+                }
+                (Self::Anisotropic { across, .. }, "across") => {
+                    *across = value.parse().with_context(invalid)?
+                }
+                _ => bail!["unexpected field {:?} for diffusion {:?}", field, mode],
+            }
+        }
+
+        match diffusion {
+            Self::Substep { max: 0 } => bail!["diffusion substep max must be at least 1"],
+            Self::Gaussian { sigma } => check_sigma("sigma", sigma, MAX_GAUSSIAN_RADIUS)?,
+            Self::Anisotropic {
+                angle,
+                along,
+                across,
+            } => {
+                if !angle.is_finite() {
+                    bail!["diffusion angle must be a number, got {}", angle];
+                }
+
+                check_sigma("along", along, MAX_ANISOTROPIC_RADIUS)?;
+                check_sigma("across", across, MAX_ANISOTROPIC_RADIUS)?;
+            }
+            _ => {}
+        }
+
+        Ok(diffusion)
+    }
+}
+
+impl fmt::Display for Diffusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Box => f.write_str("box"),
+            Self::Substep { max } => write!(f, "substep,max={}", max),
+            Self::Gaussian { sigma } => write!(f, "gaussian,sigma={}", sigma),
+            Self::Anisotropic {
+                angle,
+                along,
+                across,
+            } => write!(
+                f,
+                "anisotropic,angle={},along={},across={}",
+                angle, along, across
+            ),
+        }
+    }
+}
+
+// Per-pass parameters of `decay_and_diffuse`, bound as a second constant
+// buffer. Decay and regrowth only happen in the last pass.
+gpu_struct! {
+    hlsl cbuffer PASS;
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct PassConstants {
+        pub pass_index: u32,
+        pub pass_count: u32,
+    }
+}
+
+impl PassConstants {
+    pub fn new(pass_index: u32, pass_count: u32) -> Self {
+        Self {
+            pass_index,
+            pass_count,
+            ..Default::default()
+        }
+    }
+}
+
+pub fn max_diffuse_rate(channels: &[Channel], constants: &Constants) -> f32 {
+    channels
+        .iter()
+        .map(|channel| channel.live(constants).diffuse_rate)
+        .fold(0.0, f32::max)
+}
+
+// How many times `decay_and_diffuse` runs per step, given the largest channel
+// diffusion rate.
+pub fn pass_count(constants: &Constants, max_diffuse_rate: f32, has_nutrient: bool) -> u32 {
+    match constants.diffusion {
+        DIFFUSION_GAUSSIAN => 2,
+        DIFFUSION_SUBSTEP => {
+            let max_rate = if has_nutrient {
+                max_diffuse_rate.max(constants.nutrient_diffuse_rate)
+            } else {
+                max_diffuse_rate
+            };
+
+            ((max_rate * constants.delta_time).ceil() as u32).clamp(1, constants.max_substeps)
+        }
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(s: &str) -> String {
+        s.parse::<Diffusion>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn rejects_kernels_that_dont_fit() {
+        assert_eq!(
+            "gaussian,sigma=10"
+                .parse::<Diffusion>()
+                .unwrap()
+                .kernel()
+                .len(),
+            61
+        );
+        assert!(error("gaussian,sigma=11").contains("sigma must be at most 10.666667"));
+        assert!(error("anisotropic,along=4.5").contains("along must be at most 4"));
+        assert!(error("anisotropic,across=0").contains("across must be a positive number"));
+
+        for value in ["nan", "inf", "-inf"] {
+            assert!(error(&format!["gaussian,sigma={}", value]).contains("positive number"));
+            assert!(error(&format!["anisotropic,along={}", value]).contains("positive number"));
+        }
+
+        assert!(error("anisotropic,angle=nan").contains("angle must be a number"));
+    }
+}
@@ -0,0 +1,818 @@
+// Formulas that compute a setting from the state of the simulation, e.g.
+// `30 + 15 * sin(t * 0.2)` or `if(agents > 50000, 0.5, 2) * (1 + noise(t))`.
+//
+// Variables:
+//
+// - t: simulated seconds; dt: seconds the coming step covers; step: steps
+//   taken so far.
+// - agents: live agents; initial_agents: agents at spawn, which `agents`
+//   stays at without a population model.
+// - mean_energy: the live agents' mean energy, 0 without a population model.
+// - step_ms: wall milliseconds the last step took. It differs between runs,
+//   so a formula that uses it isn't reproducible.
+// - width, height: the field's size in texels.
+// - pi, e.
+//
+// Operators, loosest first: `||`, `&&`, comparisons (`<`, `<=`, `>`, `>=`,
+// `==`, `!=`), `+ -`, `* / %`, unary `- !`, and `^` for powers. Functions:
+// sin, cos, tan, asin, acos, atan, atan2(y, x), sqrt, abs, exp, ln, log10,
+// pow(x, y), min(a, b), max(a, b), clamp(x, lo, hi), floor, ceil, round,
+// fract, sign, mix(a, b, t), smoothstep(lo, hi, x), `noise(x)` and
+// `noise(x, y)` for smooth gradient noise between -1 and 1, and
+// `if(condition, then, else)`.
+//
+// A formula is a single expression over numbers and booleans with no loops,
+// assignments or access to anything but the variables above. It's parsed and
+// type-checked when it's loaded, and errors point at the offending column.
+// Formulas are limited to `MAX_TOKENS` tokens, which bounds the size and
+// depth of the expression tree that evaluation walks.
+use anyhow::{anyhow, Result};
+use std::{
+    convert::TryInto,
+    f64::consts::{E, PI},
+};
+
+// The variables a formula sees.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Vars {
+    pub time: f64,
+    pub delta_time: f64,
+    pub step: f64,
+    pub agents: f64,
+    pub initial_agents: f64,
+    pub mean_energy: f64,
+    pub step_ms: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+type Variable = fn(&Vars) -> f64;
+
+const VARIABLES: &[(&str, Variable)] = &[
+    ("t", |v| v.time),
+    ("dt", |v| v.delta_time),
+    ("step", |v| v.step),
+    ("agents", |v| v.agents),
+    ("initial_agents", |v| v.initial_agents),
+    ("mean_energy", |v| v.mean_energy),
+    ("step_ms", |v| v.step_ms),
+    ("width", |v| v.width),
+    ("height", |v| v.height),
+];
+
+const CONSTANTS: &[(&str, f64)] = &[("pi", PI), ("e", E)];
+
+// Name, and the least and most arguments. `if` is checked separately.
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("sin", 1, 1),
+    ("cos", 1, 1),
+    ("tan", 1, 1),
+    ("asin", 1, 1),
+    ("acos", 1, 1),
+    ("atan", 1, 1),
+    ("atan2", 2, 2),
+    ("sqrt", 1, 1),
+    ("abs", 1, 1),
+    ("exp", 1, 1),
+    ("ln", 1, 1),
+    ("log10", 1, 1),
+    ("pow", 2, 2),
+    ("min", 2, 2),
+    ("max", 2, 2),
+    ("clamp", 3, 3),
+    ("floor", 1, 1),
+    ("ceil", 1, 1),
+    ("round", 1, 1),
+    ("fract", 1, 1),
+    ("sign", 1, 1),
+    ("mix", 3, 3),
+    ("smoothstep", 3, 3),
+    ("noise", 1, 2),
+];
+
+// Formulas nested deeper than this are rejected rather than risking the
+// stack while parsing.
+const MAX_DEPTH: usize = 64;
+
+// Every node of the expression tree takes at least one token, so this also
+// bounds the nodes, and the depth of left-deep chains like `1 + 1 + ...`
+// that `MAX_DEPTH` doesn't see.
+const MAX_TOKENS: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Number,
+    Bool,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Number => "a number",
+            Self::Bool => "a boolean",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+// Booleans evaluate to 0 and 1; type checking keeps them apart from numbers.
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(f64),
+    Variable(usize),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    If(Box<[Expr; 3]>),
+    Call(&'static str, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(&'static str),
+    End,
+}
+
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "==", "!=", "&&", "||", "<", ">", "!", "+", "-", "*", "/", "%", "^", "(", ")", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = vec![];
+    let mut rest = source;
+
+    loop {
+        let trimmed = rest.trim_start();
+        let at = source.len() - trimmed.len();
+        rest = trimmed;
+
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+
+        if tokens.len() == MAX_TOKENS {
+            return Err(error_at(
+                source,
+                at,
+                format!["formula is too long, the limit is {} tokens", MAX_TOKENS],
+            ));
+        }
+
+        let len = if c.is_ascii_digit() || c == '.' {
+            let mut len = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+
+            // An exponent, e.g. `1e-3`.
+            if let Some(exponent) = rest[len..].strip_prefix(['e', 'E']) {
+                let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+
+                if digits.starts_with(|c: char| c.is_ascii_digit()) {
+                    let sign = exponent.len() - digits.len();
+                    len += 1
+                        + sign
+                        + digits
+                            .find(|c: char| !c.is_ascii_digit())
+                            .unwrap_or(digits.len());
+                }
+            }
+
+            let number = rest[..len]
+                .parse()
+                .map_err(|_| error_at(source, at, format!["invalid number {:?}", &rest[..len]]))?;
+            tokens.push((Token::Number(number), at));
+            len
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push((Token::Ident(rest[..len].to_string()), at));
+            len
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| rest.starts_with(*s))
+                .ok_or_else(|| error_at(source, at, format!["unexpected character {:?}", c]))?;
+            tokens.push((Token::Symbol(symbol), at));
+            symbol.len()
+        };
+
+        rest = &rest[len..];
+    }
+
+    tokens.push((Token::End, source.len()));
+    Ok(tokens)
+}
+
+// An error pointing at byte `at` of `source`.
+fn error_at(source: &str, at: usize, message: String) -> anyhow::Error {
+    let line_start = source[..at].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[at..].find('\n').map_or(source.len(), |i| at + i);
+    let line = source[..at].matches('\n').count() + 1;
+    let column = source[line_start..at].chars().count() + 1;
+
+    anyhow!(
+        "{} at line {}, column {}\n  {}\n  {}^",
+        message,
+        line,
+        column,
+        &source[line_start..line_end],
+        " ".repeat(column - 1)
+    )
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn at(&self) -> usize {
+        self.tokens[self.next].1
+    }
+
+    fn error(&self, at: usize, message: String) -> anyhow::Error {
+        error_at(self.source, at, message)
+    }
+
+    fn is(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Token::Symbol(s) if *s == symbol)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = self.is(symbol);
+
+        if found {
+            self.next += 1;
+        }
+
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<()> {
+        if !self.eat(symbol) {
+            return Err(self.error(self.at(), format!["expected `{}`", symbol]));
+        }
+
+        Ok(())
+    }
+
+    // Checks that the operand at `at` has type `expected`.
+    fn require(&self, (expr, ty): (Expr, Type), expected: Type, at: usize) -> Result<Expr> {
+        if ty != expected {
+            return Err(self.error(
+                at,
+                format!["expected {}, found {}", expected.name(), ty.name()],
+            ));
+        }
+
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<(Expr, Type)> {
+        self.logical("||", Op::Or, Self::and)
+    }
+
+    fn and(&mut self) -> Result<(Expr, Type)> {
+        self.logical("&&", Op::And, Self::comparison)
+    }
+
+    fn logical(
+        &mut self,
+        symbol: &str,
+        op: Op,
+        operand: fn(&mut Self) -> Result<(Expr, Type)>,
+    ) -> Result<(Expr, Type)> {
+        let at = self.at();
+        let mut lhs = operand(self)?;
+
+        while self.eat(symbol) {
+            let rhs_at = self.at();
+            let rhs = operand(self)?;
+            let lhs_expr = self.require(lhs, Type::Bool, at)?;
+            let rhs_expr = self.require(rhs, Type::Bool, rhs_at)?;
+            lhs = (
+                Expr::Binary(op, Box::new(lhs_expr), Box::new(rhs_expr)),
+                Type::Bool,
+            );
+        }
+
+        Ok(lhs)
+    }
+
+    fn comparison(&mut self) -> Result<(Expr, Type)> {
+        const COMPARISONS: &[(&str, Op)] = &[
+            ("<=", Op::LessEqual),
+            (">=", Op::GreaterEqual),
+            ("==", Op::Equal),
+            ("!=", Op::NotEqual),
+            ("<", Op::Less),
+            (">", Op::Greater),
+        ];
+        let at = self.at();
+        let lhs = self.sum()?;
+        let op = COMPARISONS
+            .iter()
+            .find(|(symbol, _)| self.is(symbol))
+            .map(|(_, op)| *op);
+        let op = match op {
+            Some(op) => op,
+            None => return Ok(lhs),
+        };
+
+        self.next += 1;
+
+        let rhs_at = self.at();
+        let rhs = self.sum()?;
+        let (lhs, rhs) = match op {
+            // Booleans compare for equality with each other.
+            Op::Equal | Op::NotEqual if lhs.1 == Type::Bool => (
+                self.require(lhs, Type::Bool, at)?,
+                self.require(rhs, Type::Bool, rhs_at)?,
+            ),
+            _ => (
+                self.require(lhs, Type::Number, at)?,
+                self.require(rhs, Type::Number, rhs_at)?,
+            ),
+        };
+
+        if COMPARISONS.iter().any(|(symbol, _)| self.is(symbol)) {
+            return Err(self.error(self.at(), "comparisons don't chain; use `&&`".to_string()));
+        }
+
+        Ok((Expr::Binary(op, Box::new(lhs), Box::new(rhs)), Type::Bool))
+    }
+
+    fn sum(&mut self) -> Result<(Expr, Type)> {
+        self.arithmetic(&[("+", Op::Add), ("-", Op::Sub)], Self::product)
+    }
+
+    fn product(&mut self) -> Result<(Expr, Type)> {
+        self.arithmetic(
+            &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Rem)],
+            Self::unary,
+        )
+    }
+
+    fn arithmetic(
+        &mut self,
+        ops: &[(&str, Op)],
+        operand: fn(&mut Self) -> Result<(Expr, Type)>,
+    ) -> Result<(Expr, Type)> {
+        let at = self.at();
+        let mut lhs = operand(self)?;
+
+        while let Some(op) = ops
+            .iter()
+            .find(|(symbol, _)| self.is(symbol))
+            .map(|(_, op)| *op)
+        {
+            self.next += 1;
+
+            let rhs_at = self.at();
+            let rhs = operand(self)?;
+            let lhs_expr = self.require(lhs, Type::Number, at)?;
+            let rhs_expr = self.require(rhs, Type::Number, rhs_at)?;
+            lhs = (
+                Expr::Binary(op, Box::new(lhs_expr), Box::new(rhs_expr)),
+                Type::Number,
+            );
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<(Expr, Type)> {
+        self.depth += 1;
+
+        if self.depth > MAX_DEPTH {
+            return Err(self.error(self.at(), "formula nests too deeply".to_string()));
+        }
+
+        let result = if self.eat("-") {
+            let at = self.at();
+            let operand = self.unary()?;
+            let operand = self.require(operand, Type::Number, at)?;
+            (Expr::Neg(Box::new(operand)), Type::Number)
+        } else if self.eat("!") {
+            let at = self.at();
+            let operand = self.unary()?;
+            let operand = self.require(operand, Type::Bool, at)?;
+            (Expr::Not(Box::new(operand)), Type::Bool)
+        } else {
+            self.power()?
+        };
+
+        self.depth -= 1;
+        Ok(result)
+    }
+
+    // `^` binds tighter than unary minus on its left, so `-x^2` is `-(x^2)`,
+    // and is right associative.
+    fn power(&mut self) -> Result<(Expr, Type)> {
+        let at = self.at();
+        let base = self.primary()?;
+
+        if !self.eat("^") {
+            return Ok(base);
+        }
+
+        let exponent_at = self.at();
+        let exponent = self.unary()?;
+        let base = self.require(base, Type::Number, at)?;
+        let exponent = self.require(exponent, Type::Number, exponent_at)?;
+
+        Ok((
+            Expr::Binary(Op::Pow, Box::new(base), Box::new(exponent)),
+            Type::Number,
+        ))
+    }
+
+    fn primary(&mut self) -> Result<(Expr, Type)> {
+        let at = self.at();
+        let token = self.peek().clone();
+
+        self.next += 1;
+
+        match token {
+            Token::Number(n) => Ok((Expr::Number(n), Type::Number)),
+            Token::Symbol("(") => {
+                let inner = self.or()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Ident(name) if self.eat("(") => self.call(&name, at),
+            Token::Ident(name) => {
+                if let Some(i) = VARIABLES.iter().position(|(v, _)| *v == name) {
+                    Ok((Expr::Variable(i), Type::Number))
+                } else if let Some((_, value)) = CONSTANTS.iter().find(|(c, _)| *c == name) {
+                    Ok((Expr::Number(*value), Type::Number))
+                } else if name == "true" || name == "false" {
+                    Ok((Expr::Number((name == "true") as u8 as f64), Type::Bool))
+                } else {
+                    Err(self.error(
+                        at,
+                        format![
+                            "unknown variable `{}`, expected one of: {}, pi, e",
+                            name,
+                            VARIABLES
+                                .iter()
+                                .map(|(v, _)| *v)
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ],
+                    ))
+                }
+            }
+            Token::End => Err(self.error(at, "unexpected end of formula".to_string())),
+            Token::Symbol(symbol) => Err(self.error(at, format!["unexpected `{}`", symbol])),
+        }
+    }
+
+    // A call of `name`, whose `(` has been read.
+    fn call(&mut self, name: &str, at: usize) -> Result<(Expr, Type)> {
+        let mut args = vec![];
+
+        if !self.eat(")") {
+            loop {
+                let arg_at = self.at();
+                args.push((self.or()?, arg_at));
+
+                if self.eat(")") {
+                    break;
+                }
+
+                if !self.eat(",") {
+                    return Err(self.error(self.at(), "expected `,` or `)`".to_string()));
+                }
+            }
+        }
+
+        if name == "if" {
+            let [condition, then, otherwise]: [_; 3] =
+                args.try_into().map_err(|args: Vec<_>| {
+                    self.error(at, format!["`if` takes 3 arguments, found {}", args.len()])
+                })?;
+            let ty = then.0 .1;
+            let condition = self.require(condition.0, Type::Bool, condition.1)?;
+            let then = self.require(then.0, ty, then.1)?;
+            let otherwise = self.require(otherwise.0, ty, otherwise.1)?;
+
+            return Ok((Expr::If(Box::new([condition, then, otherwise])), ty));
+        }
+
+        let (name, min, max) = FUNCTIONS
+            .iter()
+            .find(|(f, _, _)| *f == name)
+            .ok_or_else(|| self.error(at, format!["unknown function `{}`", name]))?;
+
+        if args.len() < *min || args.len() > *max {
+            let expected = match (min, max) {
+                (1, 1) => "1 argument".to_string(),
+                _ if min == max => format!["{} arguments", min],
+                _ => format!["{} or {} arguments", min, max],
+            };
+            return Err(self.error(
+                at,
+                format!["`{}` takes {}, found {}", name, expected, args.len()],
+            ));
+        }
+
+        let args = args
+            .into_iter()
+            .map(|(arg, arg_at)| self.require(arg, Type::Number, arg_at))
+            .collect::<Result<_>>()?;
+
+        Ok((Expr::Call(name, args), Type::Number))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Formula {
+    source: String,
+    expr: Expr,
+}
+
+impl Formula {
+    // Parses and type-checks `source`, which has to compute a number.
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            source,
+            tokens: tokenize(source)?,
+            next: 0,
+            depth: 0,
+        };
+        let at = parser.at();
+        let result = parser.or()?;
+
+        if *parser.peek() != Token::End {
+            return Err(parser.error(parser.at(), "expected an operator".to_string()));
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            expr: parser.require(result, Type::Number, at)?,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval(&self, vars: &Vars) -> f64 {
+        eval(&self.expr, vars)
+    }
+}
+
+fn eval(expr: &Expr, vars: &Vars) -> f64 {
+    let truth = |b: bool| b as u8 as f64;
+
+    match expr {
+        Expr::Number(n) => *n,
+        Expr::Variable(i) => (VARIABLES[*i].1)(vars),
+        Expr::Neg(operand) => -eval(operand, vars),
+        Expr::Not(operand) => truth(eval(operand, vars) == 0.0),
+        Expr::If(args) => {
+            let [condition, then, otherwise] = &**args;
+
+            if eval(condition, vars) != 0.0 {
+                eval(then, vars)
+            } else {
+                eval(otherwise, vars)
+            }
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = eval(lhs, vars);
+
+            // `&&` and `||` skip the right side like everywhere else.
+            match op {
+                Op::And if lhs == 0.0 => return 0.0,
+                Op::Or if lhs != 0.0 => return 1.0,
+                _ => (),
+            }
+
+            let rhs = eval(rhs, vars);
+
+            match op {
+                Op::Or | Op::And => truth(rhs != 0.0),
+                Op::Less => truth(lhs < rhs),
+                Op::LessEqual => truth(lhs <= rhs),
+                Op::Greater => truth(lhs > rhs),
+                Op::GreaterEqual => truth(lhs >= rhs),
+                Op::Equal => truth(lhs == rhs),
+                Op::NotEqual => truth(lhs != rhs),
+                Op::Add => lhs + rhs,
+                Op::Sub => lhs - rhs,
+                Op::Mul => lhs * rhs,
+                Op::Div => lhs / rhs,
+                Op::Rem => lhs.rem_euclid(rhs),
+                Op::Pow => lhs.powf(rhs),
+            }
+        }
+        Expr::Call(name, args) => {
+            let a: Vec<f64> = args.iter().map(|arg| eval(arg, vars)).collect();
+
+            match (*name, &a[..]) {
+                ("sin", [x]) => x.sin(),
+                ("cos", [x]) => x.cos(),
+                ("tan", [x]) => x.tan(),
+                ("asin", [x]) => x.asin(),
+                ("acos", [x]) => x.acos(),
+                ("atan", [x]) => x.atan(),
+                ("atan2", [y, x]) => y.atan2(*x),
+                ("sqrt", [x]) => x.sqrt(),
+                ("abs", [x]) => x.abs(),
+                ("exp", [x]) => x.exp(),
+                ("ln", [x]) => x.ln(),
+                ("log10", [x]) => x.log10(),
+                ("pow", [x, y]) => x.powf(*y),
+                ("min", [a, b]) => a.min(*b),
+                ("max", [a, b]) => a.max(*b),
+                ("clamp", [x, lo, hi]) => x.max(*lo).min(*hi),
+                ("floor", [x]) => x.floor(),
+                ("ceil", [x]) => x.ceil(),
+                ("round", [x]) => x.round(),
+                ("fract", [x]) => x - x.floor(),
+                ("sign", [x]) if *x == 0.0 => 0.0,
+                ("sign", [x]) => x.signum(),
+                ("mix", [a, b, t]) => a + (b - a) * t,
+                ("smoothstep", [lo, hi, x]) => {
+                    let t = ((x - lo) / (hi - lo)).clamp(0.0, 1.0);
+                    t * t * (3.0 - 2.0 * t)
+                }
+                ("noise", [x]) => noise(*x, 0.0),
+                ("noise", [x, y]) => noise(*x, *y),
+                _ => unreachable!["`{}` was checked at load", name],
+            }
+        }
+    }
+}
+
+fn hash(x: i64, y: i64) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+
+// 2D gradient noise scaled to about [-1, 1]. Along a line of constant `y`
+// it's smooth 1D noise.
+fn noise(x: f64, y: f64) -> f64 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let corner = |dx: f64, dy: f64| {
+        let angle =
+            hash(x0 as i64 + dx as i64, y0 as i64 + dy as i64) as f64 / u32::MAX as f64 * PI * 2.0;
+        angle.cos() * (fx - dx) + angle.sin() * (fy - dy)
+    };
+    let (u, v) = (fade(fx), fade(fy));
+    let bottom = corner(0.0, 0.0) + (corner(1.0, 0.0) - corner(0.0, 0.0)) * u;
+    let top = corner(0.0, 1.0) + (corner(1.0, 1.0) - corner(0.0, 1.0)) * u;
+
+    ((bottom + (top - bottom) * v) * 2.0_f64.sqrt()).clamp(-1.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> f64 {
+        Formula::parse(source).unwrap().eval(&Vars {
+            time: 2.0,
+            agents: 100.0,
+            ..Vars::default()
+        })
+    }
+
+    fn error(source: &str) -> String {
+        Formula::parse(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3 ^ 2"), 19.0);
+        assert_eq!(eval("-2 ^ 2"), -4.0);
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("-7 % 3"), 2.0);
+        assert_eq!(eval("1e-3 * 1E3"), 1.0);
+    }
+
+    #[test]
+    fn variables_and_conditions() {
+        assert_eq!(eval("t * 10 + agents"), 120.0);
+        assert_eq!(eval("if(agents > 50 && t <= 2, 1, 2)"), 1.0);
+        assert_eq!(eval("if(!(t == 2) || false, 1, 2)"), 2.0);
+        assert_eq!(eval("if(true, if(false, 1, 2), 3)"), 2.0);
+        assert_eq!(eval("clamp(agents, 0, pi)"), PI);
+        assert_eq!(eval("noise(t)"), eval("noise(t, 0)"));
+        // The right side is skipped, like it would be anywhere else.
+        assert_eq!(eval("if(false && 1 / 0 > 0, 1, 2)"), 2.0);
+    }
+
+    #[test]
+    fn noise_is_bounded() {
+        for i in 0..1000 {
+            let x = i as f64 * 0.37 - 150.0;
+            assert!((-1.0..=1.0).contains(&noise(x, x * 0.5)));
+        }
+        // Integer lattice points are zero crossings.
+        assert_eq!(noise(3.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_column() {
+        assert_eq!(
+            error("1 + * 2"),
+            "unexpected `*` at line 1, column 5\n  1 + * 2\n      ^"
+        );
+        assert!(error("1 +\n  foo").starts_with("unknown variable `foo`, expected one of: t, dt,"));
+        assert!(error("1 +\n  foo").contains("at line 2, column 3\n    foo\n    ^"));
+        assert!(error("sin(1").starts_with("expected `,` or `)` at line 1, column 6"));
+        assert!(error("(1").starts_with("expected `)` at line 1, column 3"));
+        assert!(error("1 2").starts_with("expected an operator at line 1, column 3"));
+        assert!(
+            error("1 < 2 < 3").starts_with("comparisons don't chain; use `&&` at line 1, column 7")
+        );
+        assert!(error("2 # 3").starts_with("unexpected character '#' at line 1, column 3"));
+        assert!(error("1..2").starts_with("invalid number \"1..2\" at line 1, column 1"));
+        assert!(error("").starts_with("unexpected end of formula at line 1, column 1"));
+    }
+
+    #[test]
+    fn type_errors_point_at_the_operand() {
+        assert!(error("1 + (2 > 1)")
+            .starts_with("expected a number, found a boolean at line 1, column 5"));
+        assert!(error("if(1, 2, 3)")
+            .starts_with("expected a boolean, found a number at line 1, column 4"));
+        assert!(error("if(t > 1, 2, t < 1)")
+            .starts_with("expected a number, found a boolean at line 1, column 14"));
+        assert!(error("!t").starts_with("expected a boolean, found a number at line 1, column 2"));
+        assert!(
+            error("t > 1").starts_with("expected a number, found a boolean at line 1, column 1")
+        );
+        assert!(error("(t > 1) == 2")
+            .starts_with("expected a boolean, found a number at line 1, column 12"));
+    }
+
+    #[test]
+    fn calls_are_checked() {
+        assert!(error("min(1)").starts_with("`min` takes 2 arguments, found 1 at line 1, column 1"));
+        assert!(error("noise()").starts_with("`noise` takes 1 or 2 arguments, found 0"));
+        assert!(error("sin(1, 2)").starts_with("`sin` takes 1 argument, found 2"));
+        assert!(error("if(true, 1)").starts_with("`if` takes 3 arguments, found 2"));
+        assert!(error("system(1)").starts_with("unknown function `system`"));
+    }
+
+    #[test]
+    fn size_is_bounded() {
+        // Left-deep chains just under the limit still work.
+        let chain = vec!["1"; MAX_TOKENS / 2].join("+");
+        assert_eq!(eval(&chain), (MAX_TOKENS / 2) as f64);
+        let chain = vec!["true"; MAX_TOKENS / 2 - 4].join("&&");
+        assert_eq!(eval(&format!["if({}, 1, 0)", chain]), 1.0);
+
+        let long = vec!["1"; MAX_TOKENS].join("+");
+        assert!(error(&long).starts_with("formula is too long, the limit is 512 tokens"));
+        let long = vec!["true"; 100_000].join("&&");
+        assert!(error(&long).starts_with("formula is too long"));
+
+        let deep = format![
+            "{}1{}",
+            "(".repeat(MAX_DEPTH + 1),
+            ")".repeat(MAX_DEPTH + 1)
+        ];
+        assert!(error(&deep).starts_with("formula nests too deeply"));
+        let deep = format!["{}1", "-".repeat(MAX_DEPTH + 1)];
+        assert!(error(&deep).starts_with("formula nests too deeply"));
+    }
+}
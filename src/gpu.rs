@@ -0,0 +1,621 @@
+use crate::{
+    backend::{check_agent_capacity, trail_rows, SimulationBackend, TrailBuffer},
+    brush::AgentBrush,
+    channels::{Channel, Interaction, SimulationTables},
+    cpu::CpuBackend,
+    d3d11::{
+        Dx11ComputeShader, Dx11ConstantBuffer, Dx11Context, Dx11Device, Dx11RWStructuredBuffer,
+        Dx11StructuredBuffer, Dx11Texture2D,
+    },
+    deposition::DEPOSITION_DETERMINISTIC,
+    diffusion::{max_diffuse_rate, pass_count, PassConstants},
+    parallel::ParallelCpuBackend,
+    shaders,
+    species::Species,
+    Agent, Constants, Vec4,
+};
+use anyhow::{bail, Result};
+use eiz::com::ComPtr;
+use std::{ops::Range, ptr};
+use winapi::{shared::dxgiformat::DXGI_FORMAT_R16G16B16A16_FLOAT, um::d3d11::ID3D11Resource};
+
+const RGBA16F_TEXEL_BYTES: usize = 8;
+const UAV_COUNT: usize = 7;
+const SRV_COUNT: usize = 6;
+
+#[derive(Clone)]
+pub struct Dx11Backend {
+    device: Dx11Device,
+    width: u32,
+    height: u32,
+    // The trail field composited through the channel colors, refreshed at
+    // the end of every `step`.
+    pub display_texture: Dx11Texture2D,
+    trail: Option<Dx11RWStructuredBuffer<f32>>,
+    diffused_trail: Option<Dx11RWStructuredBuffer<f32>>,
+    // Sized for the reserved capacity, of which the first `agent_count`
+    // entries are live.
+    agents: Option<Dx11RWStructuredBuffer<Agent>>,
+    agent_count: usize,
+    agent_capacity: usize,
+    species: Option<Dx11StructuredBuffer<Species>>,
+    channels: Option<Dx11StructuredBuffer<Channel>>,
+    interactions: Option<Dx11StructuredBuffer<Interaction>>,
+    obstacles: Option<Dx11StructuredBuffer<u32>>,
+    nutrient: Option<Dx11RWStructuredBuffer<f32>>,
+    diffused_nutrient: Option<Dx11RWStructuredBuffer<f32>>,
+    nutrient_capacity: Option<Dx11StructuredBuffer<f32>>,
+    diffusion_kernel: Option<Dx11StructuredBuffer<f32>>,
+    // Fixed-point deposits of deterministic mode, one per trail value and one
+    // per texel for the nutrient.
+    deposits: Option<Dx11RWStructuredBuffer<i32>>,
+    // A host copy of the channel table, which decides the number of substeps
+    // without reading the table back.
+    channel_table: Vec<Channel>,
+    advance_agents: Dx11ComputeShader,
+    resolve_deposits: Dx11ComputeShader,
+    decay_and_diffuse: Dx11ComputeShader,
+    compose: Dx11ComputeShader,
+    move_agents: Dx11ComputeShader,
+    constants: Dx11ConstantBuffer<Constants>,
+    pass_constants: Dx11ConstantBuffer<PassConstants>,
+    brush_constants: Dx11ConstantBuffer<AgentBrush>,
+}
+
+fn structured_buffer<T: Copy>(
+    device: &Dx11Device,
+    data: &[T],
+) -> Result<Option<Dx11StructuredBuffer<T>>> {
+    Ok(if data.is_empty() {
+        None
+    } else {
+        Some(Dx11StructuredBuffer::new_with_data(device, data)?)
+    })
+}
+
+impl Dx11Backend {
+    pub fn new(device: &Dx11Device, width: u32, height: u32) -> Result<Self> {
+        Ok(Self {
+            device: device.clone(),
+            width,
+            height,
+            display_texture: Dx11Texture2D::new(
+                device,
+                width,
+                height,
+                DXGI_FORMAT_R16G16B16A16_FLOAT,
+            )?,
+            trail: None,
+            diffused_trail: None,
+            agents: None,
+            agent_count: 0,
+            agent_capacity: 0,
+            species: None,
+            channels: None,
+            interactions: None,
+            obstacles: None,
+            nutrient: None,
+            diffused_nutrient: None,
+            nutrient_capacity: None,
+            diffusion_kernel: None,
+            deposits: None,
+            channel_table: vec![],
+            advance_agents: Dx11ComputeShader::new(device, shaders::SLIME_ADVANCE_AGENTS_CS)?,
+            resolve_deposits: Dx11ComputeShader::new(device, shaders::SLIME_RESOLVE_DEPOSITS_CS)?,
+            decay_and_diffuse: Dx11ComputeShader::new(device, shaders::SLIME_DECAY_AND_DIFFUSE_CS)?,
+            compose: Dx11ComputeShader::new(device, shaders::SLIME_COMPOSE_CS)?,
+            move_agents: Dx11ComputeShader::new(device, shaders::SLIME_MOVE_AGENTS_CS)?,
+            constants: Dx11ConstantBuffer::new_with_data(device, &[Constants::default()])?,
+            pass_constants: Dx11ConstantBuffer::new_with_data(device, &[PassConstants::default()])?,
+            brush_constants: Dx11ConstantBuffer::new_with_data(device, &[AgentBrush::default()])?,
+        })
+    }
+
+    fn trail_buffer(&self, buffer: TrailBuffer) -> Result<&Dx11RWStructuredBuffer<f32>> {
+        let trail = match buffer {
+            TrailBuffer::Current => &self.trail,
+            TrailBuffer::Scratch => &self.diffused_trail,
+        };
+
+        match trail {
+            Some(trail) => Ok(trail),
+            None => bail!["the trail field has no channels yet; upload the tables first"],
+        }
+    }
+
+    unsafe fn bind(&self, ctx: &Dx11Context) {
+        let uav = |buffer: &Option<Dx11RWStructuredBuffer<_>>| {
+            buffer.as_ref().map_or(ptr::null_mut(), |b| b.uav.as_ptr())
+        };
+
+        ctx.inner.CSSetConstantBuffers(
+            0,
+            3,
+            [
+                self.constants.inner.as_ptr(),
+                self.pass_constants.inner.as_ptr(),
+                self.brush_constants.inner.as_ptr(),
+            ]
+            .as_ptr(),
+        );
+        ctx.inner.CSSetUnorderedAccessViews(
+            0,
+            UAV_COUNT as u32,
+            [
+                uav(&self.trail),
+                uav(&self.diffused_trail),
+                self.agents
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |agents| agents.uav.as_ptr()),
+                self.display_texture.uav.as_ptr(),
+                uav(&self.nutrient),
+                uav(&self.diffused_nutrient),
+                self.deposits
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |deposits| deposits.uav.as_ptr()),
+            ]
+            .as_ptr(),
+            ptr::null(),
+        );
+        ctx.inner.CSSetShaderResources(
+            0,
+            SRV_COUNT as u32,
+            [
+                self.species
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |b| b.srv.as_ptr()),
+                self.channels
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |b| b.srv.as_ptr()),
+                self.interactions
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |b| b.srv.as_ptr()),
+                self.obstacles
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |b| b.srv.as_ptr()),
+                self.nutrient_capacity
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |b| b.srv.as_ptr()),
+                self.diffusion_kernel
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |b| b.srv.as_ptr()),
+            ]
+            .as_ptr(),
+        );
+    }
+
+    // Composites the current field into the display texture.
+    unsafe fn compose_display(&self, ctx: &Dx11Context) {
+        self.bind(ctx);
+        ctx.inner
+            .CSSetShader(self.compose.inner.as_ptr(), ptr::null_mut(), 0);
+        ctx.inner
+            .Dispatch(self.width / 8 + 1, self.height / 8 + 1, 1);
+        Self::unbind(ctx);
+    }
+
+    // Unbind so the display texture can be copied or read back.
+    unsafe fn unbind(ctx: &Dx11Context) {
+        ctx.inner.CSSetUnorderedAccessViews(
+            0,
+            UAV_COUNT as u32,
+            [ptr::null_mut(); UAV_COUNT].as_ptr(),
+            ptr::null(),
+        );
+        ctx.inner
+            .CSSetShaderResources(0, SRV_COUNT as u32, [ptr::null_mut(); SRV_COUNT].as_ptr());
+    }
+}
+
+impl SimulationBackend for Dx11Backend {
+    fn upload_tables(&mut self, tables: &SimulationTables) -> Result<()> {
+        let len = self.width as usize * self.height as usize * tables.channels.len();
+
+        if self.trail.as_ref().map_or(0, |trail| trail.len) != len {
+            let zeros = vec![0.0f32; len];
+            self.trail = Some(Dx11RWStructuredBuffer::new_with_data(&self.device, &zeros)?);
+            self.diffused_trail =
+                Some(Dx11RWStructuredBuffer::new_with_data(&self.device, &zeros)?);
+            let deposits = vec![0i32; len + self.width as usize * self.height as usize];
+            self.deposits = Some(Dx11RWStructuredBuffer::new_with_data(
+                &self.device,
+                &deposits,
+            )?);
+        }
+
+        self.species = structured_buffer(&self.device, &tables.species)?;
+        self.channels = structured_buffer(&self.device, &tables.channels)?;
+        self.interactions = structured_buffer(&self.device, &tables.interactions)?;
+        self.obstacles = structured_buffer(&self.device, &tables.obstacles)?;
+
+        let capacity = &tables.nutrient_capacity;
+
+        if self.nutrient.as_ref().map_or(0, |nutrient| nutrient.len) != capacity.len() {
+            self.nutrient = None;
+            self.diffused_nutrient = None;
+
+            if !capacity.is_empty() {
+                self.nutrient = Some(Dx11RWStructuredBuffer::new_with_data(
+                    &self.device,
+                    capacity,
+                )?);
+                self.diffused_nutrient = Some(Dx11RWStructuredBuffer::new_with_data(
+                    &self.device,
+                    capacity,
+                )?);
+            }
+        }
+
+        self.nutrient_capacity = structured_buffer(&self.device, capacity)?;
+        self.diffusion_kernel = structured_buffer(&self.device, &tables.diffusion_kernel)?;
+        self.channel_table = tables.channels.clone();
+        Ok(())
+    }
+
+    fn reserve_agents(&mut self, capacity: usize) -> Result<()> {
+        self.agent_capacity = capacity;
+        Ok(())
+    }
+
+    fn upload_agents(&mut self, agents: &[Agent]) -> Result<()> {
+        match &self.agents {
+            Some(buffer) if buffer.len >= agents.len() => {
+                buffer.write(&self.device.immediate_context(), agents)
+            }
+            _ => {
+                let len = agents.len().max(self.agent_capacity);
+                let mut data = agents.to_vec();
+                data.resize(len, Agent::default());
+                self.agents = if data.is_empty() {
+                    None
+                } else {
+                    Some(Dx11RWStructuredBuffer::new_with_data(&self.device, &data)?)
+                };
+            }
+        }
+
+        self.agent_count = agents.len();
+        Ok(())
+    }
+
+    fn step(&mut self, constants: &Constants, count: u32) -> Result<()> {
+        let ctx = self.device.immediate_context();
+        let (width, height) = (self.width, self.height);
+
+        self.trail_buffer(TrailBuffer::Current)?;
+        let passes = pass_count(
+            constants,
+            max_diffuse_rate(&self.channel_table, constants),
+            self.nutrient.is_some(),
+        );
+
+        unsafe {
+            for i in 0..count {
+                // Every substep draws its own random numbers.
+                let step_constants = Constants {
+                    step: constants.step.wrapping_add(i),
+                    ..*constants
+                };
+                self.constants.replace(&ctx, &[step_constants]);
+                self.bind(&ctx);
+
+                if self.agents.is_some() {
+                    ctx.inner
+                        .CSSetShader(self.advance_agents.inner.as_ptr(), ptr::null_mut(), 0);
+                    ctx.inner.Dispatch(constants.num_agents / 32 + 1, 1, 1);
+                }
+
+                if constants.deposition == DEPOSITION_DETERMINISTIC {
+                    ctx.inner
+                        .CSSetShader(self.resolve_deposits.inner.as_ptr(), ptr::null_mut(), 0);
+                    ctx.inner.Dispatch(width / 8 + 1, height / 8 + 1, 1);
+                }
+
+                ctx.inner
+                    .CSSetShader(self.decay_and_diffuse.inner.as_ptr(), ptr::null_mut(), 0);
+
+                for pass in 0..passes {
+                    self.pass_constants
+                        .replace(&ctx, &[PassConstants::new(pass, passes)]);
+                    self.bind(&ctx);
+                    ctx.inner.Dispatch(width / 8 + 1, height / 8 + 1, 1);
+                    std::mem::swap(&mut self.trail, &mut self.diffused_trail);
+                    std::mem::swap(&mut self.nutrient, &mut self.diffused_nutrient);
+                }
+            }
+
+            self.compose_display(&ctx);
+        }
+
+        Ok(())
+    }
+
+    fn read_trail_buffer(&self, buffer: TrailBuffer) -> Result<Vec<f32>> {
+        self.trail_buffer(buffer)?.read(&self.device)
+    }
+
+    fn write_trail_buffer(&mut self, buffer: TrailBuffer, values: &[f32]) -> Result<()> {
+        let trail = self.trail_buffer(buffer)?;
+
+        if values.len() != trail.len {
+            bail![
+                "trail buffer has {} values, expected {}",
+                values.len(),
+                trail.len
+            ];
+        }
+
+        trail.write(&self.device.immediate_context(), values);
+        Ok(())
+    }
+
+    fn read_trail_rows(&self, buffer: TrailBuffer, rows: Range<u32>) -> Result<Vec<f32>> {
+        let range = trail_rows(self.width, self.height, self.channel_table.len(), rows)?;
+        self.trail_buffer(buffer)?.read_range(&self.device, range)
+    }
+
+    fn write_trail_rows(
+        &mut self,
+        buffer: TrailBuffer,
+        rows: Range<u32>,
+        values: &[f32],
+    ) -> Result<()> {
+        let range = trail_rows(self.width, self.height, self.channel_table.len(), rows)?;
+
+        if values.len() != range.len() {
+            bail![
+                "trail rows have {} values, expected {}",
+                values.len(),
+                range.len()
+            ];
+        }
+
+        self.trail_buffer(buffer)?
+            .write_at(&self.device.immediate_context(), range.start, values);
+        Ok(())
+    }
+
+    fn read_nutrient(&self) -> Result<Vec<f32>> {
+        match &self.nutrient {
+            Some(nutrient) => nutrient.read(&self.device),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn write_nutrient(&mut self, values: &[f32]) -> Result<()> {
+        let len = self.nutrient.as_ref().map_or(0, |nutrient| nutrient.len);
+
+        if values.len() != len {
+            bail![
+                "nutrient field has {} values, expected {}",
+                values.len(),
+                len
+            ];
+        }
+
+        if let Some(nutrient) = &self.nutrient {
+            nutrient.write(&self.device.immediate_context(), values);
+        }
+        Ok(())
+    }
+
+    fn read_agents(&self) -> Result<Vec<Agent>> {
+        match &self.agents {
+            Some(agents) => agents.read_range(&self.device, 0..self.agent_count),
+            None => Ok(vec![]),
+        }
+    }
+
+    // Writes the agents after the live ones, in the storage `upload_agents`
+    // sized for the capacity.
+    fn append_agents(&mut self, agents: &[Agent]) -> Result<()> {
+        let len = self.agents.as_ref().map_or(0, |buffer| buffer.len);
+        check_agent_capacity(self.agent_count, agents.len(), len)?;
+
+        if let Some(buffer) = &self.agents {
+            buffer.write_at(&self.device.immediate_context(), self.agent_count, agents);
+        }
+
+        self.agent_count += agents.len();
+        Ok(())
+    }
+
+    fn move_agents(&mut self, constants: &Constants, brush: &AgentBrush) -> Result<()> {
+        if self.agents.is_none() {
+            return Ok(());
+        }
+
+        let ctx = self.device.immediate_context();
+        let num_agents = self.agent_count as u32;
+
+        unsafe {
+            self.constants.replace(
+                &ctx,
+                &[Constants {
+                    num_agents,
+                    ..*constants
+                }],
+            );
+            self.brush_constants.replace(&ctx, &[*brush]);
+            self.bind(&ctx);
+            ctx.inner
+                .CSSetShader(self.move_agents.inner.as_ptr(), ptr::null_mut(), 0);
+            ctx.inner.Dispatch(num_agents / 32 + 1, 1, 1);
+            Self::unbind(&ctx);
+        }
+
+        Ok(())
+    }
+
+    fn read_display(&self) -> Result<Vec<Vec4>> {
+        let bytes = self
+            .display_texture
+            .read(&self.device, RGBA16F_TEXEL_BYTES)?;
+
+        Ok(bytes
+            .chunks_exact(RGBA16F_TEXEL_BYTES)
+            .map(|texel| {
+                let c = |i: usize| f16_to_f32(u16::from_le_bytes([texel[i * 2], texel[i * 2 + 1]]));
+                Vec4 {
+                    x: c(0),
+                    y: c(1),
+                    z: c(2),
+                    w: c(3),
+                }
+            })
+            .collect())
+    }
+
+    fn refresh_display(&mut self, constants: &Constants) -> Result<()> {
+        let ctx = self.device.immediate_context();
+        self.trail_buffer(TrailBuffer::Current)?;
+
+        unsafe {
+            self.constants.replace(&ctx, &[*constants]);
+            self.compose_display(&ctx);
+        }
+
+        Ok(())
+    }
+}
+
+// Copies the composited trail field into a swap chain back buffer of the same
+// size and format.
+pub trait Dx11Present {
+    fn present(
+        &self,
+        device: &Dx11Device,
+        target: &ComPtr<ID3D11Resource>,
+        width: u32,
+    ) -> Result<()>;
+}
+
+impl Dx11Present for Dx11Backend {
+    fn present(
+        &self,
+        device: &Dx11Device,
+        target: &ComPtr<ID3D11Resource>,
+        _width: u32,
+    ) -> Result<()> {
+        unsafe {
+            device.immediate_context().inner.CopyResource(
+                target.as_ptr() as *mut _,
+                self.display_texture.inner.as_ptr() as *mut _,
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Dx11Present for CpuBackend {
+    fn present(
+        &self,
+        device: &Dx11Device,
+        target: &ComPtr<ID3D11Resource>,
+        width: u32,
+    ) -> Result<()> {
+        upload_display(&self.read_display()?, device, target, width)
+    }
+}
+
+impl Dx11Present for ParallelCpuBackend {
+    fn present(
+        &self,
+        device: &Dx11Device,
+        target: &ComPtr<ID3D11Resource>,
+        width: u32,
+    ) -> Result<()> {
+        upload_display(&self.read_display()?, device, target, width)
+    }
+}
+
+// The CPU backends composite on the host and upload the result.
+fn upload_display(
+    texels: &[Vec4],
+    device: &Dx11Device,
+    target: &ComPtr<ID3D11Resource>,
+    width: u32,
+) -> Result<()> {
+    let bytes = encode_rgba16f(texels);
+
+    unsafe {
+        device.immediate_context().inner.UpdateSubresource(
+            target.as_ptr() as *mut _,
+            0,
+            ptr::null(),
+            bytes.as_ptr() as *const _,
+            (width as usize * RGBA16F_TEXEL_BYTES) as u32,
+            bytes.len() as u32,
+        );
+    }
+    Ok(())
+}
+
+fn encode_rgba16f(texels: &[Vec4]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(texels.len() * RGBA16F_TEXEL_BYTES);
+
+    for texel in texels {
+        for c in &[texel.x, texel.y, texel.z, texel.w] {
+            bytes.extend_from_slice(&f32_to_f16(*c).to_le_bytes());
+        }
+    }
+
+    bytes
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits >> 15) as u32) << 31;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    let magnitude = match (exponent, mantissa) {
+        (0, 0) => 0,
+        (0, _) => {
+            // Subnormal: renormalize into an f32 exponent.
+            let shift = mantissa.leading_zeros() - 21;
+            ((113 - shift) << 23) | (((mantissa << shift) & 0x3ff) << 13)
+        }
+        (0x1f, _) => 0x7f80_0000 | (mantissa << 13),
+        _ => ((exponent + 112) << 23) | (mantissa << 13),
+    };
+
+    f32::from_bits(sign | magnitude)
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 112;
+
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        if half_exponent < -10 {
+            return sign;
+        }
+
+        // Subnormal: shift the implicit bit in and round to nearest even.
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - half_exponent) as u32;
+        let round = (1 << (shift - 1)) - 1 + ((mantissa >> shift) & 1);
+        return sign | ((mantissa + round) >> shift) as u16;
+    }
+
+    let half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    let half = if rest > 0x1000 || (rest == 0x1000 && half & 1 == 1) {
+        half + 1
+    } else {
+        half
+    };
+    sign | half.min(0x7c00) as u16
+}
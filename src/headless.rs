@@ -0,0 +1,141 @@
+use crate::{
+    backend::SimulationBackend,
+    image::{write_image, ImageFormat},
+    save_state, Command, SceneSource,
+};
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use structopt::StructOpt;
+
+pub const DEFAULT_FIXED_DT: f32 = 1.0 / 60.0;
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct RenderOptions {
+    /// Total number of simulation steps to run.
+    #[structopt(default_value = "1000", long)]
+    steps: u32,
+    /// Write a frame after every this many steps.
+    #[structopt(default_value = "1", long)]
+    every: u32,
+    #[structopt(default_value = "frames", long, parse(from_os_str))]
+    out: PathBuf,
+    #[structopt(default_value = "ppm", long)]
+    format: ImageFormat,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct BenchOptions {
+    /// Steps to time.
+    #[structopt(default_value = "200", long)]
+    steps: u32,
+    /// Steps to run first without timing them.
+    #[structopt(default_value = "20", long)]
+    warmup: u32,
+}
+
+pub fn run<B: SimulationBackend>(
+    backend: B,
+    source: SceneSource,
+    save_path: Option<&Path>,
+    command: &Command,
+) -> Result<()> {
+    match command {
+        Command::Render(options) => render(backend, source, save_path, options),
+        Command::Bench(options) => bench(backend, source, save_path, options),
+    }
+}
+
+// Steps the scene without a window and writes the trail field to a numbered
+// image sequence. Wall-clock time would make the output depend on how long
+// each readback takes, so the clock always runs with a fixed step here.
+fn render<B: SimulationBackend>(
+    backend: B,
+    source: SceneSource,
+    save_path: Option<&Path>,
+    options: &RenderOptions,
+) -> Result<()> {
+    let settings = source.settings().clone();
+    let fixed_dt = settings.fixed_dt.unwrap_or(DEFAULT_FIXED_DT);
+    let every = options.every.max(1);
+    let mut scene = source.build(backend)?;
+    let mut remaining = options.steps;
+    let mut frame = 0;
+    let start = Instant::now();
+
+    fs::create_dir_all(&options.out)?;
+
+    while remaining > 0 {
+        let steps = every.min(remaining);
+
+        scene.step_fixed(fixed_dt, steps)?;
+        remaining -= steps;
+
+        let path = options.out.join(format![
+            "trails_{:06}.{}",
+            frame,
+            options.format.extension()
+        ]);
+        write_image(
+            &path,
+            options.format,
+            settings.width,
+            settings.height,
+            &scene.backend.read_display()?,
+        )?;
+        frame += 1;
+    }
+
+    println![
+        "rendered {} frames ({} steps) in {:.2}s",
+        frame,
+        options.steps,
+        start.elapsed().as_secs_f32()
+    ];
+    scene.report_sorts();
+    save_state(&scene, save_path)
+}
+
+// Times steps of the scene without reading anything back, e.g.
+//
+//   trails --backend cpu-parallel --num-agents 1000000 --width 2048 \
+//       --height 2048 bench --steps 200
+fn bench<B: SimulationBackend>(
+    backend: B,
+    source: SceneSource,
+    save_path: Option<&Path>,
+    options: &BenchOptions,
+) -> Result<()> {
+    let fixed_dt = source.settings().fixed_dt.unwrap_or(DEFAULT_FIXED_DT);
+    let mut scene = source.build(backend)?;
+    let mut step_times = Vec::with_capacity(options.steps as usize);
+
+    scene.step_fixed(fixed_dt, options.warmup)?;
+
+    for _ in 0..options.steps {
+        let start = Instant::now();
+        scene.step_fixed(fixed_dt, 1)?;
+        step_times.push(start.elapsed().as_secs_f64());
+    }
+
+    if step_times.is_empty() {
+        return save_state(&scene, save_path);
+    }
+
+    let total: f64 = step_times.iter().sum();
+    step_times.sort_by(f64::total_cmp);
+    println![
+        "{} steps in {:.2}s: {:.1} steps/s, {:.2} ms/step (median {:.2}, slowest {:.2})",
+        step_times.len(),
+        total,
+        step_times.len() as f64 / total,
+        total * 1000.0 / step_times.len() as f64,
+        step_times[step_times.len() / 2] * 1000.0,
+        step_times[step_times.len() - 1] * 1000.0
+    ];
+    scene.report_sorts();
+    save_state(&scene, save_path)
+}
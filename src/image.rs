@@ -0,0 +1,214 @@
+// Minimal Netpbm-family image IO for the trail field. PPM output is clamped
+// and sRGB encoded for viewing, PFM keeps the linear scRGB values as-is.
+// Input images (masks and maps) are read as grayscale from PGM or PPM, in
+// either the binary or the plain text variant.
+use crate::Vec4;
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Pfm,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Ppm => "ppm",
+            Self::Pfm => "pfm",
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ppm" => Ok(Self::Ppm),
+            "pfm" => Ok(Self::Pfm),
+            _ => bail!["unknown image format {:?}, expected one of: ppm, pfm", s],
+        }
+    }
+}
+
+fn linear_to_srgb(v: f32) -> u8 {
+    let v = if v > 0.0 { v.min(1.0) } else { 0.0 };
+    let encoded = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+
+    (encoded * 255.0 + 0.5) as u8
+}
+
+pub fn write_image<P: AsRef<Path>>(
+    path: P,
+    format: ImageFormat,
+    width: u32,
+    height: u32,
+    texels: &[Vec4],
+) -> Result<()> {
+    let path = path.as_ref();
+
+    if texels.len() != width as usize * height as usize {
+        bail![
+            "image {:?} has {} texels, expected {}x{}",
+            path,
+            texels.len(),
+            width,
+            height
+        ];
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+
+    match format {
+        ImageFormat::Ppm => {
+            write!(out, "P6\n{} {}\n255\n", width, height)?;
+            for texel in texels {
+                out.write_all(&[
+                    linear_to_srgb(texel.x),
+                    linear_to_srgb(texel.y),
+                    linear_to_srgb(texel.z),
+                ])?;
+            }
+        }
+        ImageFormat::Pfm => {
+            // PFM stores rows bottom to top; a negative scale means little
+            // endian.
+            write!(out, "PF\n{} {}\n-1.0\n", width, height)?;
+            for row in texels.chunks_exact(width.max(1) as usize).rev() {
+                for texel in row {
+                    for c in &[texel.x, texel.y, texel.z] {
+                        out.write_all(&c.to_le_bytes())?;
+                    }
+                }
+            }
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+// A grayscale image with values in [0, 1], stored row-major from the top.
+#[derive(Debug, Clone)]
+pub struct GrayImage {
+    pub width: u32,
+    pub height: u32,
+    pub values: Vec<f32>,
+}
+
+impl GrayImage {
+    // Nearest-neighbour resample to `width` x `height`.
+    pub fn resized(&self, width: u32, height: u32) -> Vec<f32> {
+        let mut values = Vec::with_capacity(width as usize * height as usize);
+
+        for y in 0..height {
+            let sy = (y as u64 * self.height as u64 / height as u64) as usize;
+            for x in 0..width {
+                let sx = (x as u64 * self.width as u64 / width as u64) as usize;
+                values.push(self.values[sy * self.width as usize + sx]);
+            }
+        }
+
+        values
+    }
+}
+
+struct NetpbmHeader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> NetpbmHeader<'a> {
+    fn skip_space(&mut self) {
+        while let Some(&b) = self.data.get(self.pos) {
+            if b == b'#' {
+                while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+                    self.pos += 1;
+                }
+            } else if b.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<u32> {
+        self.skip_space();
+        let start = self.pos;
+
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+
+        std::str::from_utf8(&self.data[start..self.pos])?
+            .parse()
+            .context("malformed netpbm header")
+    }
+}
+
+pub fn read_gray_image<P: AsRef<Path>>(path: P) -> Result<GrayImage> {
+    let path = path.as_ref();
+    let data = fs::read(path).with_context(|| format!["failed to read image {:?}", path])?;
+    let (components, binary) = match data.get(..2) {
+        Some(b"P2") => (1, false),
+        Some(b"P3") => (3, false),
+        Some(b"P5") => (1, true),
+        Some(b"P6") => (3, true),
+        _ => bail!["{:?} is not a PGM or PPM image", path],
+    };
+    let mut header = NetpbmHeader {
+        data: &data,
+        pos: 2,
+    };
+    let width = header.number()?;
+    let height = header.number()?;
+    let max = header.number()?;
+
+    if max == 0 || max > 65535 {
+        bail!["image {:?} has an invalid maximum value {}", path, max];
+    }
+
+    let count = width as usize * height as usize * components;
+    let samples: Vec<u32> = if binary {
+        // Exactly one whitespace byte separates the header from the raster.
+        let raster = &data[(header.pos + 1).min(data.len())..];
+        let bytes = if max < 256 { 1 } else { 2 };
+
+        if raster.len() < count * bytes {
+            bail!["image {:?} is truncated", path];
+        }
+
+        raster
+            .chunks_exact(bytes)
+            .take(count)
+            .map(|b| b.iter().fold(0, |v, &b| v << 8 | b as u32))
+            .collect()
+    } else {
+        (0..count)
+            .map(|_| header.number())
+            .collect::<Result<_>>()
+            .with_context(|| format!["image {:?} is truncated", path])?
+    };
+    let values = samples
+        .chunks_exact(components)
+        .map(|texel| texel.iter().sum::<u32>() as f32 / (components as u32 * max) as f32)
+        .collect();
+
+    Ok(GrayImage {
+        width,
+        height,
+        values,
+    })
+}
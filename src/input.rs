@@ -0,0 +1,521 @@
+// The window's input actions. A binding table maps chords such as `Space` or
+// `Shift+Up` to actions; keys are named like winit's `VirtualKeyCode`
+// variants, mouse buttons `MouseLeft`, `MouseRight` and `MouseMiddle`, and
+// the wheel `WheelUp` and `WheelDown`, optionally after `Ctrl+`, `Alt+`,
+// `Shift+` and `Logo+`. Brush actions last until the key or button is
+// released.
+//
+// A bindings file is a TOML table from chords to actions that is applied
+// over the defaults, e.g.
+//
+//   "Shift+Right" = "add,setting=sensor-offset,by=1"
+//   R = "none"
+use crate::{
+    backend::SimulationBackend, brush::Brush, headless::DEFAULT_FIXED_DT, Constants, Scene,
+    Settings, Vec2,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap, f32::consts::PI, fmt, fs, path::Path, str::FromStr, time::Instant,
+};
+use toml::Value;
+
+const MODIFIERS: &[&str] = &["Ctrl", "Alt", "Shift", "Logo"];
+
+const DEFAULT_BRUSH_RADIUS: f32 = 16.0;
+
+type Field = fn(&mut Settings) -> &mut f32;
+pub type Constant = fn(&mut Constants, f32);
+
+// Settings that can change while the simulation runs, besides
+// `steps-per-tick`. The others size buffers or shape the initial state. The
+// second function writes a value straight into the constants of a step,
+// for the settings the constants carry.
+const LIVE_SETTINGS: &[(&str, Field, Option<Constant>)] = &[
+    (
+        "agent-speed",
+        |s| &mut s.agent_speed,
+        Some(|c, v| c.agent_speed = v),
+    ),
+    (
+        "agent-turn-rate-deg",
+        |s| &mut s.agent_turn_rate_deg,
+        Some(|c, v| c.agent_turn_rate_rad = v * PI / 180.0),
+    ),
+    (
+        "sensor-angle-deg",
+        |s| &mut s.sensor_angle_deg,
+        Some(|c, v| c.sensor_angle_rad = v * PI / 180.0),
+    ),
+    (
+        "sensor-offset",
+        |s| &mut s.sensor_offset,
+        Some(|c, v| c.sensor_offset = v),
+    ),
+    (
+        "same-color-weight",
+        |s| &mut s.same_color_weight,
+        Some(|c, v| c.same_color_weight = v),
+    ),
+    (
+        "different-color-weight",
+        |s| &mut s.different_color_weight,
+        Some(|c, v| c.different_color_weight = v),
+    ),
+    (
+        "eat-weight",
+        |s| &mut s.eat_weight,
+        Some(|c, v| c.eat_weight = v),
+    ),
+    (
+        "trail-weight",
+        |s| &mut s.trail_weight,
+        Some(|c, v| c.trail_weight = v),
+    ),
+    (
+        "exponential-decay-rate",
+        |s| &mut s.exponential_decay_rate,
+        Some(|c, v| c.exponential_decay_rate = v),
+    ),
+    (
+        "linear-decay-rate",
+        |s| &mut s.linear_decay_rate,
+        Some(|c, v| c.linear_decay_rate = v),
+    ),
+    (
+        "diffuse-rate",
+        |s| &mut s.diffuse_rate,
+        Some(|c, v| c.diffuse_rate = v),
+    ),
+    (
+        "obstacle-weight",
+        |s| &mut s.obstacle_weight,
+        Some(|c, v| c.obstacle_weight = v),
+    ),
+    (
+        "nutrient-weight",
+        |s| &mut s.nutrient_weight,
+        Some(|c, v| c.nutrient_weight = v),
+    ),
+    (
+        "nutrient-consume-rate",
+        |s| &mut s.nutrient_consume_rate,
+        Some(|c, v| c.nutrient_consume_rate = v),
+    ),
+    (
+        "nutrient-regrow-rate",
+        |s| &mut s.nutrient_regrow_rate,
+        Some(|c, v| c.nutrient_regrow_rate = v),
+    ),
+    (
+        "nutrient-diffuse-rate",
+        |s| &mut s.nutrient_diffuse_rate,
+        Some(|c, v| c.nutrient_diffuse_rate = v),
+    ),
+    ("time-scale", |s| &mut s.time_scale, None),
+];
+
+pub fn is_live(name: &str) -> bool {
+    name == "steps-per-tick" || LIVE_SETTINGS.iter().any(|(live, ..)| *live == name)
+}
+
+const DEFAULT_BINDINGS: &[(&str, &str)] = &[
+    ("Escape", "quit"),
+    ("F1", "help"),
+    ("Space", "pause"),
+    ("Period", "step"),
+    ("R", "reset"),
+    ("Up", "scale,setting=agent-speed,by=1.25"),
+    ("Down", "scale,setting=agent-speed,by=0.8"),
+    ("Right", "add,setting=sensor-angle-deg,by=5"),
+    ("Left", "add,setting=sensor-angle-deg,by=-5"),
+    ("PageUp", "add,setting=sensor-offset,by=2"),
+    ("PageDown", "add,setting=sensor-offset,by=-2"),
+    ("T", "scale,setting=agent-turn-rate-deg,by=1.25"),
+    ("Shift+T", "scale,setting=agent-turn-rate-deg,by=0.8"),
+    ("D", "scale,setting=diffuse-rate,by=1.25"),
+    ("Shift+D", "scale,setting=diffuse-rate,by=0.8"),
+    ("E", "scale,setting=exponential-decay-rate,by=1.25"),
+    ("Shift+E", "scale,setting=exponential-decay-rate,by=0.8"),
+    ("Equals", "scale,setting=time-scale,by=1.25"),
+    ("Minus", "scale,setting=time-scale,by=0.8"),
+    ("Shift+Up", "add,setting=steps-per-tick,by=1"),
+    ("Shift+Down", "add,setting=steps-per-tick,by=-1"),
+    ("MouseLeft", "brush,mode=paint"),
+    ("MouseRight", "brush,mode=erase"),
+    ("MouseMiddle", "brush,mode=spawn"),
+    ("Shift+MouseLeft", "brush,mode=pull"),
+    ("Shift+MouseRight", "brush,mode=push"),
+    ("WheelUp", "resize-brush,by=1.25"),
+    ("WheelDown", "resize-brush,by=0.8"),
+];
+
+// What a binding does, e.g. `scale,setting=agent-speed,by=1.25`.
+//
+// - quit, help (print the bindings), pause (toggle), step (pause and advance
+//   one step), reset (respawn with the next seed).
+// - add / scale: add `by` to or multiply by `by` one of `LIVE_SETTINGS`. The
+//   new value takes effect from the next step.
+// - brush: drags a `Brush`, e.g. `brush,mode=push,strength=100`.
+// - resize-brush: multiplies the radius of every brush by `by`.
+#[derive(Debug, Clone)]
+pub enum Action {
+    Quit,
+    Help,
+    Pause,
+    Step,
+    Reset,
+    Add { setting: String, by: f32 },
+    Scale { setting: String, by: f32 },
+    Brush(Brush),
+    ResizeBrush { by: f32 },
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(("brush", brush)) = s.split_once(',').map(|(mode, rest)| (mode.trim(), rest)) {
+            return Ok(Self::Brush(brush.parse()?));
+        }
+
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let (mut setting, mut by) = (None, None);
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["action field {:?} should be key=value", field])?;
+
+            match key {
+                "setting" if is_live(value) => setting = Some(value.to_string()),
+                "setting" => bail![
+                    "{:?} can't change live, expected steps-per-tick or one of: {}",
+                    value,
+                    LIVE_SETTINGS
+                        .iter()
+                        .map(|(name, ..)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ],
+                "by" => {
+                    by = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!["invalid value for action field {:?}", key])?,
+                    )
+                }
+                _ => bail!["unexpected field {:?} for action {:?}", field, mode],
+            }
+        }
+
+        let adjust = |setting: Option<String>, by: Option<f32>| -> Result<(String, f32)> {
+            Ok((
+                setting.with_context(|| format!["action {:?} needs a setting", mode])?,
+                by.with_context(|| format!["action {:?} needs `by`", mode])?,
+            ))
+        };
+        let action = match mode {
+            "quit" => Self::Quit,
+            "help" => Self::Help,
+            "pause" => Self::Pause,
+            "step" => Self::Step,
+            "reset" => Self::Reset,
+            "add" => {
+                let (setting, by) = adjust(setting, by)?;
+                return Ok(Self::Add { setting, by });
+            }
+            "scale" => {
+                let (setting, by) = adjust(setting, by)?;
+                return Ok(Self::Scale { setting, by });
+            }
+            "resize-brush" if setting.is_none() => {
+                let by = by.with_context(|| format!["action {:?} needs `by`", mode])?;
+                return Ok(Self::ResizeBrush { by });
+            }
+            "brush" => bail!["action \"brush\" needs a mode"],
+            _ => bail![
+                "unknown action {:?}, expected one of: quit, help, pause, step, reset, add, scale, \
+                 brush, resize-brush",
+                mode
+            ],
+        };
+
+        if setting.is_some() || by.is_some() {
+            bail!["action {:?} takes no fields", mode];
+        }
+
+        Ok(action)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Quit => f.write_str("quit"),
+            Self::Help => f.write_str("help"),
+            Self::Pause => f.write_str("pause"),
+            Self::Step => f.write_str("step"),
+            Self::Reset => f.write_str("reset"),
+            Self::Add { setting, by } => write!(f, "add,setting={},by={}", setting, by),
+            Self::Scale { setting, by } => write!(f, "scale,setting={},by={}", setting, by),
+            Self::Brush(brush) => write!(f, "brush,{}", brush),
+            Self::ResizeBrush { by } => write!(f, "resize-brush,by={}", by),
+        }
+    }
+}
+
+// Puts the modifiers of a chord in the canonical order.
+fn normalize_chord(chord: &str) -> Result<String> {
+    let mut parts: Vec<&str> = chord.split('+').map(str::trim).collect();
+    let key = parts.pop().filter(|key| !key.is_empty());
+    let key = key.with_context(|| format!["binding {:?} has no key", chord])?;
+
+    if let Some(unknown) = parts.iter().find(|m| !MODIFIERS.contains(m)) {
+        bail![
+            "unknown modifier {:?} in binding {:?}, expected one of: {}",
+            unknown,
+            chord,
+            MODIFIERS.join(", ")
+        ];
+    }
+
+    Ok(MODIFIERS
+        .iter()
+        .filter(|m| parts.contains(m))
+        .chain(Some(&key))
+        .copied()
+        .collect::<Vec<_>>()
+        .join("+"))
+}
+
+// The chord for `key` with the given modifiers held.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn chord(ctrl: bool, alt: bool, shift: bool, logo: bool, key: &str) -> String {
+    MODIFIERS
+        .iter()
+        .zip([ctrl, alt, shift, logo])
+        .filter(|(_, held)| *held)
+        .map(|(m, _)| *m)
+        .chain(Some(key))
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+#[derive(Debug, Clone)]
+pub struct Bindings(BTreeMap<String, Action>);
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self(
+            DEFAULT_BINDINGS
+                .iter()
+                .map(|(chord, action)| (chord.to_string(), action.parse().unwrap()))
+                .collect(),
+        )
+    }
+}
+
+impl Bindings {
+    // The defaults, with the bindings of the file at `path` applied over
+    // them. Binding a chord to `none` removes it.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut bindings = Self::default();
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(bindings),
+        };
+        let text = fs::read_to_string(path)
+            .with_context(|| format!["failed to read bindings file {:?}", path])?;
+        let table = match text
+            .parse::<Value>()
+            .with_context(|| format!["failed to parse bindings file {:?}", path])?
+        {
+            Value::Table(table) => table,
+            _ => bail!["bindings file {:?} should be a table", path],
+        };
+
+        for (chord, action) in &table {
+            let chord =
+                normalize_chord(chord).with_context(|| format!["in bindings file {:?}", path])?;
+            let action = action
+                .as_str()
+                .with_context(|| format!["binding {:?} in {:?} should be a string", chord, path])?;
+
+            if action == "none" {
+                bindings.0.remove(&chord);
+            } else {
+                let action = action
+                    .parse()
+                    .with_context(|| format!["invalid binding {:?} in {:?}", chord, path])?;
+                bindings.0.insert(chord, action);
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn get(&self, chord: &str) -> Option<&Action> {
+        self.0.get(chord)
+    }
+}
+
+impl fmt::Display for Bindings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (chord, action) in &self.0 {
+            writeln!(f, "{:>16}  {}", chord, action)?;
+        }
+        Ok(())
+    }
+}
+
+// Applies `f` to a live setting and returns the new value.
+pub fn adjust(settings: &mut Settings, name: &str, f: impl Fn(f32) -> f32) -> Option<String> {
+    if name == "steps-per-tick" {
+        settings.steps_per_tick = f(settings.steps_per_tick as f32).round().max(1.0) as u32;
+        return Some(settings.steps_per_tick.to_string());
+    }
+
+    let (_, field, _) = LIVE_SETTINGS.iter().find(|(live, ..)| *live == name)?;
+    let value = field(settings);
+    *value = f(*value);
+    Some(value.to_string())
+}
+
+// How to write the live setting `name` into the constants, if they carry it.
+pub fn constant(name: &str) -> Option<Constant> {
+    LIVE_SETTINGS
+        .iter()
+        .find(|(live, ..)| *live == name)
+        .and_then(|(_, _, constant)| *constant)
+}
+
+// Input state of the window: the bindings, whether the simulation runs and
+// the brush being dragged.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Controls {
+    bindings: Bindings,
+    paused: bool,
+    // Steps to take while paused.
+    pending_steps: u32,
+    // The brush and the key or button holding it.
+    stroke: Option<(String, Brush)>,
+    brush_radius: f32,
+    // In simulation texels; `None` outside the window.
+    cursor: Option<Vec2>,
+    last_tick: Instant,
+    pub exited: bool,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Controls {
+    pub fn new(bindings: Bindings) -> Self {
+        Self {
+            bindings,
+            paused: false,
+            pending_steps: 0,
+            stroke: None,
+            brush_radius: DEFAULT_BRUSH_RADIUS,
+            cursor: None,
+            last_tick: Instant::now(),
+            exited: false,
+        }
+    }
+
+    pub fn move_cursor(&mut self, cursor: Option<Vec2>) {
+        self.cursor = cursor;
+    }
+
+    // Runs the action bound to `chord`, which is `key` with the held
+    // modifiers, if any.
+    pub fn press<B: SimulationBackend>(
+        &mut self,
+        key: &str,
+        chord: &str,
+        scene: &mut Scene<B>,
+    ) -> Result<()> {
+        let action = match self.bindings.get(chord) {
+            Some(action) => action.clone(),
+            None => return Ok(()),
+        };
+
+        match action {
+            Action::Quit => self.exited = true,
+            Action::Help => print!["{}", self.bindings],
+            Action::Pause => {
+                self.paused = !self.paused;
+                println!["{}", if self.paused { "paused" } else { "resumed" }];
+            }
+            Action::Step => {
+                self.paused = true;
+                self.pending_steps += 1;
+            }
+            Action::Reset => {
+                let seed = scene.settings.seed.wrapping_add(1);
+                scene.reset(seed)?;
+                println!["reset with seed {}", seed];
+            }
+            Action::Add { setting, by } => self.adjust(scene, &setting, |v| v + by)?,
+            Action::Scale { setting, by } => self.adjust(scene, &setting, |v| v * by)?,
+            Action::Brush(brush) => self.stroke = Some((key.to_string(), brush)),
+            Action::ResizeBrush { by } => {
+                let max_radius = scene.settings.width.max(scene.settings.height) as f32;
+                self.brush_radius = (self.brush_radius * by).clamp(1.0, max_radius);
+                println!["brush radius = {}", self.brush_radius];
+            }
+        }
+
+        Ok(())
+    }
+
+    // Ends the stroke `key` holds, whatever modifiers changed since.
+    pub fn release(&mut self, key: &str) {
+        if self.stroke.as_ref().is_some_and(|(held, _)| held == key) {
+            self.stroke = None;
+        }
+    }
+
+    fn adjust<B: SimulationBackend>(
+        &mut self,
+        scene: &mut Scene<B>,
+        setting: &str,
+        f: impl Fn(f32) -> f32,
+    ) -> Result<()> {
+        let mut settings = scene.settings.clone();
+
+        if let Some(value) = adjust(&mut settings, setting, f) {
+            scene.update_settings(settings)?;
+            println!["{} = {}", setting, value];
+        }
+
+        Ok(())
+    }
+
+    // Applies the brush being dragged and advances the scene by a tick
+    // unless paused. Brushes also work while paused.
+    pub fn tick<B: SimulationBackend>(&mut self, scene: &mut Scene<B>) -> Result<()> {
+        let now = Instant::now();
+        let delta_time = now.duration_since(self.last_tick).as_secs_f32();
+        self.last_tick = now;
+
+        if let (Some((_, brush)), Some(cursor)) = (&self.stroke, self.cursor) {
+            brush.apply(scene, cursor, self.brush_radius, delta_time)?;
+        }
+
+        if !self.paused {
+            return scene.render();
+        }
+
+        scene.clock.skip_wall_time();
+
+        if self.pending_steps > 0 {
+            let fixed_dt = scene.settings.fixed_dt.unwrap_or(DEFAULT_FIXED_DT);
+            scene.step_fixed(fixed_dt, self.pending_steps)?;
+            self.pending_steps = 0;
+        }
+
+        Ok(())
+    }
+}
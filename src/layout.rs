@@ -0,0 +1,180 @@
+// Structs shared with the shaders. `gpu_struct!` declares them `#[repr(C)]`
+// and checks at compile time that every field sits where HLSL packing puts
+// it; build.rs checks that the HLSL declarations list the same fields with
+// the same types. Declare a struct with `hlsl cbuffer NAME;` to match
+// `cbuffer NAME` in a shader, or with `hlsl struct;` to match the HLSL struct
+// of the same name.
+//
+// In a constant buffer a field may not straddle a 16-byte register and the
+// buffer size is a multiple of 16 bytes. The macro inserts a `_pad_FIELD`
+// array before every field, empty unless the field would straddle a
+// register, and a `_pad` array at the end; construct the structs with
+// `..Default::default()`. Structured buffer elements are packed tightly.
+use crate::{Vec2, Vec4};
+
+// Rust types with an HLSL equivalent. build.rs maps the type names the same
+// way.
+pub trait HlslType {
+    const SIZE: usize;
+}
+
+impl HlslType for f32 {
+    const SIZE: usize = 4;
+}
+
+impl HlslType for u32 {
+    const SIZE: usize = 4;
+}
+
+impl HlslType for i32 {
+    const SIZE: usize = 4;
+}
+
+impl HlslType for Vec2 {
+    const SIZE: usize = 8;
+}
+
+impl HlslType for Vec4 {
+    const SIZE: usize = 16;
+}
+
+#[derive(Clone, Copy)]
+pub enum Packing {
+    Cbuffer,
+    Structured,
+}
+
+impl Packing {
+    // Offset of a field of `size` bytes following a field that ends at `end`.
+    pub const fn place(self, end: usize, size: usize) -> usize {
+        match self {
+            Self::Cbuffer if end / 16 != (end + size - 1) / 16 => end.div_ceil(16) * 16,
+            _ => end,
+        }
+    }
+
+    // Size of a struct whose last field ends at `end`.
+    pub const fn size(self, end: usize) -> usize {
+        match self {
+            Self::Cbuffer => end.div_ceil(16) * 16,
+            Self::Structured => end,
+        }
+    }
+}
+
+// Where the last of constant buffer fields of `sizes` ends.
+const fn cbuffer_end(sizes: &[usize]) -> usize {
+    let mut end = 0;
+    let mut i = 0;
+
+    while i < sizes.len() {
+        end = Packing::Cbuffer.place(end, sizes[i]) + sizes[i];
+        i += 1;
+    }
+
+    end
+}
+
+// Words of padding a constant buffer needs before a field of `size` bytes
+// that follows fields of `sizes`.
+pub const fn padding_before(sizes: &[usize], size: usize) -> usize {
+    let end = cbuffer_end(sizes);
+    (Packing::Cbuffer.place(end, size) - end) / 4
+}
+
+// Words of padding a constant buffer with fields of `sizes` needs at the end.
+pub const fn padding_words(sizes: &[usize]) -> usize {
+    let end = cbuffer_end(sizes);
+    (Packing::Cbuffer.size(end) - end) / 4
+}
+
+macro_rules! gpu_struct {
+    (
+        hlsl cbuffer $hlsl:ident;
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($field_vis:vis $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $crate::layout::gpu_struct!(
+            @cbuffer [$(#[$meta])* $vis struct $name] [] []
+            $($field_vis $field: $ty,)*
+        );
+
+        $crate::layout::gpu_struct!(@check $name, Cbuffer, $($field: $ty),*);
+    };
+    // Moves the fields over one at a time, each after its padding, keeping
+    // the sizes of the fields so far.
+    (
+        @cbuffer [$($header:tt)*] [$($fields:tt)*] [$($size:expr,)*]
+        $field_vis:vis $field:ident: $ty:ty,
+        $($rest:tt)*
+    ) => {
+        paste::paste! {
+            $crate::layout::gpu_struct!(
+                @cbuffer [$($header)*]
+                [
+                    $($fields)*
+                    [<_pad_ $field>]: [u32; $crate::layout::padding_before(
+                        &[$($size),*],
+                        <$ty as $crate::layout::HlslType>::SIZE,
+                    )],
+                    $field_vis $field: $ty,
+                ]
+                [$($size,)* <$ty as $crate::layout::HlslType>::SIZE,]
+                $($rest)*
+            );
+        }
+    };
+    (@cbuffer [$($header:tt)*] [$($fields:tt)*] [$($size:expr,)*]) => {
+        #[repr(C)]
+        $($header)* {
+            $($fields)*
+            _pad: [u32; $crate::layout::padding_words(&[$($size),*])],
+        }
+    };
+    (
+        hlsl struct;
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($field_vis:vis $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $(#[$meta])*
+        #[repr(C)]
+        $vis struct $name {
+            $($field_vis $field: $ty,)*
+        }
+
+        $crate::layout::gpu_struct!(@check $name, Structured, $($field: $ty),*);
+    };
+    (@check $name:ident, $packing:ident, $($field:ident: $ty:ty),*) => {
+        const _: () = {
+            let packing = $crate::layout::Packing::$packing;
+            let mut end = 0;
+
+            $(
+                let size = <$ty as $crate::layout::HlslType>::SIZE;
+                assert!(
+                    std::mem::size_of::<$ty>() == size,
+                    concat!("`", stringify!($ty), "` has a different size in HLSL")
+                );
+                assert!(
+                    std::mem::offset_of!($name, $field) == packing.place(end, size),
+                    concat!(
+                        "`", stringify!($name), "::", stringify!($field),
+                        "` isn't where HLSL packing puts it"
+                    )
+                );
+                end = packing.place(end, size) + size;
+            )*
+
+            assert!(
+                std::mem::size_of::<$name>() == packing.size(end),
+                concat!("`", stringify!($name), "` has a different size in HLSL")
+            );
+        };
+    };
+}
+
+pub(crate) use gpu_struct;
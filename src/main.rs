@@ -1,17 +1,39 @@
-use anyhow::Result;
-use d3d11::{
-    Dx11ComputeShader, Dx11ConstantBuffer, Dx11Device, Dx11RWStructuredBuffer, Dx11Texture2D,
-};
+use anyhow::{bail, Result};
+use backend::{BackendKind, SimulationBackend, TrailBuffer};
+use boundary::Boundary;
+use channels::{ChannelSpec, SimulationTables};
+use clock::SimClock;
+use deposition::Deposition;
+use diffusion::Diffusion;
+use headless::{BenchOptions, RenderOptions};
+use input::Bindings;
+#[cfg(windows)]
+use input::Controls;
+use layout::gpu_struct;
+use nutrient::FoodSpec;
+use order::{AgentOrder, SortStats};
+use population::{mean_energy, PopulationModel};
 use rand::{prelude::StdRng, Rng, SeedableRng};
-use std::{cmp, f32::consts::PI, ptr, time::Instant};
-use structopt::StructOpt;
-use winapi::{
-    shared::dxgiformat::DXGI_FORMAT_R16G16B16A16_FLOAT,
-    um::{synchapi::WaitForSingleObject, winbase::INFINITE},
+use sampling::Sampling;
+use snapshot::Snapshot;
+use spawn::{SpawnMode, Spawner};
+use species::{species_table, SpeciesSpec};
+use std::{
+    env,
+    f32::consts::PI,
+    ops::{Add, Div, Mul, Sub},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
+use steering::Steering;
+use structopt::StructOpt;
+use timeline::Timeline;
+#[cfg(windows)]
+use winapi::um::{synchapi::WaitForSingleObject, winbase::INFINITE};
+#[cfg(windows)]
 use winit::{
     dpi::PhysicalSize,
-    event::{ElementState, VirtualKeyCode},
+    event::{ElementState, ModifiersState, MouseButton, MouseScrollDelta},
     event_loop::{ControlFlow, EventLoop},
     platform::{
         run_return::EventLoopExtRunReturn,
@@ -20,20 +42,66 @@ use winit::{
     window::{Fullscreen, WindowBuilder},
 };
 
-use crate::d3d11::Dx11SwapChain;
+use crate::{cpu::CpuBackend, parallel::ParallelCpuBackend};
+#[cfg(windows)]
+use crate::{
+    d3d11::{Dx11Device, Dx11SwapChain},
+    gpu::{Dx11Backend, Dx11Present},
+};
 
+mod backend;
+mod boundary;
+mod brush;
+mod channels;
+mod clock;
+mod cpu;
+#[cfg(windows)]
 mod d3d11;
+mod deposition;
+mod diffusion;
+#[cfg(windows)]
 mod encoder;
+mod formula;
+#[cfg(windows)]
+mod gpu;
+mod headless;
+mod image;
+mod input;
+mod layout;
+mod nutrient;
+mod obstacles;
+mod order;
+mod parallel;
+mod population;
+mod sampling;
+mod scene_file;
+mod snapshot;
+mod spawn;
+mod species;
+mod steering;
+mod timeline;
+#[cfg(test)]
+mod test_util;
+#[cfg(windows)]
 mod shaders {
     pub const SLIME_ADVANCE_AGENTS_CS: &[u8] =
         include_bytes!(concat!(env!("OUT_DIR"), "/shader/slime.advance_agents.cso"));
+    pub const SLIME_RESOLVE_DEPOSITS_CS: &[u8] = include_bytes!(concat!(
+        env!("OUT_DIR"),
+        "/shader/slime.resolve_deposits.cso"
+    ));
     pub const SLIME_DECAY_AND_DIFFUSE_CS: &[u8] = include_bytes!(concat!(
         env!("OUT_DIR"),
         "/shader/slime.decay_and_diffuse.cso"
     ));
+    pub const SLIME_COMPOSE_CS: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/shader/slime.compose.cso"));
+    pub const SLIME_MOVE_AGENTS_CS: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/shader/slime.move_agents.cso"));
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Vec4 {
     x: f32,
     y: f32,
@@ -41,43 +109,209 @@ pub struct Vec4 {
     w: f32,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+impl Vec4 {
+    pub fn splat(v: f32) -> Self {
+        Self {
+            x: v,
+            y: v,
+            z: v,
+            w: v,
+        }
+    }
+
+    pub fn dot(self, rhs: Self) -> f32 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z + self.w * rhs.w
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        [self.x, self.y, self.z, self.w]
+    }
+}
+
+impl Add for Vec4 {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+            z: self.z + rhs.z,
+            w: self.w + rhs.w,
+        }
+    }
+}
+
+impl Sub for Vec4 {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z,
+            w: self.w - rhs.w,
+        }
+    }
+}
+
+impl Mul<f32> for Vec4 {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self {
+        Self {
+            x: self.x * rhs,
+            y: self.y * rhs,
+            z: self.z * rhs,
+            w: self.w * rhs,
+        }
+    }
+}
+
+impl Div<f32> for Vec4 {
+    type Output = Self;
+
+    fn div(self, rhs: f32) -> Self {
+        Self {
+            x: self.x / rhs,
+            y: self.y / rhs,
+            z: self.z / rhs,
+            w: self.w / rhs,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Vec2 {
     x: f32,
     y: f32,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct Agent {
-    color: Vec4,
-    position: Vec2,
-    heading: f32,
+impl Vec2 {
+    pub fn dot(self, rhs: Self) -> f32 {
+        self.x * rhs.x + self.y * rhs.y
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
 }
 
-impl Agent {
-    pub fn morton_pos(&self) -> u32 {
-        const B: [u32; 4] = [0x55555555, 0x33333333, 0x0F0F0F0F, 0x00FF00FF];
-        const S: [u32; 4] = [1, 2, 4, 8];
+impl Add for Vec2 {
+    type Output = Self;
 
-        let mut x = self.position.x.floor() as u32;
-        let mut y = self.position.y.floor() as u32;
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+        }
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+        }
+    }
+}
+
+impl Mul<f32> for Vec2 {
+    type Output = Self;
+
+    fn mul(self, rhs: f32) -> Self {
+        Self {
+            x: self.x * rhs,
+            y: self.y * rhs,
+        }
+    }
+}
 
-        x = (x | (x << S[3])) & B[3];
-        x = (x | (x << S[2])) & B[2];
-        x = (x | (x << S[1])) & B[1];
-        x = (x | (x << S[0])) & B[0];
+gpu_struct! {
+    hlsl struct;
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Agent {
+        color: Vec4,
+        position: Vec2,
+        heading: f32,
+        species: u32,
+        // Only meaningful with a population model.
+        energy: f32,
+        // Selects the agent's stream of the kernels' counter-based generator.
+        rng_key: u32,
+        // Run-and-tumble memory or remaining Lévy run length.
+        steer_state: f32,
+        // Unique and kept for the agent's life, so agents can be followed across
+        // sorts, splits and snapshots.
+        id: u32,
+    }
+}
 
-        y = (y | (y << S[3])) & B[3];
-        y = (y | (y << S[2])) & B[2];
-        y = (y | (y << S[1])) & B[1];
-        y = (y | (y << S[0])) & B[0];
+// Consecutive keys are fine since the kernels hash them, and unlike random
+// keys they never collide.
+fn assign_rng_keys(agents: &mut [Agent], rng: &mut StdRng) {
+    let base: u32 = rng.gen();
 
-        x | (y << 1)
+    for (i, agent) in agents.iter_mut().enumerate() {
+        agent.rng_key = base.wrapping_add(i as u32);
     }
 }
 
-#[derive(Debug, Clone, Copy, StructOpt)]
+#[cfg(windows)]
+const DEFAULT_BACKEND: &str = "dx11";
+#[cfg(not(windows))]
+const DEFAULT_BACKEND: &str = "cpu";
+
+// The fewest agents brushes and timeline events can add without a population
+// model.
+const MIN_ADDED_AGENTS: usize = 1 << 16;
+
+#[derive(Debug, StructOpt)]
+struct Options {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    /// Write the full simulation state to this file on exit.
+    #[structopt(long, parse(from_os_str))]
+    save_state: Option<PathBuf>,
+    /// Resume from a snapshot instead of spawning agents; the simulation
+    /// settings stored in the snapshot replace the ones given here.
+    #[structopt(long, parse(from_os_str))]
+    load_state: Option<PathBuf>,
+    /// TOML table of key bindings applied over the defaults, e.g.
+    /// `"Shift+Up" = "scale,setting=agent-speed,by=2"`.
+    #[structopt(long, parse(from_os_str))]
+    bindings: Option<PathBuf>,
+    /// Read settings from a TOML scene file. Settings given on the command
+    /// line override the file's.
+    #[structopt(long, parse(from_os_str))]
+    scene: Option<PathBuf>,
+    /// TOML file of keyframed settings and events at given simulated times.
+    #[structopt(long, parse(from_os_str))]
+    timeline: Option<PathBuf>,
+    #[structopt(flatten)]
+    settings: Settings,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run without a window and write the trail field to an image sequence.
+    Render(RenderOptions),
+    /// Run without a window and report how many steps per second the backend
+    /// takes.
+    Bench(BenchOptions),
+}
+
+#[derive(Debug, Clone, StructOpt)]
 struct Settings {
+    /// dx11, cpu or cpu-parallel.
+    #[structopt(default_value = DEFAULT_BACKEND, long)]
+    backend: BackendKind,
+    /// Threads of the cpu-parallel backend; 0 uses every core.
+    #[structopt(default_value = "0", long)]
+    threads: usize,
     #[structopt(default_value = "256", long)]
     width: u32,
     #[structopt(default_value = "256", long)]
@@ -114,58 +348,211 @@ struct Settings {
     diffuse_rate: f32,
     #[structopt(default_value = "4.0", long)]
     density: f32,
+    /// How agents are placed: disc, uniform, `ring[,inner=R,outer=R,heading=inward|outward|tangent]`,
+    /// `clusters[,count=N,radius=R]`, grid, `mask,path=FILE.pgm` or
+    /// `csv,path=FILE` with `x,y[,heading_deg]` rows.
+    #[structopt(default_value = "disc", long)]
+    spawn: SpawnMode,
+    /// Edge of the world: torus, reflect, absorb or `dish[,radius=R]`.
+    #[structopt(default_value = "torus", long)]
+    boundary: Boundary,
+    /// Diffusion operator: box, `substep[,max=N]`, `gaussian[,sigma=S]` or
+    /// `anisotropic[,angle=DEG,along=S,across=S]`.
+    #[structopt(default_value = "box", long)]
+    diffusion: Diffusion,
+    /// Steering model: sensors, `probabilistic[,sharpness=K]`,
+    /// `gradient[,gain=G,spacing=S]`, `run-and-tumble[,tumble=R,bias=B,memory=T]`
+    /// or `levy[,alpha=A,min=L,threshold=T]`.
+    #[structopt(default_value = "sensors", long)]
+    steering: Steering,
+    /// How agents read and write the fields: nearest, or accurate for
+    /// bilinear sensing and deposits that follow the agent's whole step.
+    #[structopt(default_value = "nearest", long)]
+    sampling: Sampling,
+    /// How agents write the fields: direct, or `deterministic[,bits=N]` for
+    /// order-independent fixed-point accumulation.
+    #[structopt(default_value = "direct", long)]
+    deposition: Deposition,
+    /// Order of the agents in memory: none, `morton[,every=N]` or
+    /// `hilbert[,every=N]`. Agents are sorted along the curve at spawn and
+    /// then every N steps, 64 by default; `every=0` only sorts at spawn.
+    #[structopt(default_value = "morton", long)]
+    agent_order: AgentOrder,
+    /// PGM/PPM image stretched over the field whose bright texels are walls
+    /// that agents cannot enter and trails cannot diffuse into.
+    #[structopt(long, parse(from_os_str))]
+    obstacles: Option<PathBuf>,
+    /// What a sensor adds to its sum for every obstacle texel it covers.
+    #[structopt(default_value = "-1000.0", long)]
+    obstacle_weight: f32,
+    /// Add a food source, e.g. `x=64,y=128,radius=8,amount=2`. May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    food: Vec<FoodSpec>,
+    /// PGM/PPM image stretched over the field whose brightness is added to
+    /// the nutrient capacity.
+    #[structopt(long, parse(from_os_str))]
+    food_image: Option<PathBuf>,
+    /// How strongly agents are drawn to nutrient when sensing.
+    #[structopt(default_value = "1.0", long)]
+    nutrient_weight: f32,
+    /// Nutrient each agent eats per second from the texel it stands on.
+    #[structopt(default_value = "1.0", long)]
+    nutrient_consume_rate: f32,
+    /// Fraction of the missing nutrient that grows back per second.
+    #[structopt(default_value = "0.0", long)]
+    nutrient_regrow_rate: f32,
+    #[structopt(default_value = "0.0", long)]
+    nutrient_diffuse_rate: f32,
+    #[structopt(default_value = "0:1:0", long, parse(try_from_str = species::parse_color))]
+    nutrient_color: Vec4,
+    /// Advance the simulation by this many seconds per step instead of by
+    /// wall-clock time, making runs reproducible.
+    #[structopt(long)]
+    fixed_dt: Option<f32>,
+    #[structopt(default_value = "1.0", long)]
+    time_scale: f32,
+    /// Add a species, e.g. `count=500,speed=2,sensor-angle-deg=45,color=12:0:0`.
+    /// May be repeated; unset fields use the global agent settings.
+    #[structopt(long, number_of_values = 1)]
+    species: Vec<SpeciesSpec>,
+    /// Add a pheromone channel, e.g. `decay=0.5,diffuse=2,color=0:0:12`. May
+    /// be repeated. With channels given, agents sense and deposit through the
+    /// species' `deposit` and `attract` rows instead of by color similarity.
+    #[structopt(long = "channel", number_of_values = 1)]
+    channels: Vec<ChannelSpec>,
+    /// Let agents spend and gain energy, die and split, e.g.
+    /// `energy=1,move-cost=0.02,trail-gain=0.5,split=2,capacity=200000`.
+    #[structopt(long)]
+    population: Option<PopulationModel>,
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Constants {
-    resolution: Vec2,            // 0
-    num_agents: u32,             // 2
-    steps_per_tick: u32,         // 3
-    agent_speed: f32,            // 4
-    agent_turn_rate_rad: f32,    // 5
-    sensor_angle_rad: f32,       // 6
-    sensor_offset: f32,          // 7
-    sensor_size: u32,            // 8
-    _pad0: u32,                  // 9
-    _pad1: u32,                  // 10
-    _pad2: u32,                  // 11
-    agent_color: Vec4,           // 12
-    same_color_weight: f32,      // 16
-    different_color_weight: f32, // 17
-    eat_weight: f32,             // 18
-    trail_weight: f32,           // 19
-    diffuse_rate: f32,           // 20
-    exponential_decay_rate: f32, // 21
-    linear_decay_rate: f32,      // 22
-    time: f32,                   // 23
-    delta_time: f32,             // 24
-    _pad3: u32,                  // 25
-    _pad4: u32,                  // 26
-    _pad5: u32,                  // 27
+impl Settings {
+    pub fn total_agents(&self) -> u32 {
+        species_table(self).iter().map(|s| s.count).sum()
+    }
+
+    // The most agents there can be at once: what a population model grows up
+    // to, or else room for the initial agents and as many again, at least
+    // `MIN_ADDED_AGENTS`, for brushes and timeline events to add.
+    pub fn agent_capacity(&self) -> usize {
+        let total = self.total_agents() as usize;
+        let capacity = match self.population {
+            Some(model) => model.capacity(total as u32),
+            None => total + total.max(MIN_ADDED_AGENTS),
+        };
+        capacity.min(u32::MAX as usize)
+    }
+
+    // Rejects combinations that parse but can't run.
+    pub fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            bail!["width and height must be at least 1"];
+        }
+
+        if self.total_agents() == 0 {
+            bail!["the scene has no agents; set num-agents or give a species a count"];
+        }
+
+        if !(self.density > 0.0 && self.density.is_finite()) {
+            bail!["density must be positive, got {}", self.density];
+        }
+
+        if self.steps_per_tick == 0 {
+            bail!["steps-per-tick must be at least 1"];
+        }
+
+        if let Some(fixed_dt) = self.fixed_dt {
+            if !(fixed_dt > 0.0 && fixed_dt.is_finite()) {
+                bail!["fixed-dt must be positive, got {}", fixed_dt];
+            }
+        }
+
+        if let Some(population) = &self.population {
+            let capacity = population.capacity(self.total_agents());
+
+            if capacity < self.total_agents() as usize {
+                bail![
+                    "population capacity {} is below the initial {} agents",
+                    capacity,
+                    self.total_agents()
+                ];
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Fields that only the shaders read (steps_per_tick, agent_color) are still
+// part of the cbuffer layout.
+gpu_struct! {
+    hlsl cbuffer SETTINGS;
+    #[allow(dead_code)]
+    #[derive(Debug, Default, Clone, Copy)]
+    struct Constants {
+        resolution: Vec2,            // 0
+        num_agents: u32,             // 2
+        steps_per_tick: u32,         // 3
+        agent_speed: f32,            // 4
+        agent_turn_rate_rad: f32,    // 5
+        sensor_angle_rad: f32,       // 6
+        sensor_offset: f32,          // 7
+        sensor_size: i32,            // 8
+        num_channels: u32,           // 9
+        interaction_mode: u32,       // 10
+        boundary: u32,               // 11
+        agent_color: Vec4,           // 12
+        same_color_weight: f32,      // 16
+        different_color_weight: f32, // 17
+        eat_weight: f32,             // 18
+        trail_weight: f32,           // 19
+        diffuse_rate: f32,           // 20
+        exponential_decay_rate: f32, // 21
+        linear_decay_rate: f32,      // 22
+        time: f32,                   // 23
+        delta_time: f32,             // 24
+        dish_radius: f32,            // 25
+        obstacle_weight: f32,        // 26
+        nutrient_weight: f32,        // 27
+        nutrient_color: Vec4,        // 28
+        nutrient_consume_rate: f32,  // 32
+        nutrient_regrow_rate: f32,   // 33
+        nutrient_diffuse_rate: f32,  // 34
+        population: u32,             // 35
+        move_cost: f32,              // 36
+        trail_gain: f32,             // 37
+        nutrient_gain: f32,          // 38
+        diffusion: u32,              // 39
+        kernel_radius: u32,          // 40
+        max_substeps: u32,           // 41
+        step: u32,                   // 42
+        steering: u32,               // 43
+        steering_params: Vec4,       // 44
+        sampling: u32,               // 48
+        deposition: u32,             // 49
+        deposit_scale: f32,          // 50
+    }
 }
 
 impl Constants {
-    pub fn new(
-        settings: &Settings,
-        initial_time: Instant,
-        last_frame_time: Instant,
-        current_time: Instant,
-    ) -> Constants {
+    pub fn new(settings: &Settings, time: f32, delta_time: f32) -> Constants {
+        let population = settings.population.unwrap_or_default();
+
         Self {
             resolution: Vec2 {
                 x: settings.width as f32,
                 y: settings.height as f32,
             },
-            num_agents: settings.num_agents,
+            num_agents: settings.total_agents(),
             steps_per_tick: settings.steps_per_tick,
             agent_speed: settings.agent_speed,
-            agent_turn_rate_rad: settings.agent_turn_rate_deg as f32 * PI / 180.0,
-            sensor_angle_rad: settings.sensor_angle_deg as f32 * PI / 180.0,
+            agent_turn_rate_rad: settings.agent_turn_rate_deg * PI / 180.0,
+            sensor_angle_rad: settings.sensor_angle_deg * PI / 180.0,
             sensor_offset: settings.sensor_offset,
-            sensor_size: settings.sensor_size,
-            _pad0: 0,
-            _pad1: 0,
-            _pad2: 0,
+            sensor_size: settings.sensor_size as i32,
+            num_channels: settings.channel_count(),
+            interaction_mode: settings.interaction_mode(),
+            boundary: settings.boundary.code(),
             agent_color: Vec4 {
                 x: 0.0,
                 y: 0.0,
@@ -179,11 +566,31 @@ impl Constants {
             diffuse_rate: settings.diffuse_rate,
             exponential_decay_rate: settings.exponential_decay_rate,
             linear_decay_rate: settings.linear_decay_rate,
-            time: current_time.duration_since(initial_time).as_secs_f32(),
-            delta_time: current_time.duration_since(last_frame_time).as_secs_f32(),
-            _pad3: 0,
-            _pad4: 0,
-            _pad5: 0,
+            time,
+            delta_time,
+            dish_radius: settings
+                .boundary
+                .dish_radius(settings.width, settings.height),
+            obstacle_weight: settings.obstacle_weight,
+            nutrient_weight: settings.nutrient_weight,
+            nutrient_color: settings.nutrient_color,
+            nutrient_consume_rate: settings.nutrient_consume_rate,
+            nutrient_regrow_rate: settings.nutrient_regrow_rate,
+            nutrient_diffuse_rate: settings.nutrient_diffuse_rate,
+            population: settings.population.is_some() as u32,
+            move_cost: population.move_cost,
+            trail_gain: population.trail_gain,
+            nutrient_gain: population.nutrient_gain,
+            diffusion: settings.diffusion.code(),
+            kernel_radius: settings.diffusion.kernel_radius(),
+            max_substeps: settings.diffusion.max_substeps(),
+            step: 0,
+            steering: settings.steering.code(),
+            steering_params: settings.steering.params(),
+            sampling: settings.sampling.code(),
+            deposition: settings.deposition.code(),
+            deposit_scale: settings.deposition.scale(),
+            ..Default::default()
         }
     }
 }
@@ -205,132 +612,341 @@ fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
     (r1 + m, g1 + m, b1 + m)
 }
 
-fn polar_to_rect(angle: f32, radius: f32) -> (f32, f32) {
-    let (x, y) = angle.sin_cos();
-    (x * radius, y * radius)
+// The color of agents of species without one.
+fn random_hue(rng: &mut StdRng) -> Vec4 {
+    let (r, g, b) = hsv_to_rgb(rng.gen(), 1.0, 1.0);
+
+    Vec4 {
+        x: r * 12.0,
+        y: g * 12.0,
+        z: b * 12.0,
+        w: 1.0,
+    }
 }
 
 #[derive(Clone)]
-struct Scene {
-    device: Dx11Device,
-    trails_texture: Dx11Texture2D,
-    diffuse_texture: Dx11Texture2D,
-    agents: Dx11RWStructuredBuffer<Agent>,
-    advance_agents: Dx11ComputeShader,
-    decay_and_diffuse: Dx11ComputeShader,
+struct Scene<B: SimulationBackend> {
+    backend: B,
+    // Live agents, which only differs from the settings with a population
+    // model or once agents are added.
+    num_agents: u32,
+    // The agents reserved on the backend, which there are never more of.
+    agent_capacity: usize,
+    // The id the next offspring gets.
+    next_id: u32,
     settings: Settings,
-    constants: Dx11ConstantBuffer<Constants>,
-    initial_time: Instant,
-    last_frame_time: Instant,
-}
-
-impl Scene {
-    pub fn new(device: &Dx11Device, settings: Settings) -> Result<Self> {
-        let trails_texture = Dx11Texture2D::new(
-            device,
-            settings.width,
-            settings.height,
-            DXGI_FORMAT_R16G16B16A16_FLOAT,
-        )?;
-        let diffuse_texture = Dx11Texture2D::new(
-            device,
-            settings.width,
-            settings.height,
-            DXGI_FORMAT_R16G16B16A16_FLOAT,
-        )?;
+    // Kept so live changes only upload tables that differ, and the images
+    // they come from are read once.
+    tables: SimulationTables,
+    clock: SimClock,
+    sort_stats: SortStats,
+    timeline: Timeline,
+    // For the timeline's formulas: the wall time of the last step, and the
+    // live agents' mean energy as of the last population update.
+    step_time: Duration,
+    mean_energy: f32,
+}
+
+impl<B: SimulationBackend> Scene<B> {
+    pub fn new(backend: B, settings: Settings) -> Result<Self> {
+        let mut scene = Self {
+            backend,
+            num_agents: 0,
+            agent_capacity: 0,
+            next_id: 0,
+            tables: SimulationTables::new(&settings)?,
+            settings,
+            clock: SimClock::new(),
+            sort_stats: SortStats::default(),
+            timeline: Timeline::default(),
+            step_time: Duration::default(),
+            mean_energy: 0.0,
+        };
+        scene.spawn()?;
+        Ok(scene)
+    }
+
+    fn spawn(&mut self) -> Result<()> {
+        let settings = &self.settings;
         let mut agents = vec![];
         let mut rng = StdRng::seed_from_u64(settings.seed as u64);
-        let radius = cmp::min(settings.width, settings.height) as f32 / settings.density;
-        agents.resize_with(settings.num_agents as usize, || {
-            let (px, py) = polar_to_rect(rng.gen::<f32>() * 2.0 * PI, rng.gen());
-            let (r, g, b) = hsv_to_rgb(rng.gen(), 1.0, 1.0);
-            Agent {
-                color: Vec4 {
-                    x: r * 12.0,
-                    y: g * 12.0,
-                    z: b * 12.0,
-                    w: 1.0,
-                },
-                position: Vec2 {
-                    x: settings.width as f32 / 2.0 + px * radius,
-                    y: settings.height as f32 / 2.0 + py * radius,
-                },
-                heading: rng.gen::<f32>() * PI * 2.0,
+        let spawner = Spawner::new(settings, settings.total_agents(), &mut rng)?;
+        let table = species_table(settings);
+        for (species, entry) in table.iter().enumerate() {
+            for _ in 0..entry.count {
+                let placement = spawner.place(&mut rng, agents.len() as u32);
+                let hue = random_hue(&mut rng);
+                let random_heading = rng.gen::<f32>() * PI * 2.0;
+                agents.push(Agent {
+                    color: entry.color.unwrap_or(hue),
+                    position: placement.position,
+                    heading: placement.heading.unwrap_or(random_heading),
+                    species: species as u32,
+                    energy: settings.population.map_or(0.0, |p| p.energy),
+                    rng_key: 0,
+                    steer_state: 0.0,
+                    id: agents.len() as u32,
+                });
             }
-        });
-        agents.sort_by(|a, b| a.morton_pos().cmp(&b.morton_pos()));
-        let initial_time = Instant::now();
-        let last_frame_time = initial_time;
-        let constants = Dx11ConstantBuffer::new_with_data(
-            device,
-            &[Constants::new(
-                &settings,
-                initial_time,
-                last_frame_time,
-                last_frame_time,
-            )],
-        )?;
+        }
+        assign_rng_keys(&mut agents, &mut rng);
+        settings
+            .agent_order
+            .sort(&mut agents, settings.width, settings.height);
+        self.backend.upload_tables(&self.tables)?;
+        self.agent_capacity = settings.agent_capacity();
+        self.backend.reserve_agents(self.agent_capacity)?;
+        self.backend.upload_agents(&agents)?;
+        self.num_agents = agents.len() as u32;
+        self.next_id = agents.len() as u32;
+        self.mean_energy = mean_energy(&agents);
+        Ok(())
+    }
+
+    // Starts over from a fresh spawn with `seed` and empty fields.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn reset(&mut self, seed: u32) -> Result<()> {
+        let texels = self.settings.width as usize * self.settings.height as usize;
+        let zeros = vec![0.0; texels * self.tables.channels.len()];
+
+        self.settings.seed = seed;
+        self.backend
+            .write_trail_buffer(TrailBuffer::Current, &zeros)?;
+        self.backend
+            .write_trail_buffer(TrailBuffer::Scratch, &zeros)?;
+
+        if !self.tables.nutrient_capacity.is_empty() {
+            self.backend
+                .write_nutrient(&self.tables.nutrient_capacity)?;
+        }
+
+        self.clock = SimClock::new();
+        self.sort_stats = SortStats::default();
+        self.timeline.seek(0.0);
+        self.spawn()
+    }
+
+    // Switches to settings that differ only in values the tables and
+    // constants are built from. The global parameters reach the backend
+    // through the constants, so the tables are only uploaded again when a
+    // species or channel of their own changes.
+    pub fn update_settings(&mut self, settings: Settings) -> Result<()> {
+        if self.tables.rebuild(&settings)? {
+            self.backend.upload_tables(&self.tables)?;
+        }
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn from_snapshot(mut backend: B, snapshot: Snapshot) -> Result<Self> {
+        let tables = SimulationTables::new(&snapshot.settings)?;
+        backend.upload_tables(&tables)?;
+        let agent_capacity = snapshot
+            .settings
+            .agent_capacity()
+            .max(snapshot.agents.len());
+        backend.reserve_agents(agent_capacity)?;
+        backend.upload_agents(&snapshot.agents)?;
+        backend.write_trail_buffer(TrailBuffer::Current, &snapshot.trail)?;
+        backend.write_trail_buffer(TrailBuffer::Scratch, &snapshot.scratch_trail)?;
+
+        // Older snapshots have no nutrient field and start at capacity.
+        if !snapshot.nutrient.is_empty() {
+            backend.write_nutrient(&snapshot.nutrient)?;
+        }
+
         Ok(Self {
-            device: device.clone(),
-            trails_texture,
-            diffuse_texture,
-            agents: Dx11RWStructuredBuffer::new_with_data(device, &agents)?,
-            settings,
-            initial_time,
-            last_frame_time,
-            constants,
-            advance_agents: Dx11ComputeShader::new(device, shaders::SLIME_ADVANCE_AGENTS_CS)?,
-            decay_and_diffuse: Dx11ComputeShader::new(device, shaders::SLIME_DECAY_AND_DIFFUSE_CS)?,
+            backend,
+            num_agents: snapshot.agents.len() as u32,
+            agent_capacity,
+            next_id: snapshot
+                .agents
+                .iter()
+                .map(|a| a.id.wrapping_add(1))
+                .max()
+                .unwrap_or(0),
+            settings: snapshot.settings,
+            tables,
+            clock: SimClock::restore(snapshot.time, snapshot.steps),
+            sort_stats: SortStats::default(),
+            timeline: Timeline::default(),
+            step_time: Duration::default(),
+            mean_energy: mean_energy(&snapshot.agents),
+        })
+    }
+
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            settings: self.settings.clone(),
+            time: self.clock.time,
+            steps: self.clock.steps,
+            agents: self.backend.read_agents()?,
+            trail: self.backend.read_trail_buffer(TrailBuffer::Current)?,
+            scratch_trail: self.backend.read_trail_buffer(TrailBuffer::Scratch)?,
+            nutrient: self.backend.read_nutrient()?,
         })
     }
 
-    pub fn render(&mut self) {
-        let ctx = self.device.immediate_context();
-
-        unsafe {
-            let current_time = Instant::now();
-
-            let constants = Constants::new(
-                &self.settings,
-                self.initial_time,
-                self.last_frame_time,
-                current_time,
-            );
-            self.constants.replace(&ctx, &[constants]);
-
-            for _i in 0..self.settings.steps_per_tick {
-                ctx.inner
-                    .CSSetShader(self.advance_agents.inner.as_ptr(), ptr::null_mut(), 0);
-                ctx.inner
-                    .CSSetConstantBuffers(0, 1, [self.constants.inner.as_ptr()].as_ptr());
-                ctx.inner.CSSetUnorderedAccessViews(
-                    0,
-                    3,
-                    [
-                        self.trails_texture.uav.as_ptr(),
-                        self.diffuse_texture.uav.as_ptr(),
-                        self.agents.uav.as_ptr(),
-                    ]
-                    .as_ptr(),
-                    ptr::null(),
-                );
-                ctx.inner.Dispatch(self.settings.num_agents / 32 + 1, 1, 1);
-                ctx.inner
-                    .CSSetShader(self.decay_and_diffuse.inner.as_ptr(), ptr::null_mut(), 0);
-                ctx.inner
-                    .Dispatch(self.settings.width / 8 + 1, self.settings.height / 8 + 1, 1);
-                std::mem::swap(&mut self.trails_texture, &mut self.diffuse_texture);
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn render(&mut self) -> Result<()> {
+        let steps = self.settings.steps_per_tick;
+
+        match self.settings.fixed_dt {
+            Some(fixed_dt) => self.step_fixed(fixed_dt, steps),
+            None => {
+                let delta_time = self.clock.wall_delta(self.settings.time_scale);
+                self.advance(delta_time, steps)
             }
+        }
+    }
+
+    // Every substep gets its own time so per-step randomness differs and the
+    // clock is independent of the frame rate.
+    pub fn step_fixed(&mut self, fixed_dt: f32, count: u32) -> Result<()> {
+        let delta_time = fixed_dt * self.settings.time_scale;
+
+        for _ in 0..count {
+            self.advance(delta_time, 1)?;
+        }
+
+        Ok(())
+    }
+
+    fn advance(&mut self, delta_time: f32, steps: u32) -> Result<()> {
+        timeline::apply(self, delta_time)?;
+
+        let mut constants = self.constants(delta_time);
+        timeline::drive(self, delta_time, &mut constants)?;
+        let first_step = self.clock.steps;
+        let start = Instant::now();
+
+        self.backend.step(&constants, steps)?;
+        self.step_time = start.elapsed() / steps.max(1);
+        self.sort_stats.record_steps(
+            &self.settings.agent_order,
+            first_step,
+            steps,
+            start.elapsed(),
+        );
+        self.clock.advance(delta_time, steps);
+        self.update_population()?;
 
-            self.last_frame_time = current_time;
+        if self.settings.agent_order.due(first_step, self.clock.steps) {
+            self.sort_agents()?;
         }
+
+        Ok(())
+    }
+
+    pub fn report_sorts(&self) {
+        if !self.sort_stats.is_empty() {
+            println!["{}", self.sort_stats];
+        }
+    }
+
+    // Goes through the host so it works on every backend. It only runs every
+    // few steps, so the round trip doesn't matter.
+    fn sort_agents(&mut self) -> Result<()> {
+        let start = Instant::now();
+        let mut agents = self.backend.read_agents()?;
+        let before = order::locality(&agents);
+
+        self.settings
+            .agent_order
+            .sort(&mut agents, self.settings.width, self.settings.height);
+        self.backend.upload_agents(&agents)?;
+        self.sort_stats
+            .record_sort(before, order::locality(&agents), start.elapsed());
+        Ok(())
+    }
+
+    fn constants(&self, delta_time: f32) -> Constants {
+        Constants {
+            num_agents: self.num_agents,
+            step: self.clock.steps as u32,
+            ..Constants::new(&self.settings, self.clock.time as f32, delta_time)
+        }
+    }
+
+    // Dead agents and splits are handled on the host between steps, which
+    // keeps the kernels free of allocation.
+    fn update_population(&mut self) -> Result<()> {
+        let model = match self.settings.population {
+            Some(model) => model,
+            None => return Ok(()),
+        };
+        let mut agents = self.backend.read_agents()?;
+
+        let changed = model.update(
+            &mut agents,
+            model
+                .capacity(self.settings.total_agents())
+                .min(self.agent_capacity),
+            species_table(&self.settings).len() as u32,
+            self.settings.seed,
+            self.clock.steps,
+            &mut self.next_id,
+        );
+
+        if changed {
+            self.backend.upload_agents(&agents)?;
+        }
+
+        self.num_agents = agents.len() as u32;
+        self.mean_energy = mean_energy(&agents);
+        Ok(())
     }
 }
 
-pub fn main() -> anyhow::Result<()> {
-    let settings = Settings::from_args();
+// Where a scene's initial state comes from.
+enum SceneStart {
+    Spawn(Settings),
+    Snapshot(Snapshot),
+}
+
+// The initial state and the timeline the scene follows from there.
+struct SceneSource {
+    start: SceneStart,
+    timeline: Timeline,
+}
+
+impl SceneSource {
+    fn settings(&self) -> &Settings {
+        match &self.start {
+            SceneStart::Spawn(settings) => settings,
+            SceneStart::Snapshot(snapshot) => &snapshot.settings,
+        }
+    }
+
+    fn build<B: SimulationBackend>(self, backend: B) -> Result<Scene<B>> {
+        let mut scene = match self.start {
+            SceneStart::Spawn(settings) => Scene::new(backend, settings)?,
+            SceneStart::Snapshot(snapshot) => Scene::from_snapshot(backend, snapshot)?,
+        };
 
-    println!["{:?}", settings];
+        scene.timeline = self.timeline;
+        scene.timeline.seek(scene.clock.time);
+        Ok(scene)
+    }
+}
+
+fn save_state<B: SimulationBackend>(scene: &Scene<B>, path: Option<&Path>) -> Result<()> {
+    if let Some(path) = path {
+        scene.snapshot()?.write(path)?;
+        println!["saved state to {:?}", path];
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn run_windowed<B: SimulationBackend + Dx11Present>(
+    device: &Dx11Device,
+    backend: B,
+    source: SceneSource,
+    save_path: Option<&Path>,
+    bindings: &Bindings,
+) -> Result<()> {
+    let settings = source.settings().clone();
     let frame_count = 2;
     let mut event_loop = EventLoop::<()>::new_any_thread();
     let (width, height) = (settings.width, settings.height);
@@ -342,67 +958,226 @@ pub fn main() -> anyhow::Result<()> {
         .with_resizable(false)
         .build(&event_loop)?;
     let hwnd = window.hwnd();
-    let device = Dx11Device::new()?;
-    let swap_chain = Dx11SwapChain::new_with_hwnd(&device, hwnd, width, height, frame_count)?;
-    let mut scene = Scene::new(&device, settings)?;
-    let mut exited = false;
+    let swap_chain = Dx11SwapChain::new_with_hwnd(device, hwnd, width, height, frame_count)?;
+    let mut scene = source.build(backend)?;
+    let mut controls = Controls::new(bindings.clone());
+    let mut modifiers = ModifiersState::empty();
+    let mut window_size = window.inner_size();
+    let mut result = Ok(());
     window.set_visible(true);
-    event_loop.run_return(move |event, _, control_flow| {
-        if exited {
+    event_loop.run_return(|event, _, control_flow| {
+        if controls.exited {
             *control_flow = ControlFlow::Exit;
             return;
         }
         *control_flow = ControlFlow::Poll;
         match event {
-            winit::event::Event::WindowEvent { event, .. } => match event {
-                winit::event::WindowEvent::CloseRequested => {
-                    exited = true;
-                }
-                winit::event::WindowEvent::KeyboardInput { input, .. } => {
-                    if input.state == ElementState::Pressed {
-                        match input.virtual_keycode {
-                            Some(VirtualKeyCode::Escape) => exited = true,
-                            _ => (),
+            winit::event::Event::WindowEvent { event, .. } => {
+                let key = match event {
+                    winit::event::WindowEvent::CloseRequested => {
+                        controls.exited = true;
+                        None
+                    }
+                    winit::event::WindowEvent::ModifiersChanged(state) => {
+                        modifiers = state;
+                        None
+                    }
+                    winit::event::WindowEvent::Resized(size) => {
+                        window_size = size;
+                        None
+                    }
+                    winit::event::WindowEvent::CursorMoved { position, .. } => {
+                        // The swap chain stretches the field over the window.
+                        controls.move_cursor(Some(Vec2 {
+                            x: position.x as f32 * width as f32 / window_size.width.max(1) as f32,
+                            y: position.y as f32 * height as f32 / window_size.height.max(1) as f32,
+                        }));
+                        None
+                    }
+                    winit::event::WindowEvent::CursorLeft { .. } => {
+                        controls.move_cursor(None);
+                        None
+                    }
+                    winit::event::WindowEvent::KeyboardInput { input, .. } => input
+                        .virtual_keycode
+                        .map(|key| (format!["{:?}", key], input.state)),
+                    winit::event::WindowEvent::MouseInput { state, button, .. } => match button {
+                        MouseButton::Left => Some(("MouseLeft".to_string(), state)),
+                        MouseButton::Right => Some(("MouseRight".to_string(), state)),
+                        MouseButton::Middle => Some(("MouseMiddle".to_string(), state)),
+                        MouseButton::Other(_) => None,
+                    },
+                    winit::event::WindowEvent::MouseWheel { delta, .. } => {
+                        let y = match delta {
+                            MouseScrollDelta::LineDelta(_, y) => y,
+                            MouseScrollDelta::PixelDelta(position) => position.y as f32,
+                        };
+
+                        match y {
+                            y if y > 0.0 => Some(("WheelUp".to_string(), ElementState::Pressed)),
+                            y if y < 0.0 => Some(("WheelDown".to_string(), ElementState::Pressed)),
+                            _ => None,
                         }
                     }
+                    _ => None,
+                };
+
+                match key {
+                    Some((key, ElementState::Pressed)) => {
+                        let chord = input::chord(
+                            modifiers.ctrl(),
+                            modifiers.alt(),
+                            modifiers.shift(),
+                            modifiers.logo(),
+                            &key,
+                        );
+                        result = controls.press(&key, &chord, &mut scene);
+
+                        if result.is_err() {
+                            controls.exited = true;
+                        }
+                    }
+                    Some((key, ElementState::Released)) => controls.release(&key),
+                    None => (),
                 }
-                winit::event::WindowEvent::ModifiersChanged(_) => {
-                    println!["modifierz"];
-                }
-                winit::event::WindowEvent::CursorMoved { position, .. } => {
-                    println!["mousemove {:?}", position];
-                }
-                winit::event::WindowEvent::CursorEntered { .. } => {
-                    println!["cursorenter"];
-                }
-                winit::event::WindowEvent::CursorLeft { .. } => {
-                    println!["cursorleft"];
-                }
-                winit::event::WindowEvent::MouseWheel { delta, phase, .. } => {
-                    println!["wheel {:?} {:?}", delta, phase];
+            }
+            winit::event::Event::MainEventsCleared => {
+                unsafe {
+                    WaitForSingleObject(swap_chain.wait_handle, INFINITE);
                 }
-                winit::event::WindowEvent::MouseInput { state, button, .. } => {
-                    println!["mouseinput {:?} {:?}", state, button];
+
+                result = controls.tick(&mut scene).and_then(|_| {
+                    scene
+                        .backend
+                        .present(device, &swap_chain.back_buffer, width)
+                });
+
+                if result.is_err() {
+                    controls.exited = true;
+                    return;
                 }
-                _ => (),
-            },
-            winit::event::Event::MainEventsCleared => {
-                let ctx = device.immediate_context();
 
                 unsafe {
-                    WaitForSingleObject(swap_chain.wait_handle, INFINITE);
-                    scene.render();
-                    ctx.inner.CopyResource(
-                        swap_chain.back_buffer.as_ptr() as *mut _,
-                        scene.trails_texture.inner.as_ptr() as *mut _,
-                    );
                     swap_chain.inner.Present(1, 0);
                 }
-                //
             }
             _ => (),
         }
     });
 
-    Ok(())
+    result?;
+    scene.report_sorts();
+    save_state(&scene, save_path)
+}
+
+fn run_headless(source: SceneSource, save_path: Option<&Path>, command: &Command) -> Result<()> {
+    let settings = source.settings().clone();
+
+    match settings.backend {
+        #[cfg(windows)]
+        BackendKind::Dx11 => {
+            let device = Dx11Device::new()?;
+            headless::run(
+                Dx11Backend::new(&device, settings.width, settings.height)?,
+                source,
+                save_path,
+                command,
+            )
+        }
+        #[cfg(not(windows))]
+        BackendKind::Dx11 => {
+            bail!["the dx11 backend is only available on Windows"]
+        }
+        BackendKind::Cpu => headless::run(
+            CpuBackend::new(settings.width, settings.height),
+            source,
+            save_path,
+            command,
+        ),
+        BackendKind::CpuParallel => headless::run(
+            ParallelCpuBackend::new(settings.width, settings.height, settings.threads)?,
+            source,
+            save_path,
+            command,
+        ),
+    }
+}
+
+#[cfg(windows)]
+fn run_interactive(
+    source: SceneSource,
+    save_path: Option<&Path>,
+    bindings: &Bindings,
+) -> Result<()> {
+    let settings = source.settings().clone();
+    let device = Dx11Device::new()?;
+
+    match settings.backend {
+        BackendKind::Dx11 => run_windowed(
+            &device,
+            Dx11Backend::new(&device, settings.width, settings.height)?,
+            source,
+            save_path,
+            bindings,
+        ),
+        BackendKind::Cpu => run_windowed(
+            &device,
+            CpuBackend::new(settings.width, settings.height),
+            source,
+            save_path,
+            bindings,
+        ),
+        BackendKind::CpuParallel => run_windowed(
+            &device,
+            ParallelCpuBackend::new(settings.width, settings.height, settings.threads)?,
+            source,
+            save_path,
+            bindings,
+        ),
+    }
+}
+
+#[cfg(not(windows))]
+fn run_interactive(
+    _source: SceneSource,
+    _save_path: Option<&Path>,
+    _bindings: &Bindings,
+) -> Result<()> {
+    bail!["the interactive window requires Direct3D 11, which is only available on Windows; use `trails render` to run headless"]
+}
+
+pub fn main() -> anyhow::Result<()> {
+    let mut options = Options::from_args();
+
+    if let Some(path) = options.scene.clone() {
+        options =
+            Options::from_iter_safe(scene_file::merge_args(&path, env::args_os().collect())?)?;
+    }
+
+    let start = match &options.load_state {
+        Some(path) => SceneStart::Snapshot(Snapshot::read(path, &options.settings)?),
+        None => SceneStart::Spawn(options.settings.clone()),
+    };
+    let mut source = SceneSource {
+        start,
+        timeline: Timeline::default(),
+    };
+    let save_path = options.save_state.as_deref();
+
+    source.settings().validate()?;
+
+    if let Some(path) = &options.timeline {
+        source.timeline = Timeline::load(path, source.settings())?;
+    }
+
+    println!["{:?}", source.settings()];
+
+    match &options.command {
+        Some(command) => run_headless(source, save_path, command),
+        None => run_interactive(
+            source,
+            save_path,
+            &Bindings::load(options.bindings.as_deref())?,
+        ),
+    }
 }
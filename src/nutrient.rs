@@ -0,0 +1,99 @@
+use crate::{image::read_gray_image, Settings};
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+// One `--food` source, e.g. `x=64,y=128,radius=8,amount=2`: a disc of
+// nutrient capacity `amount` per texel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FoodSpec {
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+    pub amount: f32,
+}
+
+impl FromStr for FoodSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (mut x, mut y) = (None, None);
+        let mut spec = Self {
+            x: 0.0,
+            y: 0.0,
+            radius: 5.0,
+            amount: 1.0,
+        };
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["food field {:?} should be key=value", field])?;
+            let value: f32 = value
+                .parse()
+                .with_context(|| format!["invalid value for food field {:?}", key])?;
+
+            match key {
+                "x" => x = Some(value),
+                "y" => y = Some(value),
+                "radius" => spec.radius = value,
+                "amount" => spec.amount = value,
+                _ => bail![
+                    "unknown food field {:?}, expected one of: x, y, radius, amount",
+                    key
+                ],
+            }
+        }
+
+        spec.x = x.context("food source needs an x=... field")?;
+        spec.y = y.context("food source needs a y=... field")?;
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for FoodSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "x={},y={},radius={},amount={}",
+            self.x, self.y, self.radius, self.amount
+        )
+    }
+}
+
+// Per-texel nutrient capacity from `--food` sources and `--food-image`.
+// The nutrient field starts full and regrows towards it. Empty when there
+// is no food at all, which disables the nutrient layer.
+pub fn nutrient_capacity(settings: &Settings) -> Result<Vec<f32>> {
+    if settings.food.is_empty() && settings.food_image.is_none() {
+        return Ok(vec![]);
+    }
+
+    let (width, height) = (settings.width, settings.height);
+    let mut capacity = match &settings.food_image {
+        Some(path) => {
+            let image = read_gray_image(path)?;
+
+            if image.width == 0 || image.height == 0 {
+                bail!["food image {:?} is empty", path];
+            }
+
+            image.resized(width, height)
+        }
+        None => vec![0.0; width as usize * height as usize],
+    };
+
+    for food in &settings.food {
+        for y in 0..height {
+            for x in 0..width {
+                let dx = x as f32 + 0.5 - food.x;
+                let dy = y as f32 + 0.5 - food.y;
+
+                if dx * dx + dy * dy <= food.radius * food.radius {
+                    capacity[y as usize * width as usize + x as usize] += food.amount;
+                }
+            }
+        }
+    }
+
+    Ok(capacity)
+}
@@ -0,0 +1,25 @@
+use crate::{image::read_gray_image, Settings};
+use anyhow::{bail, Result};
+
+// Texels at least this bright in the obstacle image are walls.
+const OBSTACLE_THRESHOLD: f32 = 0.5;
+
+// Loads `--obstacles` as one u32 per texel (nonzero for walls), stretched
+// over the field. Empty when no obstacle map is set.
+pub fn load_obstacles(settings: &Settings) -> Result<Vec<u32>> {
+    let path = match &settings.obstacles {
+        Some(path) => path,
+        None => return Ok(vec![]),
+    };
+    let image = read_gray_image(path)?;
+
+    if image.width == 0 || image.height == 0 {
+        bail!["obstacle map {:?} is empty", path];
+    }
+
+    Ok(image
+        .resized(settings.width, settings.height)
+        .into_iter()
+        .map(|v| (v >= OBSTACLE_THRESHOLD) as u32)
+        .collect())
+}
@@ -0,0 +1,347 @@
+use crate::{Agent, Vec2};
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr, time::Duration};
+
+// Space-filling curves that map a texel to a key. Keys interleave all 32 bits
+// of both coordinates, so any field size fits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Morton,
+    Hilbert,
+}
+
+impl Curve {
+    // `width` and `height` bound the Hilbert curve, which has to know the
+    // size of the square it fills. Positions outside the field saturate.
+    pub fn key(self, position: Vec2, width: u32, height: u32) -> u64 {
+        let x = position.x.floor() as u32;
+        let y = position.y.floor() as u32;
+
+        match self {
+            Self::Morton => spread(x) | (spread(y) << 1),
+            Self::Hilbert => {
+                let bits = (32 - (width.max(height).max(2) - 1).leading_zeros()).max(1);
+                let mask = (1u64 << bits) - 1;
+                hilbert(x as u64 & mask, y as u64 & mask, bits)
+            }
+        }
+    }
+}
+
+// Moves bit i of `v` to bit 2i.
+fn spread(v: u32) -> u64 {
+    let mut v = v as u64;
+    v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+    (v | (v << 1)) & 0x5555_5555_5555_5555
+}
+
+// Distance along the Hilbert curve filling a square of side 2^bits.
+fn hilbert(mut x: u64, mut y: u64, bits: u32) -> u64 {
+    let n = 1u64 << bits;
+    let mut d = 0;
+    let mut s = n >> 1;
+
+    while s > 0 {
+        let rx = (x & s != 0) as u64;
+        let ry = (y & s != 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        s >>= 1;
+    }
+
+    d
+}
+
+pub const DEFAULT_SORT_INTERVAL: u32 = 64;
+
+// The order of the agents in memory, e.g. `hilbert,every=128`.
+//
+// Agents are sorted along the curve at spawn, so agents that sense the same
+// texels sit together in memory. They drift apart as they move, so they are
+// sorted again every `every` steps, `DEFAULT_SORT_INTERVAL` unless set;
+// `every=0` only sorts at spawn. `none` keeps the spawn order. Sorting
+// changes which agent a backend updates first, so with direct deposition it
+// changes the result on the CPU backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgentOrder {
+    pub curve: Option<Curve>,
+    pub every: u32,
+}
+
+impl AgentOrder {
+    // The sort is stable, so agents on the same texel keep their order.
+    pub fn sort(&self, agents: &mut [Agent], width: u32, height: u32) {
+        if let Some(curve) = self.curve {
+            agents.sort_by_cached_key(|a| curve.key(a.position, width, height));
+        }
+    }
+
+    // Whether a sort is due after stepping from `from` to `to`.
+    pub fn due(&self, from: u64, to: u64) -> bool {
+        let every = self.every as u64;
+        self.curve.is_some() && every > 0 && from / every != to / every
+    }
+}
+
+impl FromStr for AgentOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let mut order = Self {
+            curve: match mode {
+                "none" => None,
+                "morton" => Some(Curve::Morton),
+                "hilbert" => Some(Curve::Hilbert),
+                _ => bail![
+                    "unknown agent order {:?}, expected one of: none, morton, hilbert",
+                    mode
+                ],
+            },
+            every: 0,
+        };
+
+        if order.curve.is_some() {
+            order.every = DEFAULT_SORT_INTERVAL;
+        }
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["agent order field {:?} should be key=value", field])?;
+
+            match key {
+                "every" if order.curve.is_some() => {
+                    order.every = value
+                        .parse()
+                        .with_context(|| format!["invalid value for agent order field {:?}", key])?
+                }
+                _ => bail!["unexpected field {:?} for agent order {:?}", field, mode],
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+impl fmt::Display for AgentOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.curve {
+            None => "none",
+            Some(Curve::Morton) => "morton",
+            Some(Curve::Hilbert) => "hilbert",
+        })?;
+
+        if self.curve.is_some() && self.every != DEFAULT_SORT_INTERVAL {
+            write!(f, ",every={}", self.every)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Mean distance in texels between agents that are neighbors in memory. The
+// lower it is, the more often neighboring threads read the same texels.
+pub fn locality(agents: &[Agent]) -> f32 {
+    if agents.len() < 2 {
+        return 0.0;
+    }
+
+    let total: f64 = agents
+        .windows(2)
+        .map(|pair| (pair[1].position - pair[0].position).length() as f64)
+        .sum();
+
+    (total / (agents.len() - 1) as f64) as f32
+}
+
+// How periodic sorting affects locality and step time. Step times are split
+// by whether they fall in the first or second half of a sort interval; a
+// sort that pays off makes the first half faster. On the dx11 backend they
+// only cover submitting the work, except for the steps that read agents back.
+#[derive(Debug, Clone, Default)]
+pub struct SortStats {
+    sorts: u32,
+    sort_time: Duration,
+    locality_before: f64,
+    locality_after: f64,
+    early: (Duration, u64),
+    late: (Duration, u64),
+}
+
+impl SortStats {
+    pub fn record_sort(&mut self, before: f32, after: f32, time: Duration) {
+        self.sorts += 1;
+        self.sort_time += time;
+        self.locality_before += before as f64;
+        self.locality_after += after as f64;
+    }
+
+    pub fn record_steps(
+        &mut self,
+        order: &AgentOrder,
+        first_step: u64,
+        steps: u32,
+        time: Duration,
+    ) {
+        if order.every == 0 {
+            return;
+        }
+
+        let phase = first_step % order.every as u64;
+        let half = if phase < (order.every as u64).div_ceil(2) {
+            &mut self.early
+        } else {
+            &mut self.late
+        };
+
+        half.0 += time;
+        half.1 += steps as u64;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sorts == 0
+    }
+}
+
+impl fmt::Display for SortStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sorts = self.sorts.max(1) as f64;
+        let per_step =
+            |(time, steps): (Duration, u64)| time.as_secs_f64() * 1000.0 / steps.max(1) as f64;
+
+        write!(
+            f,
+            "sorted agents {} times in {:.2}s; {:.2} texels between neighbors before a sort, \
+             {:.2} after; {:.3} ms per step in the first half of an interval, {:.3} ms in the \
+             second",
+            self.sorts,
+            self.sort_time.as_secs_f32(),
+            self.locality_before / sorts,
+            self.locality_after / sorts,
+            per_step(self.early),
+            per_step(self.late)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: u32, y: u32) -> Vec2 {
+        Vec2 {
+            x: x as f32 + 0.5,
+            y: y as f32 + 0.5,
+        }
+    }
+
+    fn interleave(x: u32, y: u32) -> u64 {
+        (0..32).fold(0, |key, i| {
+            key | ((x as u64 >> i) & 1) << (2 * i) | ((y as u64 >> i) & 1) << (2 * i + 1)
+        })
+    }
+
+    #[test]
+    fn morton_keys_use_every_bit() {
+        let size = 1 << 20;
+
+        for &(x, y) in &[
+            (0, 0),
+            (65535, 0),
+            (65536, 0),
+            (0, 65536),
+            (70000, 123456),
+            (size - 1, size - 1),
+        ] {
+            assert_eq!(
+                Curve::Morton.key(at(x, y), size, size),
+                interleave(x, y),
+                "({}, {})",
+                x,
+                y
+            );
+        }
+
+        assert_eq!(Curve::Morton.key(at(65536, 0), size, size), 1 << 32);
+        assert_eq!(Curve::Morton.key(at(0, 65536), size, size), 1 << 33);
+    }
+
+    #[test]
+    fn hilbert_keys_visit_every_cell_once() {
+        let mut cells = vec![None; 64];
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let key = Curve::Hilbert.key(at(x, y), 8, 8) as usize;
+                assert!(cells[key].replace((x, y)).is_none());
+            }
+        }
+
+        for pair in cells.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0].unwrap(), pair[1].unwrap());
+            assert_eq!(
+                (x0 as i32 - x1 as i32).abs() + (y0 as i32 - y1 as i32).abs(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn hilbert_keys_above_65535() {
+        let size = 1 << 17;
+        let key = |x, y| Curve::Hilbert.key(at(x, y), size, size);
+        let mut keys = vec![];
+
+        for y in 65534..65538 {
+            for x in 65534..65538 {
+                keys.push(key(x, y));
+            }
+        }
+
+        keys.sort_unstable();
+        keys.dedup();
+        assert_eq!(keys.len(), 16);
+        assert_eq!(key(0, 0), 0);
+        assert_eq!(key(size - 1, 0), (1 << 34) - 1);
+        assert!(keys.iter().all(|&k| k < 1 << 34));
+    }
+
+    #[test]
+    fn parses_orders() {
+        let order = |s: &str| s.parse::<AgentOrder>().unwrap();
+
+        assert_eq!(order("morton").every, DEFAULT_SORT_INTERVAL);
+        assert_eq!(order("hilbert,every=0").every, 0);
+        assert_eq!(order("none").every, 0);
+        assert_eq!(order("morton").to_string(), "morton");
+        assert_eq!(order("hilbert,every=8").to_string(), "hilbert,every=8");
+        assert!("none,every=8".parse::<AgentOrder>().is_err());
+        assert!("zorder".parse::<AgentOrder>().is_err());
+    }
+
+    #[test]
+    fn sorts_when_an_interval_boundary_passes() {
+        let order = AgentOrder {
+            curve: Some(Curve::Morton),
+            every: 64,
+        };
+
+        assert!(!order.due(0, 63));
+        assert!(order.due(63, 64));
+        assert!(order.due(10, 200));
+        assert!(!AgentOrder { every: 0, ..order }.due(0, 1000));
+    }
+}
@@ -0,0 +1,324 @@
+// Multithreaded CPU backend. Runs the kernels of the reference backend in
+// cpu.rs, but keeps the agents as a structure of arrays and advances them
+// in tiles straight from the columns on a pool of threads, and decays the
+// field in bands of rows on the same pool.
+//
+// `trails bench` measures how fast it steps. On one core a step of a million
+// agents on a 2048x2048 field takes about a second with the default
+// settings: about 0.1 s of diffusion and the rest in the agent update, where
+// each agent's 27 sensor samples land on scattered texels. That is far from
+// the GPU's rate; the time per step falls with the number of cores.
+//
+// Like on the GPU, concurrent agents can lose each other's writes with
+// direct deposition. With deterministic deposition the result is the same as
+// the reference backend's for any thread count.
+use crate::{
+    backend::{check_agent_capacity, SimulationBackend, TrailBuffer},
+    brush::AgentBrush,
+    channels::SimulationTables,
+    cpu::{advance_agents, AgentTile, FieldState, LiveTables},
+    Agent, Constants, Vec2, Vec4,
+};
+use anyhow::{Context, Result};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use std::{ops::Range, sync::Arc};
+
+// Agents per tile, the unit of work handed to a pool thread.
+const TILE: usize = 1024;
+
+#[derive(Clone, Default)]
+struct AgentColumns {
+    color: Vec<Vec4>,
+    position: Vec<Vec2>,
+    heading: Vec<f32>,
+    species: Vec<u32>,
+    energy: Vec<f32>,
+    rng_key: Vec<u32>,
+    steer_state: Vec<f32>,
+    id: Vec<u32>,
+}
+
+impl AgentColumns {
+    // Replaces the agents, keeping the columns' allocations.
+    fn assign(&mut self, agents: &[Agent]) {
+        self.truncate(0);
+        self.extend(agents);
+    }
+
+    fn extend(&mut self, agents: &[Agent]) {
+        self.color.extend(agents.iter().map(|a| a.color));
+        self.position.extend(agents.iter().map(|a| a.position));
+        self.heading.extend(agents.iter().map(|a| a.heading));
+        self.species.extend(agents.iter().map(|a| a.species));
+        self.energy.extend(agents.iter().map(|a| a.energy));
+        self.rng_key.extend(agents.iter().map(|a| a.rng_key));
+        self.steer_state
+            .extend(agents.iter().map(|a| a.steer_state));
+        self.id.extend(agents.iter().map(|a| a.id));
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.color.truncate(len);
+        self.position.truncate(len);
+        self.heading.truncate(len);
+        self.species.truncate(len);
+        self.energy.truncate(len);
+        self.rng_key.truncate(len);
+        self.steer_state.truncate(len);
+        self.id.truncate(len);
+    }
+
+    fn reserve(&mut self, capacity: usize) {
+        let additional = capacity.saturating_sub(self.len());
+
+        self.color.reserve(additional);
+        self.position.reserve(additional);
+        self.heading.reserve(additional);
+        self.species.reserve(additional);
+        self.energy.reserve(additional);
+        self.rng_key.reserve(additional);
+        self.steer_state.reserve(additional);
+        self.id.reserve(additional);
+    }
+
+    fn len(&self) -> usize {
+        self.position.len()
+    }
+
+    fn get(&self, i: usize) -> Agent {
+        Agent {
+            color: self.color[i],
+            position: self.position[i],
+            heading: self.heading[i],
+            species: self.species[i],
+            energy: self.energy[i],
+            rng_key: self.rng_key[i],
+            steer_state: self.steer_state[i],
+            id: self.id[i],
+        }
+    }
+
+    fn to_agents(&self) -> Vec<Agent> {
+        (0..self.len()).map(|i| self.get(i)).collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct ParallelCpuBackend {
+    fields: FieldState,
+    agents: AgentColumns,
+    agent_capacity: usize,
+    // Lives as long as the backend, so steps don't start threads.
+    pool: Arc<ThreadPool>,
+}
+
+impl ParallelCpuBackend {
+    // `threads` of 0 uses every core.
+    pub fn new(width: u32, height: u32, threads: usize) -> Result<Self> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!["trails-worker-{}", i])
+            .build()
+            .context("failed to start the worker threads")?;
+
+        Ok(Self {
+            fields: FieldState::new(width, height),
+            agents: AgentColumns::default(),
+            agent_capacity: 0,
+            pool: Arc::new(pool),
+        })
+    }
+
+    // Each task advances a tile of `TILE` agents straight from the columns.
+    fn step_once(&mut self, constants: &Constants) {
+        let grid = self.fields.grid();
+        let num_agents = (constants.num_agents as usize).min(self.agents.len());
+        let (tables, fields) = self.fields.agent_view();
+        let live = LiveTables::new(constants, &tables);
+        let columns = &mut self.agents;
+        let tiles = (
+            columns.color[..num_agents].par_chunks(TILE),
+            columns.position[..num_agents].par_chunks_mut(TILE),
+            columns.heading[..num_agents].par_chunks_mut(TILE),
+            columns.species[..num_agents].par_chunks(TILE),
+            columns.energy[..num_agents].par_chunks_mut(TILE),
+            columns.rng_key[..num_agents].par_chunks(TILE),
+            columns.steer_state[..num_agents].par_chunks_mut(TILE),
+        );
+
+        self.pool.install(|| {
+            tiles.into_par_iter().for_each(
+                |(color, position, heading, species, energy, rng_key, steer_state)| {
+                    let tile = AgentTile {
+                        color,
+                        position,
+                        heading,
+                        species,
+                        energy,
+                        rng_key,
+                        steer_state,
+                    };
+                    advance_agents(constants, &tables, &live, grid, fields, tile);
+                },
+            )
+        });
+
+        self.fields.decay(constants, Some(&self.pool));
+    }
+}
+
+impl SimulationBackend for ParallelCpuBackend {
+    fn upload_tables(&mut self, tables: &SimulationTables) -> Result<()> {
+        self.fields.upload_tables(tables);
+        Ok(())
+    }
+
+    fn reserve_agents(&mut self, capacity: usize) -> Result<()> {
+        self.agents.reserve(capacity);
+        self.agent_capacity = capacity;
+        Ok(())
+    }
+
+    fn upload_agents(&mut self, agents: &[Agent]) -> Result<()> {
+        self.agents.assign(agents);
+        Ok(())
+    }
+
+    fn step(&mut self, constants: &Constants, count: u32) -> Result<()> {
+        for i in 0..count {
+            self.step_once(&Constants {
+                step: constants.step.wrapping_add(i),
+                ..*constants
+            });
+        }
+        Ok(())
+    }
+
+    fn read_trail_buffer(&self, buffer: TrailBuffer) -> Result<Vec<f32>> {
+        Ok(self.fields.read_trail_buffer(buffer))
+    }
+
+    fn write_trail_buffer(&mut self, buffer: TrailBuffer, values: &[f32]) -> Result<()> {
+        self.fields.write_trail_buffer(buffer, values)
+    }
+
+    fn read_trail_rows(&self, buffer: TrailBuffer, rows: Range<u32>) -> Result<Vec<f32>> {
+        self.fields.read_trail_rows(buffer, rows)
+    }
+
+    fn write_trail_rows(
+        &mut self,
+        buffer: TrailBuffer,
+        rows: Range<u32>,
+        values: &[f32],
+    ) -> Result<()> {
+        self.fields.write_trail_rows(buffer, rows, values)
+    }
+
+    fn read_nutrient(&self) -> Result<Vec<f32>> {
+        Ok(self.fields.read_nutrient())
+    }
+
+    fn write_nutrient(&mut self, values: &[f32]) -> Result<()> {
+        self.fields.write_nutrient(values)
+    }
+
+    fn read_agents(&self) -> Result<Vec<Agent>> {
+        Ok(self.agents.to_agents())
+    }
+
+    fn append_agents(&mut self, agents: &[Agent]) -> Result<()> {
+        check_agent_capacity(self.agents.len(), agents.len(), self.agent_capacity)?;
+        self.agents.extend(agents);
+        Ok(())
+    }
+
+    // Only positions and headings change.
+    fn move_agents(&mut self, constants: &Constants, brush: &AgentBrush) -> Result<()> {
+        let columns = &mut self.agents;
+
+        for i in 0..columns.len() {
+            let mut agent = columns.get(i);
+            brush.move_agent(constants, self.fields.obstacles(), &mut agent);
+            columns.position[i] = agent.position;
+            columns.heading[i] = agent.heading;
+        }
+        Ok(())
+    }
+
+    fn read_display(&self) -> Result<Vec<Vec4>> {
+        Ok(self.fields.read_display())
+    }
+
+    fn refresh_display(&mut self, _constants: &Constants) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpu::CpuBackend, Scene, Settings};
+    use structopt::StructOpt;
+
+    // The trail field and the agents' positions and headings, as bits.
+    fn run<B: SimulationBackend>(backend: B, settings: &Settings) -> (Vec<u32>, Vec<[u32; 3]>) {
+        let mut scene = Scene::new(backend, settings.clone()).unwrap();
+        scene.step_fixed(0.05, 20).unwrap();
+
+        let trail = scene
+            .backend
+            .read_trail_buffer(TrailBuffer::Current)
+            .unwrap();
+        let agents = scene.backend.read_agents().unwrap();
+
+        (
+            trail.iter().map(|v| v.to_bits()).collect(),
+            agents
+                .iter()
+                .map(|a| {
+                    [
+                        a.position.x.to_bits(),
+                        a.position.y.to_bits(),
+                        a.heading.to_bits(),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn deterministic_deposition_matches_the_reference() {
+        let settings = Settings::from_iter_safe(&[
+            "trails",
+            "--width=96",
+            "--height=64",
+            "--num-agents=3000",
+            "--deposition=deterministic",
+            "--agent-order=morton,every=8",
+            "--channel=decay=0.5,diffuse=2",
+            "--channel=decay=0.2",
+            "--species=count=2000,deposit=1:0,attract=1:-1",
+            "--species=count=1000,deposit=0:1,attract=-1:1,speed=40",
+        ])
+        .unwrap();
+        let reference = run(CpuBackend::new(96, 64), &settings);
+
+        assert!(reference.0.iter().any(|&v| v != 0));
+
+        for threads in [1, 3] {
+            let parallel = run(ParallelCpuBackend::new(96, 64, threads).unwrap(), &settings);
+
+            assert!(
+                parallel.0 == reference.0,
+                "trail differs with {} threads",
+                threads
+            );
+            assert!(
+                parallel.1 == reference.1,
+                "agents differ with {} threads",
+                threads
+            );
+        }
+    }
+}
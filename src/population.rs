@@ -0,0 +1,178 @@
+use crate::Agent;
+use anyhow::{bail, Context, Result};
+use rand::{prelude::StdRng, Rng, SeedableRng};
+use std::{f32::consts::PI, fmt, str::FromStr};
+
+// The optional population model, e.g.
+// `energy=1,move-cost=0.02,trail-gain=0.5,split=2,capacity=200000`.
+//
+// Agents spawn with `energy`. Every step the kernels charge `move-cost` per
+// texel travelled and credit `trail-gain` / `nutrient-gain` per unit of trail
+// or nutrient eaten; agents at zero energy stop moving and depositing. Between
+// steps the host drops dead agents from the buffer and splits agents with at
+// least `split` energy in two, halving their energy, until the buffer holds
+// `capacity` agents (four times the initial population by default).
+// Offspring get their parent's color scaled per component by up to
+// `mutation`, and switch to a random species with probability
+// `species-mutation`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PopulationModel {
+    pub energy: f32,
+    pub move_cost: f32,
+    pub trail_gain: f32,
+    pub nutrient_gain: f32,
+    pub split: f32,
+    pub mutation: f32,
+    pub species_mutation: f32,
+    pub capacity: Option<u32>,
+}
+
+impl Default for PopulationModel {
+    fn default() -> Self {
+        Self {
+            energy: 1.0,
+            move_cost: 0.01,
+            trail_gain: 0.5,
+            nutrient_gain: 1.0,
+            split: 2.0,
+            mutation: 0.05,
+            species_mutation: 0.0,
+            capacity: None,
+        }
+    }
+}
+
+impl FromStr for PopulationModel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut model = Self::default();
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["population field {:?} should be key=value", field])?;
+            let invalid = || format!["invalid value for population field {:?}", key];
+
+            match key {
+                "energy" => model.energy = value.parse().with_context(invalid)?,
+                "move-cost" => model.move_cost = value.parse().with_context(invalid)?,
+                "trail-gain" => model.trail_gain = value.parse().with_context(invalid)?,
+                "nutrient-gain" => model.nutrient_gain = value.parse().with_context(invalid)?,
+                "split" => model.split = value.parse().with_context(invalid)?,
+                "mutation" => model.mutation = value.parse().with_context(invalid)?,
+                "species-mutation" => {
+                    model.species_mutation = value.parse().with_context(invalid)?
+                }
+                "capacity" => model.capacity = Some(value.parse().with_context(invalid)?),
+                _ => bail![
+                    "unknown population field {:?}, expected one of: energy, move-cost, \
+                     trail-gain, nutrient-gain, split, mutation, species-mutation, capacity",
+                    key
+                ],
+            }
+        }
+
+        if model.energy <= 0.0 {
+            bail!["population energy must be positive"];
+        }
+
+        if model.split <= 0.0 {
+            bail!["population split threshold must be positive"];
+        }
+
+        Ok(model)
+    }
+}
+
+impl fmt::Display for PopulationModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "energy={},move-cost={},trail-gain={},nutrient-gain={},split={},mutation={},\
+             species-mutation={}",
+            self.energy,
+            self.move_cost,
+            self.trail_gain,
+            self.nutrient_gain,
+            self.split,
+            self.mutation,
+            self.species_mutation
+        )?;
+
+        if let Some(capacity) = self.capacity {
+            write!(f, ",capacity={}", capacity)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PopulationModel {
+    pub fn capacity(&self, initial: u32) -> usize {
+        self.capacity
+            .map_or(initial as usize * 4, |capacity| capacity as usize)
+    }
+
+    // Compacts away dead agents and splits the ones above the threshold, and
+    // returns whether any did. Offspring take ids from `next_id` on. The
+    // random draws only depend on `seed` and `step`, so resuming from a
+    // snapshot reproduces them.
+    pub fn update(
+        &self,
+        agents: &mut Vec<Agent>,
+        capacity: usize,
+        species_count: u32,
+        seed: u32,
+        step: u64,
+        next_id: &mut u32,
+    ) -> bool {
+        let mut rng = StdRng::seed_from_u64(seed as u64 ^ step.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+
+        let before = agents.len();
+        agents.retain(|agent| agent.energy > 0.0);
+        let mut changed = agents.len() != before;
+
+        for i in 0..agents.len() {
+            if agents.len() >= capacity {
+                break;
+            }
+
+            if agents[i].energy < self.split {
+                continue;
+            }
+
+            let parent = &mut agents[i];
+            parent.energy *= 0.5;
+
+            let mut child = *parent;
+            let mut mutate =
+                |c: f32| (c * (1.0 + self.mutation * rng.gen_range(-1.0..=1.0))).max(0.0);
+            child.color.x = mutate(child.color.x);
+            child.color.y = mutate(child.color.y);
+            child.color.z = mutate(child.color.z);
+            child.heading = rng.gen::<f32>() * PI * 2.0;
+            child.rng_key = rng.gen();
+            child.id = *next_id;
+            *next_id = next_id.wrapping_add(1);
+
+            if species_count > 1 && rng.gen::<f32>() < self.species_mutation {
+                child.species = rng.gen_range(0..species_count);
+            }
+
+            agents.push(child);
+            changed = true;
+        }
+
+        changed
+    }
+}
+
+// 0 without agents.
+pub fn mean_energy(agents: &[Agent]) -> f32 {
+    if agents.is_empty() {
+        return 0.0;
+    }
+
+    agents.iter().map(|a| a.energy as f64).sum::<f64>() as f32 / agents.len() as f32
+}
@@ -0,0 +1,50 @@
+use anyhow::{bail, Result};
+use std::{fmt, str::FromStr};
+
+pub const SAMPLING_NEAREST: u32 = 0;
+pub const SAMPLING_ACCURATE: u32 = 1;
+
+// How agents read and write the fields.
+//
+// - nearest: sensors, eating and deposits use the texel under the position.
+// - accurate: sensors sample bilinearly and deposits are splatted bilinearly
+//   at unit steps along the segment the agent moved this step, so fast agents
+//   leave continuous trails. Eating still uses the texel under the agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    Nearest,
+    Accurate,
+}
+
+impl Sampling {
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Nearest => SAMPLING_NEAREST,
+            Self::Accurate => SAMPLING_ACCURATE,
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Self::Nearest),
+            "accurate" => Ok(Self::Accurate),
+            _ => bail![
+                "unknown sampling {:?}, expected one of: nearest, accurate",
+                s
+            ],
+        }
+    }
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Nearest => "nearest",
+            Self::Accurate => "accurate",
+        })
+    }
+}
@@ -0,0 +1,221 @@
+// TOML scene files, e.g.
+//
+//   width = 512
+//   height = 512
+//   seed = 7
+//   fixed_dt = 0.016
+//   spawn = { mode = "ring", inner = 40, outer = 60, heading = "tangent" }
+//   nutrient_color = [0, 1, 0]
+//
+//   [[species]]
+//   count = 5000
+//   sensor_angle_deg = 45
+//   color = [12, 0, 0]
+//
+// Keys are the command line setting names, with `_` or `-`. A value is
+// either what the command line takes, as a string or number, or a table for
+// the settings that take `key=value` lists, with the leading mode of specs
+// like `spawn` given as `mode`. Arrays of numbers are joined with `:` like
+// colors and interaction rows; arrays of strings or tables repeat a setting
+// such as `species`, `channel` or `food`.
+//
+// The file is turned into command line arguments, so it accepts exactly
+// what the command line does. Settings given on the command line replace
+// the file's, and settings in neither take their defaults.
+use crate::Settings;
+use anyhow::{bail, Context, Result};
+use std::{ffi::OsString, fs, iter, path::Path};
+use structopt::StructOpt;
+use toml::Value;
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// Scalars as they are, arrays of scalars joined with `:`.
+fn field_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .map(scalar)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(":")),
+        _ => scalar(value),
+    }
+}
+
+// A table becomes `mode,key=value,...`.
+fn spec(name: &str, value: &Value) -> Result<String> {
+    let table = match value {
+        Value::Table(table) => table,
+        _ => return field_value(value).with_context(|| format!["unsupported value for {}", name]),
+    };
+    let mut fields = vec![];
+
+    if let Some(mode) = table.get("mode") {
+        fields.push(scalar(mode).with_context(|| format!["{}.mode should be a string", name])?);
+    }
+
+    for (key, value) in table.iter().filter(|(key, _)| *key != "mode") {
+        let value = field_value(value)
+            .with_context(|| format!["unsupported value for {}.{}", name, key])?;
+        fields.push(format!["{}={}", key.replace('_', "-"), value]);
+    }
+
+    Ok(fields.join(","))
+}
+
+// The scene file's settings as (name, value) pairs.
+fn scene_args(path: &Path) -> Result<Vec<(String, String)>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!["failed to read scene file {:?}", path])?;
+    let table = match text
+        .parse::<Value>()
+        .with_context(|| format!["failed to parse scene file {:?}", path])?
+    {
+        Value::Table(table) => table,
+        _ => bail!["scene file {:?} should be a table", path],
+    };
+    let mut args = vec![];
+
+    for (key, value) in &table {
+        let name = key.replace('_', "-");
+        let repeated = match value {
+            Value::Array(values) => values
+                .iter()
+                .all(|v| matches!(v, Value::String(_) | Value::Table(_))),
+            _ => false,
+        };
+
+        match value {
+            Value::Array(values) if repeated => {
+                for value in values {
+                    args.push((name.clone(), spec(key, value)?));
+                }
+            }
+            _ => args.push((name, spec(key, value)?)),
+        }
+    }
+
+    Ok(args)
+}
+
+// Whether `args` (without the program name) set the setting `name`.
+fn given(args: &[OsString], name: &str) -> bool {
+    let flag = format!["--{}", name];
+
+    args.iter().filter_map(|arg| arg.to_str()).any(|arg| {
+        arg == flag
+            || arg
+                .strip_prefix(flag.as_str())
+                .is_some_and(|rest| rest.starts_with('='))
+    })
+}
+
+// The command line with the scene file's settings inserted in front of it,
+// minus the ones the command line sets itself. The file is checked on its
+// own first, so its errors name the file.
+pub fn merge_args(path: &Path, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let scene = scene_args(path)?;
+    let (program, rest) = args.split_first().context("missing program name")?;
+    let flags = scene
+        .iter()
+        .map(|(name, value)| OsString::from(format!["--{}={}", name, value]));
+
+    Settings::from_iter_safe(iter::once(program.clone()).chain(flags.clone()))
+        .with_context(|| format!["invalid scene file {:?}", path])?;
+
+    Ok(iter::once(program.clone())
+        .chain(
+            flags
+                .zip(&scene)
+                .filter(|(_, (name, _))| !given(rest, name))
+                .map(|(flag, _)| flag),
+        )
+        .chain(rest.iter().cloned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{spawn::SpawnMode, species::parse_color, test_util::TempFile};
+
+    const SCENE: &str = r#"
+        width = 512
+        height = 256
+        seed = 7
+        fixed_dt = 0.016
+        spawn = { mode = "ring", inner = 40, outer = 60 }
+        nutrient_color = [0, 1, 0]
+
+        [[species]]
+        count = 300
+        sensor_angle_deg = 45
+
+        [[species]]
+        count = 200
+        color = [12, 0, 0]
+    "#;
+
+    fn merged(name: &str, text: &str, args: &[&str]) -> Result<Settings> {
+        let file = TempFile::with_contents(&format!["{}.toml", name], text);
+        let args = iter::once("trails").chain(args.iter().copied());
+        let merged = merge_args(file.path(), args.map(OsString::from).collect())?;
+        Ok(Settings::from_iter_safe(merged)?)
+    }
+
+    #[test]
+    fn file_settings_fill_in_the_command_line() {
+        let settings = merged("fill", SCENE, &["--width=64", "--seed", "9"]).unwrap();
+
+        assert_eq!(settings.width, 64);
+        assert_eq!(settings.height, 256);
+        assert_eq!(settings.seed, 9);
+        assert_eq!(settings.fixed_dt, Some(0.016));
+        assert_eq!(settings.spawn, "ring,inner=40,outer=60".parse().unwrap());
+        assert_eq!(
+            settings.nutrient_color.to_array(),
+            parse_color("0:1:0").unwrap().to_array()
+        );
+        assert_eq!(settings.species.len(), 2);
+        assert_eq!(settings.species[0].sensor_angle_deg, Some(45.0));
+        assert_eq!(settings.species[1].count, Some(200));
+    }
+
+    #[test]
+    fn command_line_replaces_repeated_settings() {
+        let settings = merged("repeated", SCENE, &["--species=count=10"]).unwrap();
+
+        assert_eq!(settings.species.len(), 1);
+        assert_eq!(settings.species[0].count, Some(10));
+        assert!(matches!(settings.spawn, SpawnMode::Ring { .. }));
+    }
+
+    #[test]
+    fn errors_name_the_file() {
+        let error = |name, text| format!["{:#}", merged(name, text, &[]).unwrap_err()];
+
+        assert!(error("unknown", "widht = 5").contains("invalid scene file"));
+        assert!(error("nested", "width = [[1]]").contains("unsupported value for width"));
+        assert!(error("syntax", "width = ").contains("failed to parse scene file"));
+    }
+
+    #[test]
+    fn finds_given_settings() {
+        let args: Vec<OsString> = ["--width=5", "--seed", "3", "--height-scale=2"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+
+        assert!(given(&args, "width"));
+        assert!(given(&args, "seed"));
+        assert!(!given(&args, "height"));
+    }
+}
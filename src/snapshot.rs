@@ -0,0 +1,535 @@
+// Versioned binary snapshots of the full simulation state.
+//
+// Layout (all integers and floats little endian):
+//
+//   magic        b"TRAILSNP"
+//   version      u32
+//   settings     u32 byte length + UTF-8 `name=value` lines, one per setting,
+//                using the command line names. Repeated settings such as
+//                `species` and `channel` appear once per value. Settings that
+//                are missing take their defaults, so older snapshots keep
+//                loading when new settings are added. Images such as the
+//                obstacle map are stored by path and read again on load.
+//   clock        f64 time, u64 steps
+//   field        u32 width, u32 height, u32 channels
+//   agents       u32 count, u32 words per agent, then per agent the color,
+//                position and heading as f32, (since version 2) the species
+//                index as u32, (since version 4) the energy as f32, (since
+//                version 5) the random number key as u32, (since version 6)
+//                the steering state as f32 and (since version 7) the id as
+//                u32. Agents from older snapshots start with the population
+//                model's initial energy, keys derived from the seed, a zero
+//                steering state and their index as id.
+//   trails       width * height * channels f32 for the current buffer, then
+//                the same again for the scratch buffer. Texels are row-major
+//                with their channels interleaved.
+//   nutrient     (since version 3) u32 count, either zero or width * height,
+//                then that many f32. Older snapshots start with the nutrient
+//                field at capacity.
+use crate::{assign_rng_keys, species::format_color, Agent, Settings, Vec2, Vec4};
+use anyhow::{bail, Context, Result};
+use rand::{prelude::StdRng, SeedableRng};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+use structopt::StructOpt;
+
+const MAGIC: &[u8; 8] = b"TRAILSNP";
+const VERSION: u32 = 7;
+
+fn agent_words(version: u32) -> u32 {
+    match version {
+        1 => 7,
+        2 | 3 => 8,
+        4 => 9,
+        5 => 10,
+        6 => 11,
+        _ => 12,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub settings: Settings,
+    pub time: f64,
+    pub steps: u64,
+    pub agents: Vec<Agent>,
+    pub trail: Vec<f32>,
+    pub scratch_trail: Vec<f32>,
+    pub nutrient: Vec<f32>,
+}
+
+fn settings_to_text(settings: &Settings) -> String {
+    let mut fields = vec![
+        ("width", settings.width.to_string()),
+        ("height", settings.height.to_string()),
+        ("num-agents", settings.num_agents.to_string()),
+        ("steps-per-tick", settings.steps_per_tick.to_string()),
+        ("seed", settings.seed.to_string()),
+        ("agent-speed", settings.agent_speed.to_string()),
+        (
+            "agent-turn-rate-deg",
+            settings.agent_turn_rate_deg.to_string(),
+        ),
+        ("sensor-angle-deg", settings.sensor_angle_deg.to_string()),
+        ("sensor-offset", settings.sensor_offset.to_string()),
+        ("sensor-size", settings.sensor_size.to_string()),
+        ("same-color-weight", settings.same_color_weight.to_string()),
+        (
+            "different-color-weight",
+            settings.different_color_weight.to_string(),
+        ),
+        ("eat-weight", settings.eat_weight.to_string()),
+        ("trail-weight", settings.trail_weight.to_string()),
+        (
+            "exponential-decay-rate",
+            settings.exponential_decay_rate.to_string(),
+        ),
+        ("linear-decay-rate", settings.linear_decay_rate.to_string()),
+        ("diffuse-rate", settings.diffuse_rate.to_string()),
+        ("density", settings.density.to_string()),
+        ("spawn", settings.spawn.to_string()),
+        ("boundary", settings.boundary.to_string()),
+        ("diffusion", settings.diffusion.to_string()),
+        ("steering", settings.steering.to_string()),
+        ("sampling", settings.sampling.to_string()),
+        ("deposition", settings.deposition.to_string()),
+        ("agent-order", settings.agent_order.to_string()),
+        ("obstacle-weight", settings.obstacle_weight.to_string()),
+        ("nutrient-weight", settings.nutrient_weight.to_string()),
+        (
+            "nutrient-consume-rate",
+            settings.nutrient_consume_rate.to_string(),
+        ),
+        (
+            "nutrient-regrow-rate",
+            settings.nutrient_regrow_rate.to_string(),
+        ),
+        (
+            "nutrient-diffuse-rate",
+            settings.nutrient_diffuse_rate.to_string(),
+        ),
+        ("nutrient-color", format_color(settings.nutrient_color)),
+        ("time-scale", settings.time_scale.to_string()),
+    ];
+
+    if let Some(population) = &settings.population {
+        fields.push(("population", population.to_string()));
+    }
+
+    if let Some(fixed_dt) = settings.fixed_dt {
+        fields.push(("fixed-dt", fixed_dt.to_string()));
+    }
+
+    if let Some(obstacles) = &settings.obstacles {
+        fields.push(("obstacles", obstacles.display().to_string()));
+    }
+
+    if let Some(food_image) = &settings.food_image {
+        fields.push(("food-image", food_image.display().to_string()));
+    }
+
+    for food in &settings.food {
+        fields.push(("food", food.to_string()));
+    }
+
+    for species in &settings.species {
+        fields.push(("species", species.to_string()));
+    }
+
+    for channel in &settings.channels {
+        fields.push(("channel", channel.to_string()));
+    }
+
+    fields
+        .into_iter()
+        .map(|(name, value)| format!["{}={}\n", name, value])
+        .collect()
+}
+
+// The backend and its thread count are properties of the process, not of the
+// simulation state, so they are taken from the current command line rather
+// than the snapshot.
+fn settings_from_text(text: &str, current: &Settings) -> Result<Settings> {
+    let mut args = vec!["trails".to_string()];
+
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once('=')
+            .with_context(|| format!["malformed setting {:?} in snapshot", line])?;
+        // `--name=value` keeps negative values from parsing as flags.
+        args.push(format!["--{}={}", name, value]);
+    }
+
+    let mut settings = Settings::from_iter_safe(args)?;
+    settings.backend = current.backend;
+    settings.threads = current.threads;
+    Ok(settings)
+}
+
+struct Writer<W: Write>(W);
+
+impl<W: Write> Writer<W> {
+    fn u32(&mut self, v: u32) -> Result<()> {
+        Ok(self.0.write_all(&v.to_le_bytes())?)
+    }
+
+    fn u64(&mut self, v: u64) -> Result<()> {
+        Ok(self.0.write_all(&v.to_le_bytes())?)
+    }
+
+    fn f32(&mut self, v: f32) -> Result<()> {
+        Ok(self.0.write_all(&v.to_le_bytes())?)
+    }
+
+    fn f64(&mut self, v: f64) -> Result<()> {
+        Ok(self.0.write_all(&v.to_le_bytes())?)
+    }
+
+    fn vec4(&mut self, v: Vec4) -> Result<()> {
+        self.f32(v.x)?;
+        self.f32(v.y)?;
+        self.f32(v.z)?;
+        self.f32(v.w)
+    }
+}
+
+struct Reader<R: Read>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes()?))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes()?))
+    }
+
+    fn vec4(&mut self) -> Result<Vec4> {
+        Ok(Vec4 {
+            x: self.f32()?,
+            y: self.f32()?,
+            z: self.f32()?,
+            w: self.f32()?,
+        })
+    }
+}
+
+impl Snapshot {
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut w = Writer(BufWriter::new(File::create(path)?));
+        let settings = settings_to_text(&self.settings);
+
+        w.0.write_all(MAGIC)?;
+        w.u32(VERSION)?;
+        w.u32(settings.len() as u32)?;
+        w.0.write_all(settings.as_bytes())?;
+        w.f64(self.time)?;
+        w.u64(self.steps)?;
+        w.u32(self.settings.width)?;
+        w.u32(self.settings.height)?;
+        w.u32(self.settings.channel_count())?;
+        w.u32(self.agents.len() as u32)?;
+        w.u32(agent_words(VERSION))?;
+
+        for agent in &self.agents {
+            w.vec4(agent.color)?;
+            w.f32(agent.position.x)?;
+            w.f32(agent.position.y)?;
+            w.f32(agent.heading)?;
+            w.u32(agent.species)?;
+            w.f32(agent.energy)?;
+            w.u32(agent.rng_key)?;
+            w.f32(agent.steer_state)?;
+            w.u32(agent.id)?;
+        }
+
+        for value in self.trail.iter().chain(&self.scratch_trail) {
+            w.f32(*value)?;
+        }
+
+        w.u32(self.nutrient.len() as u32)?;
+
+        for value in &self.nutrient {
+            w.f32(*value)?;
+        }
+
+        w.0.flush()
+            .with_context(|| format!["failed to write snapshot {:?}", path])?;
+        Ok(())
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P, current: &Settings) -> Result<Self> {
+        let path = path.as_ref();
+        let mut r = Reader(BufReader::new(File::open(path)?));
+
+        if &r.bytes::<8>()? != MAGIC {
+            bail!["{:?} is not a trails snapshot", path];
+        }
+
+        let version = r.u32()?;
+
+        if version == 0 || version > VERSION {
+            bail![
+                "snapshot {:?} has version {}, this build reads versions 1 to {}",
+                path,
+                version,
+                VERSION
+            ];
+        }
+
+        let mut settings = vec![0; r.u32()? as usize];
+        r.0.read_exact(&mut settings)?;
+        let settings = settings_from_text(std::str::from_utf8(&settings)?, current)?;
+        let time = r.f64()?;
+        let steps = r.u64()?;
+        let (width, height, channels) = (r.u32()?, r.u32()?, r.u32()?);
+
+        let expected_channels = settings.channel_count();
+
+        if (width, height, channels) != (settings.width, settings.height, expected_channels) {
+            bail![
+                "snapshot {:?} has a {}x{}x{} trail field, expected {}x{}x{}",
+                path,
+                width,
+                height,
+                channels,
+                settings.width,
+                settings.height,
+                expected_channels
+            ];
+        }
+
+        let agent_count = r.u32()?;
+        let words = r.u32()?;
+
+        if words != agent_words(version) {
+            bail![
+                "snapshot {:?} stores {} words per agent, expected {}",
+                path,
+                words,
+                agent_words(version)
+            ];
+        }
+
+        let initial_energy = settings.population.map_or(0.0, |p| p.energy);
+        let mut agents = (0..agent_count)
+            .map(|i| {
+                Ok(Agent {
+                    color: r.vec4()?,
+                    position: Vec2 {
+                        x: r.f32()?,
+                        y: r.f32()?,
+                    },
+                    heading: r.f32()?,
+                    species: if version >= 2 { r.u32()? } else { 0 },
+                    energy: if version >= 4 {
+                        r.f32()?
+                    } else {
+                        initial_energy
+                    },
+                    rng_key: if version >= 5 { r.u32()? } else { 0 },
+                    steer_state: if version >= 6 { r.f32()? } else { 0.0 },
+                    id: if version >= 7 { r.u32()? } else { i },
+                })
+            })
+            .collect::<Result<Vec<Agent>>>()?;
+
+        if version < 5 {
+            assign_rng_keys(
+                &mut agents,
+                &mut StdRng::seed_from_u64(settings.seed as u64),
+            );
+        }
+
+        let values = width as usize * height as usize * channels as usize;
+        let trail = (0..values).map(|_| r.f32()).collect::<Result<Vec<_>>>()?;
+        let scratch_trail = (0..values).map(|_| r.f32()).collect::<Result<Vec<_>>>()?;
+        let nutrient = if version >= 3 {
+            let count = r.u32()?;
+
+            if count != 0 && count as usize != width as usize * height as usize {
+                bail![
+                    "snapshot {:?} has {} nutrient values, expected {}",
+                    path,
+                    count,
+                    width as usize * height as usize
+                ];
+            }
+
+            (0..count).map(|_| r.f32()).collect::<Result<Vec<_>>>()?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            settings,
+            time,
+            steps,
+            agents,
+            trail,
+            scratch_trail,
+            nutrient,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+    use std::fs;
+
+    fn settings(args: &[&str]) -> Settings {
+        Settings::from_iter_safe(["trails"].iter().chain(args)).unwrap()
+    }
+
+    fn agent(i: u32) -> Agent {
+        Agent {
+            color: Vec4::splat(i as f32),
+            position: Vec2 {
+                x: i as f32 + 0.5,
+                y: 1.25,
+            },
+            heading: 0.1 * i as f32,
+            species: i % 2,
+            energy: 2.0,
+            rng_key: 1000 + i,
+            steer_state: -1.5,
+            id: 7 * i,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = Snapshot {
+            settings: settings(&[
+                "--width=4",
+                "--height=2",
+                "--seed=3",
+                "--species=count=3,speed=20",
+                "--species=count=2,color=1:0:0",
+                "--channel=decay=0.5",
+                "--agent-order=hilbert,every=16",
+                "--fixed-dt=0.05",
+            ]),
+            time: 12.5,
+            steps: 250,
+            agents: (0..5).map(agent).collect(),
+            trail: (0..8).map(|i| i as f32).collect(),
+            scratch_trail: (0..8).map(|i| -(i as f32)).collect(),
+            nutrient: vec![],
+        };
+        let (first, second) = (TempFile::new("round-trip-1"), TempFile::new("round-trip-2"));
+
+        snapshot.write(first.path()).unwrap();
+        let read = Snapshot::read(first.path(), &settings(&[])).unwrap();
+        read.write(second.path()).unwrap();
+
+        assert!(fs::read(first.path()).unwrap() == fs::read(second.path()).unwrap());
+        assert_eq!(read.time, 12.5);
+        assert_eq!(read.steps, 250);
+        assert_eq!(read.settings.seed, 3);
+        assert_eq!(read.settings.fixed_dt, Some(0.05));
+        assert_eq!(read.agents[3].position.x, 3.5);
+        assert_eq!(read.agents[3].heading, 0.1 * 3.0);
+        assert_eq!(read.agents[3].species, 1);
+        assert_eq!(read.agents[3].energy, 2.0);
+        assert_eq!(read.agents[3].rng_key, 1003);
+        assert_eq!(read.agents[3].id, 21);
+        assert_eq!(read.settings.species.len(), 2);
+        assert_eq!(read.settings.channels.len(), 1);
+        assert_eq!(read.settings.agent_order.every, 16);
+        assert_eq!(read.scratch_trail, snapshot.scratch_trail);
+    }
+
+    // A version 1 snapshot: 7 words per agent and no nutrient section.
+    fn version_1(settings: &str, agents: u32) -> Vec<u8> {
+        let mut w = Writer(vec![]);
+
+        w.0.extend_from_slice(MAGIC);
+        w.u32(1).unwrap();
+        w.u32(settings.len() as u32).unwrap();
+        w.0.extend_from_slice(settings.as_bytes());
+        w.f64(3.0).unwrap();
+        w.u64(60).unwrap();
+        w.u32(2).unwrap();
+        w.u32(2).unwrap();
+        w.u32(4).unwrap();
+        w.u32(agents).unwrap();
+        w.u32(7).unwrap();
+
+        for i in 0..agents {
+            w.vec4(Vec4::splat(1.0)).unwrap();
+            w.f32(i as f32).unwrap();
+            w.f32(1.0).unwrap();
+            w.f32(0.5).unwrap();
+        }
+
+        for i in 0..2 * 2 * 2 * 4 {
+            w.f32(i as f32).unwrap();
+        }
+
+        w.0
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let file = TempFile::with_contents(
+            "version-1",
+            version_1("width=2\nheight=2\nseed=5\npopulation=energy=3\n", 3),
+        );
+        let read = Snapshot::read(file.path(), &settings(&[])).unwrap();
+
+        assert_eq!(read.steps, 60);
+        assert_eq!(read.settings.seed, 5);
+        assert_eq!(read.agents.len(), 3);
+        assert!(read.nutrient.is_empty());
+        assert_eq!(read.trail.len(), 16);
+        assert_eq!(read.scratch_trail[0], 16.0);
+
+        for (i, agent) in read.agents.iter().enumerate() {
+            assert_eq!(agent.position.x, i as f32);
+            assert_eq!(agent.species, 0);
+            assert_eq!(agent.energy, 3.0);
+            assert_eq!(agent.steer_state, 0.0);
+            assert_eq!(agent.id, i as u32);
+        }
+
+        assert_ne!(read.agents[0].rng_key, read.agents[1].rng_key);
+    }
+
+    #[test]
+    fn rejects_newer_versions_and_other_fields() {
+        let mut bytes = version_1("width=2\nheight=2\n", 0);
+        bytes[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let newer = TempFile::with_contents("newer", bytes);
+        let mismatched = TempFile::with_contents("mismatched", version_1("width=2\nheight=3\n", 0));
+
+        assert!(Snapshot::read(newer.path(), &settings(&[]))
+            .unwrap_err()
+            .to_string()
+            .contains(&format!["version {}", VERSION + 1]));
+        assert!(Snapshot::read(mismatched.path(), &settings(&[]))
+            .unwrap_err()
+            .to_string()
+            .contains("has a 2x2x4 trail field, expected 2x3x4"));
+    }
+}
@@ -0,0 +1,558 @@
+use crate::{
+    image::{read_gray_image, GrayImage},
+    Settings, Vec2,
+};
+use anyhow::{bail, Context, Result};
+use rand::{prelude::StdRng, Rng};
+use std::{
+    cmp,
+    f32::consts::PI,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingHeading {
+    Random,
+    Inward,
+    Outward,
+    Tangent,
+}
+
+impl FromStr for RingHeading {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "random" => Ok(Self::Random),
+            "inward" => Ok(Self::Inward),
+            "outward" => Ok(Self::Outward),
+            "tangent" => Ok(Self::Tangent),
+            _ => bail![
+                "unknown ring heading {:?}, expected one of: random, inward, outward, tangent",
+                s
+            ],
+        }
+    }
+}
+
+impl fmt::Display for RingHeading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Random => "random",
+            Self::Inward => "inward",
+            Self::Outward => "outward",
+            Self::Tangent => "tangent",
+        })
+    }
+}
+
+// Where new agents are placed, e.g. `ring,inner=40,outer=60,heading=tangent`.
+// Radii are in texels; unset radii derive from `--density` the same way as
+// the default disc.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpawnMode {
+    // Uniform angle and radius within `min(width, height) / density`.
+    Disc,
+    Uniform,
+    Ring {
+        inner: Option<f32>,
+        outer: Option<f32>,
+        heading: RingHeading,
+    },
+    Clusters {
+        count: u32,
+        radius: Option<f32>,
+    },
+    Grid,
+    // Density proportional to the brightness of a PGM/PPM image stretched
+    // over the field.
+    Mask(PathBuf),
+    // `x,y[,heading_deg]` per line. Rows are used in order and repeat when
+    // there are more agents than rows.
+    Csv(PathBuf),
+}
+
+impl FromStr for SpawnMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let mut options = vec![];
+
+        for field in fields {
+            options.push(
+                field
+                    .split_once('=')
+                    .with_context(|| format!["spawn field {:?} should be key=value", field])?,
+            );
+        }
+
+        let check_keys = |allowed: &[&str]| -> Result<()> {
+            for (key, _) in &options {
+                if !allowed.contains(key) {
+                    bail![
+                        "unknown field {:?} for spawn mode {:?}, expected one of: {}",
+                        key,
+                        mode,
+                        allowed.join(", ")
+                    ];
+                }
+            }
+            Ok(())
+        };
+        let value = |key: &str| options.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let number = |key: &str| -> Result<Option<f32>> {
+            value(key)
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| format!["invalid value for spawn field {:?}", key])
+                })
+                .transpose()
+        };
+        let path = |mode: &str| -> Result<PathBuf> {
+            value("path")
+                .map(PathBuf::from)
+                .with_context(|| format!["spawn mode {:?} needs a path=... field", mode])
+        };
+
+        match mode {
+            "disc" => check_keys(&[]).map(|_| Self::Disc),
+            "uniform" => check_keys(&[]).map(|_| Self::Uniform),
+            "ring" => {
+                check_keys(&["inner", "outer", "heading"])?;
+                Ok(Self::Ring {
+                    inner: number("inner")?,
+                    outer: number("outer")?,
+                    heading: value("heading").unwrap_or("random").parse()?,
+                })
+            }
+            "clusters" => {
+                check_keys(&["count", "radius"])?;
+                Ok(Self::Clusters {
+                    count: value("count")
+                        .map(|v| v.parse().context("invalid value for spawn field \"count\""))
+                        .transpose()?
+                        .unwrap_or(5),
+                    radius: number("radius")?,
+                })
+            }
+            "grid" => check_keys(&[]).map(|_| Self::Grid),
+            "mask" => {
+                check_keys(&["path"])?;
+                Ok(Self::Mask(path(mode)?))
+            }
+            "csv" => {
+                check_keys(&["path"])?;
+                Ok(Self::Csv(path(mode)?))
+            }
+            _ => bail![
+                "unknown spawn mode {:?}, expected one of: disc, uniform, ring, clusters, grid, mask, csv",
+                mode
+            ],
+        }
+    }
+}
+
+impl fmt::Display for SpawnMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disc => f.write_str("disc"),
+            Self::Uniform => f.write_str("uniform"),
+            Self::Ring {
+                inner,
+                outer,
+                heading,
+            } => {
+                f.write_str("ring")?;
+                if let Some(inner) = inner {
+                    write!(f, ",inner={}", inner)?;
+                }
+                if let Some(outer) = outer {
+                    write!(f, ",outer={}", outer)?;
+                }
+                write!(f, ",heading={}", heading)
+            }
+            Self::Clusters { count, radius } => {
+                write!(f, "clusters,count={}", count)?;
+                if let Some(radius) = radius {
+                    write!(f, ",radius={}", radius)?;
+                }
+                Ok(())
+            }
+            Self::Grid => f.write_str("grid"),
+            Self::Mask(path) => write!(f, "mask,path={}", path.display()),
+            Self::Csv(path) => write!(f, "csv,path={}", path.display()),
+        }
+    }
+}
+
+fn polar_to_rect(angle: f32, radius: f32) -> (f32, f32) {
+    let (x, y) = angle.sin_cos();
+    (x * radius, y * radius)
+}
+
+// A spawn position, and the heading if the mode dictates one.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub position: Vec2,
+    pub heading: Option<f32>,
+}
+
+// Resolved spawn mode: images and CSV files are loaded and cluster centers
+// drawn once, up front.
+pub struct Spawner {
+    width: f32,
+    height: f32,
+    radius: f32,
+    kind: SpawnerKind,
+}
+
+enum SpawnerKind {
+    Disc,
+    Uniform,
+    Ring {
+        inner: f32,
+        outer: f32,
+        heading: RingHeading,
+    },
+    Clusters {
+        centers: Vec<Vec2>,
+        radius: f32,
+    },
+    Grid {
+        columns: u32,
+        cell: Vec2,
+    },
+    Mask {
+        image: GrayImage,
+        cumulative: Vec<f32>,
+    },
+    Csv(Vec<Placement>),
+}
+
+impl Spawner {
+    pub fn new(settings: &Settings, total: u32, rng: &mut StdRng) -> Result<Self> {
+        let (width, height) = (settings.width as f32, settings.height as f32);
+        let radius = cmp::min(settings.width, settings.height) as f32 / settings.density;
+        let kind = match &settings.spawn {
+            SpawnMode::Disc => SpawnerKind::Disc,
+            SpawnMode::Uniform => SpawnerKind::Uniform,
+            SpawnMode::Ring {
+                inner,
+                outer,
+                heading,
+            } => {
+                let outer = outer.unwrap_or(radius);
+                let inner = inner.unwrap_or(outer * 0.8);
+
+                if !(0.0 <= inner && inner <= outer) {
+                    bail![
+                        "ring spawn needs 0 <= inner <= outer, got {} and {}",
+                        inner,
+                        outer
+                    ];
+                }
+
+                SpawnerKind::Ring {
+                    inner,
+                    outer,
+                    heading: *heading,
+                }
+            }
+            SpawnMode::Clusters { count, radius: r } => {
+                if *count == 0 {
+                    bail!["cluster spawn needs at least one cluster"];
+                }
+
+                let r = r.unwrap_or(radius / 4.0);
+                let centers = (0..*count)
+                    .map(|_| Vec2 {
+                        x: rng.gen::<f32>() * width,
+                        y: rng.gen::<f32>() * height,
+                    })
+                    .collect();
+
+                SpawnerKind::Clusters { centers, radius: r }
+            }
+            SpawnMode::Grid => {
+                let columns = ((total as f32 * width / height).sqrt().ceil() as u32).max(1);
+                let rows = total.div_ceil(columns).max(1);
+
+                SpawnerKind::Grid {
+                    columns,
+                    cell: Vec2 {
+                        x: width / columns as f32,
+                        y: height / rows as f32,
+                    },
+                }
+            }
+            SpawnMode::Mask(path) => {
+                let image = read_gray_image(path)?;
+                let cumulative: Vec<f32> = image
+                    .values
+                    .iter()
+                    .scan(0.0, |sum, v| {
+                        *sum += v.max(0.0);
+                        Some(*sum)
+                    })
+                    .collect();
+
+                if cumulative.last().is_none_or(|&total| total <= 0.0) {
+                    bail!["spawn mask {:?} is entirely black", path];
+                }
+
+                SpawnerKind::Mask { image, cumulative }
+            }
+            SpawnMode::Csv(path) => SpawnerKind::Csv(read_csv(path)?),
+        };
+
+        Ok(Self {
+            width,
+            height,
+            radius,
+            kind,
+        })
+    }
+
+    // Positions outside the field wrap around, like moving agents do.
+    pub fn place(&self, rng: &mut StdRng, index: u32) -> Placement {
+        let placement = self.place_unwrapped(rng, index);
+        let wrap = |x: f32, size: f32| x - size * (x / size).floor();
+
+        Placement {
+            position: Vec2 {
+                x: wrap(placement.position.x, self.width),
+                y: wrap(placement.position.y, self.height),
+            },
+            ..placement
+        }
+    }
+
+    fn place_unwrapped(&self, rng: &mut StdRng, index: u32) -> Placement {
+        let center = Vec2 {
+            x: self.width / 2.0,
+            y: self.height / 2.0,
+        };
+        let at = |position| Placement {
+            position,
+            heading: None,
+        };
+
+        match &self.kind {
+            SpawnerKind::Disc => {
+                let (px, py) = polar_to_rect(rng.gen::<f32>() * 2.0 * PI, rng.gen());
+                at(Vec2 {
+                    x: center.x + px * self.radius,
+                    y: center.y + py * self.radius,
+                })
+            }
+            SpawnerKind::Uniform => at(Vec2 {
+                x: rng.gen::<f32>() * self.width,
+                y: rng.gen::<f32>() * self.height,
+            }),
+            SpawnerKind::Ring {
+                inner,
+                outer,
+                heading,
+            } => {
+                // Uniform over the annulus area rather than the radius.
+                let angle = rng.gen::<f32>() * 2.0 * PI;
+                let t: f32 = rng.gen();
+                let r = (inner * inner + t * (outer * outer - inner * inner)).sqrt();
+                let (px, py) = polar_to_rect(angle, 1.0);
+                let outward = py.atan2(px);
+
+                Placement {
+                    position: Vec2 {
+                        x: center.x + px * r,
+                        y: center.y + py * r,
+                    },
+                    heading: match heading {
+                        RingHeading::Random => None,
+                        RingHeading::Outward => Some(outward),
+                        RingHeading::Inward => Some(outward + PI),
+                        RingHeading::Tangent => Some(outward + PI / 2.0),
+                    },
+                }
+            }
+            SpawnerKind::Clusters { centers, radius } => {
+                let c = centers[index as usize % centers.len()];
+                let (px, py) = polar_to_rect(rng.gen::<f32>() * 2.0 * PI, rng.gen::<f32>().sqrt());
+                at(Vec2 {
+                    x: c.x + px * radius,
+                    y: c.y + py * radius,
+                })
+            }
+            SpawnerKind::Grid { columns, cell } => at(Vec2 {
+                x: ((index % columns) as f32 + 0.5) * cell.x,
+                y: ((index / columns) as f32 + 0.5) * cell.y,
+            }),
+            SpawnerKind::Mask { image, cumulative } => {
+                let target = rng.gen::<f32>() * cumulative[cumulative.len() - 1];
+                let i = cumulative
+                    .partition_point(|&sum| sum <= target)
+                    .min(cumulative.len() - 1);
+                let (x, y) = (i as u32 % image.width, i as u32 / image.width);
+                let (jx, jy): (f32, f32) = (rng.gen(), rng.gen());
+                at(Vec2 {
+                    x: (x as f32 + jx) / image.width as f32 * self.width,
+                    y: (y as f32 + jy) / image.height as f32 * self.height,
+                })
+            }
+            SpawnerKind::Csv(rows) => {
+                let row = &rows[index as usize % rows.len()];
+                *row
+            }
+        }
+    }
+}
+
+fn read_csv(path: &Path) -> Result<Vec<Placement>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!["failed to read spawn list {:?}", path])?;
+    let mut rows = vec![];
+    let mut first = true;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let header_allowed = std::mem::replace(&mut first, false);
+        let columns = line.split(',').map(str::trim).collect::<Vec<_>>();
+        let parsed = columns
+            .iter()
+            .map(|c| c.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>();
+        let values = match parsed {
+            Ok(values) => values,
+            // Allow a header line.
+            Err(_) if header_allowed => continue,
+            Err(_) => bail![
+                "{:?} line {}: expected numbers, got {:?}",
+                path,
+                i + 1,
+                line
+            ],
+        };
+
+        match values[..] {
+            [x, y] => rows.push(Placement {
+                position: Vec2 { x, y },
+                heading: None,
+            }),
+            [x, y, heading_deg] => rows.push(Placement {
+                position: Vec2 { x, y },
+                heading: Some(heading_deg * PI / 180.0),
+            }),
+            _ => bail![
+                "{:?} line {}: expected x,y or x,y,heading_deg, got {} columns",
+                path,
+                i + 1,
+                values.len()
+            ],
+        }
+    }
+
+    if rows.is_empty() {
+        bail!["spawn list {:?} has no rows", path];
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempFile;
+    use rand::SeedableRng;
+    use structopt::StructOpt;
+
+    #[test]
+    fn parses_modes() {
+        let mode: SpawnMode = "ring,outer=60,heading=tangent".parse().unwrap();
+
+        assert_eq!(
+            mode,
+            SpawnMode::Ring {
+                inner: None,
+                outer: Some(60.0),
+                heading: RingHeading::Tangent,
+            }
+        );
+        assert_eq!(mode.to_string().parse::<SpawnMode>().unwrap(), mode);
+        assert_eq!(
+            "mask,path=food.pgm".parse::<SpawnMode>().unwrap(),
+            SpawnMode::Mask("food.pgm".into())
+        );
+
+        let error = |s: &str| s.parse::<SpawnMode>().unwrap_err().to_string();
+        assert!(error("csv").contains("needs a path=... field"));
+        assert!(error("mask,path=a.pgm,radius=2").contains("unknown field \"radius\""));
+        assert!(error("clusters,count=x").contains("invalid value"));
+    }
+
+    #[test]
+    fn reads_csv_rows() {
+        let file =
+            TempFile::with_contents("rows.csv", "x,y,heading\n\n# comment\n1.5, 2\n3,4,90\n");
+        let rows = read_csv(file.path()).unwrap();
+        let position = |i: usize| (rows[i].position.x, rows[i].position.y);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(position(0), (1.5, 2.0));
+        assert_eq!(rows[0].heading, None);
+        assert_eq!(position(1), (3.0, 4.0));
+        assert!((rows[1].heading.unwrap() - PI / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reports_bad_csv_lines() {
+        let error = |contents: &str| {
+            let file = TempFile::with_contents("bad.csv", contents);
+            read_csv(file.path()).unwrap_err().to_string()
+        };
+
+        assert!(error("1,2\n3,four\n").contains("line 2: expected numbers, got \"3,four\""));
+        assert!(error("1,2,3,4\n").contains("line 1: expected x,y or x,y,heading_deg, got 4"));
+        assert!(error("x,y\n# nothing else\n").contains("has no rows"));
+    }
+
+    fn mask_settings(mask: &TempFile, args: &[&str]) -> Settings {
+        let spawn = format!["--spawn=mask,path={}", mask.path().display()];
+        Settings::from_iter_safe(["trails", &spawn].iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn places_agents_on_the_mask() {
+        // Only the top right pixel of a 4x2 mask is lit, so on a 40x20 field
+        // every agent lands in the 10x10 texels it covers.
+        let mask = TempFile::with_contents("mask.pgm", "P2\n4 2\n255\n0 0 0 255\n0 0 0 0\n");
+        let settings = mask_settings(&mask, &["--width=40", "--height=20"]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let spawner = Spawner::new(&settings, 100, &mut rng).unwrap();
+
+        for i in 0..100 {
+            let position = spawner.place(&mut rng, i).position;
+            assert!((30.0..40.0).contains(&position.x), "{:?}", position);
+            assert!((0.0..10.0).contains(&position.y), "{:?}", position);
+        }
+    }
+
+    #[test]
+    fn rejects_a_black_mask() {
+        let mask = TempFile::with_contents("black.pgm", "P2\n2 1\n255\n0 0\n");
+        let settings = mask_settings(&mask, &[]);
+        let error = Spawner::new(&settings, 1, &mut StdRng::seed_from_u64(1))
+            .err()
+            .unwrap()
+            .to_string();
+
+        assert!(error.contains("is entirely black"));
+    }
+}
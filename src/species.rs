@@ -0,0 +1,259 @@
+use crate::{layout::gpu_struct, Constants, Settings, Vec4};
+use anyhow::{bail, Context, Result};
+use std::{f32::consts::PI, fmt, str::FromStr};
+
+// Per-species motion and sensing parameters, indexed by `Agent::species`.
+// Matches `struct Species` in shader/slime.hlsl. Parameters the species
+// doesn't set are flagged in `inherit` and read from the constants, so they
+// follow the global settings.
+gpu_struct! {
+    hlsl struct;
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub struct Species {
+        pub speed: f32,
+        pub turn_rate_rad: f32,
+        pub sensor_angle_rad: f32,
+        pub sensor_offset: f32,
+        pub sensor_size: i32,
+        pub inherit: u32,
+    }
+}
+
+pub const INHERIT_SPEED: u32 = 1;
+pub const INHERIT_TURN_RATE: u32 = 2;
+pub const INHERIT_SENSOR_ANGLE: u32 = 4;
+pub const INHERIT_SENSOR_OFFSET: u32 = 8;
+
+impl Species {
+    // The parameters with the inherited ones filled in from `constants`.
+    pub fn live(self, constants: &Constants) -> Species {
+        let pick = |flag: u32, own: f32, global: f32| {
+            if self.inherit & flag != 0 {
+                global
+            } else {
+                own
+            }
+        };
+
+        Species {
+            speed: pick(INHERIT_SPEED, self.speed, constants.agent_speed),
+            turn_rate_rad: pick(
+                INHERIT_TURN_RATE,
+                self.turn_rate_rad,
+                constants.agent_turn_rate_rad,
+            ),
+            sensor_angle_rad: pick(
+                INHERIT_SENSOR_ANGLE,
+                self.sensor_angle_rad,
+                constants.sensor_angle_rad,
+            ),
+            sensor_offset: pick(
+                INHERIT_SENSOR_OFFSET,
+                self.sensor_offset,
+                constants.sensor_offset,
+            ),
+            ..self
+        }
+    }
+}
+
+// One `--species` entry, e.g. `count=5000,speed=40,sensor-angle-deg=60,color=12:0:0`.
+// Parameters that are left out fall back to the global settings, and `count`
+// defaults to an even share of `--num-agents`. Without a color each agent
+// gets a random hue, like the single-species default. `deposit` and `attract`
+// are this species' rows of the interaction matrix, one value per
+// `--channel`, e.g. `deposit=1:0,attract=1:-2`.
+#[derive(Debug, Default, Clone)]
+pub struct SpeciesSpec {
+    pub count: Option<u32>,
+    pub speed: Option<f32>,
+    pub turn_rate_deg: Option<f32>,
+    pub sensor_angle_deg: Option<f32>,
+    pub sensor_offset: Option<f32>,
+    pub sensor_size: Option<u32>,
+    pub color: Option<Vec4>,
+    pub deposit: Option<Vec<f32>>,
+    pub attraction: Option<Vec<f32>>,
+}
+
+impl SpeciesSpec {
+    pub fn resolve(&self, settings: &Settings) -> Species {
+        let flag = |value: Option<f32>, flag: u32| if value.is_none() { flag } else { 0 };
+
+        Species {
+            speed: self.speed.unwrap_or(0.0),
+            turn_rate_rad: self.turn_rate_deg.unwrap_or(0.0) * PI / 180.0,
+            sensor_angle_rad: self.sensor_angle_deg.unwrap_or(0.0) * PI / 180.0,
+            sensor_offset: self.sensor_offset.unwrap_or(0.0),
+            sensor_size: self.sensor_size.unwrap_or(settings.sensor_size) as i32,
+            inherit: flag(self.speed, INHERIT_SPEED)
+                | flag(self.turn_rate_deg, INHERIT_TURN_RATE)
+                | flag(self.sensor_angle_deg, INHERIT_SENSOR_ANGLE)
+                | flag(self.sensor_offset, INHERIT_SENSOR_OFFSET),
+        }
+    }
+
+    // Replaces the fields that `other` sets.
+    pub fn overlay(&mut self, other: SpeciesSpec) {
+        self.count = other.count.or(self.count);
+        self.speed = other.speed.or(self.speed);
+        self.turn_rate_deg = other.turn_rate_deg.or(self.turn_rate_deg);
+        self.sensor_angle_deg = other.sensor_angle_deg.or(self.sensor_angle_deg);
+        self.sensor_offset = other.sensor_offset.or(self.sensor_offset);
+        self.sensor_size = other.sensor_size.or(self.sensor_size);
+        self.color = other.color.or(self.color);
+        self.deposit = other.deposit.or(self.deposit.take());
+        self.attraction = other.attraction.or(self.attraction.take());
+    }
+}
+
+pub fn parse_values(s: &str) -> Result<Vec<f32>> {
+    s.split(':')
+        .map(|c| c.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!["invalid value list {:?}", s])
+}
+
+fn format_values(values: &[f32]) -> String {
+    values
+        .iter()
+        .map(f32::to_string)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub fn parse_color(s: &str) -> Result<Vec4> {
+    match parse_values(s)?[..] {
+        [x, y, z] => Ok(Vec4 { x, y, z, w: 1.0 }),
+        [x, y, z, w] => Ok(Vec4 { x, y, z, w }),
+        _ => bail!["color {:?} should be r:g:b or r:g:b:a", s],
+    }
+}
+
+pub fn format_color(c: Vec4) -> String {
+    format!["{}:{}:{}:{}", c.x, c.y, c.z, c.w]
+}
+
+impl FromStr for SpeciesSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut spec = Self::default();
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["species field {:?} should be key=value", field])?;
+            let bad_value = || format!["invalid value for species field {:?}", key];
+
+            match key {
+                "count" => spec.count = Some(value.parse().with_context(bad_value)?),
+                "speed" => spec.speed = Some(value.parse().with_context(bad_value)?),
+                "turn-rate-deg" => {
+                    spec.turn_rate_deg = Some(value.parse().with_context(bad_value)?)
+                }
+                "sensor-angle-deg" => {
+                    spec.sensor_angle_deg = Some(value.parse().with_context(bad_value)?)
+                }
+                "sensor-offset" => {
+                    spec.sensor_offset = Some(value.parse().with_context(bad_value)?)
+                }
+                "sensor-size" => spec.sensor_size = Some(value.parse().with_context(bad_value)?),
+                "color" => spec.color = Some(parse_color(value)?),
+                "deposit" => spec.deposit = Some(parse_values(value)?),
+                "attract" => spec.attraction = Some(parse_values(value)?),
+                _ => bail![
+                    "unknown species field {:?}, expected one of: count, speed, turn-rate-deg, \
+                     sensor-angle-deg, sensor-offset, sensor-size, color, deposit, attract",
+                    key
+                ],
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+impl fmt::Display for SpeciesSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = vec![];
+
+        if let Some(v) = self.count {
+            fields.push(format!["count={}", v]);
+        }
+        if let Some(v) = self.speed {
+            fields.push(format!["speed={}", v]);
+        }
+        if let Some(v) = self.turn_rate_deg {
+            fields.push(format!["turn-rate-deg={}", v]);
+        }
+        if let Some(v) = self.sensor_angle_deg {
+            fields.push(format!["sensor-angle-deg={}", v]);
+        }
+        if let Some(v) = self.sensor_offset {
+            fields.push(format!["sensor-offset={}", v]);
+        }
+        if let Some(v) = self.sensor_size {
+            fields.push(format!["sensor-size={}", v]);
+        }
+        if let Some(c) = self.color {
+            fields.push(format!["color={}", format_color(c)]);
+        }
+        if let Some(v) = &self.deposit {
+            fields.push(format!["deposit={}", format_values(v)]);
+        }
+        if let Some(v) = &self.attraction {
+            fields.push(format!["attract={}", format_values(v)]);
+        }
+
+        f.write_str(&fields.join(","))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpeciesEntry {
+    pub params: Species,
+    pub count: u32,
+    pub color: Option<Vec4>,
+    pub deposit: Option<Vec<f32>>,
+    pub attraction: Option<Vec<f32>>,
+}
+
+// Resolves the species table and each species' population. With no
+// `--species` given there is a single species built from the global
+// settings, holding all `--num-agents` agents.
+pub fn species_table(settings: &Settings) -> Vec<SpeciesEntry> {
+    if settings.species.is_empty() {
+        return vec![SpeciesEntry {
+            params: SpeciesSpec::default().resolve(settings),
+            count: settings.num_agents,
+            color: None,
+            deposit: None,
+            attraction: None,
+        }];
+    }
+
+    let unassigned = settings
+        .species
+        .iter()
+        .filter(|s| s.count.is_none())
+        .count() as u32;
+    let assigned: u32 = settings.species.iter().filter_map(|s| s.count).sum();
+    let share = settings
+        .num_agents
+        .saturating_sub(assigned)
+        .checked_div(unassigned)
+        .unwrap_or(0);
+
+    settings
+        .species
+        .iter()
+        .map(|spec| SpeciesEntry {
+            params: spec.resolve(settings),
+            count: spec.count.unwrap_or(share),
+            color: spec.color,
+            deposit: spec.deposit.clone(),
+            attraction: spec.attraction.clone(),
+        })
+        .collect()
+}
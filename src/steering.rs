@@ -0,0 +1,178 @@
+use crate::Vec4;
+use anyhow::{bail, Context, Result};
+use std::{fmt, str::FromStr};
+
+pub const STEERING_SENSORS: u32 = 0;
+pub const STEERING_PROBABILISTIC: u32 = 1;
+pub const STEERING_GRADIENT: u32 = 2;
+pub const STEERING_RUN_AND_TUMBLE: u32 = 3;
+pub const STEERING_LEVY: u32 = 4;
+
+// How agents pick their heading, e.g. `run-and-tumble,tumble=2,memory=0.5`.
+//
+// - sensors: compare the front, left and right sensors and turn a full turn
+//   step towards the strongest, or randomly if the front is weakest.
+// - probabilistic: turn towards the stronger side sensor with a probability
+//   of `sharpness` times their normalized difference.
+// - gradient: estimate the field gradient around the front sensor from
+//   samples `spacing` texels apart and turn towards it, `gain` times the
+//   angle but at most one turn step.
+// - run-and-tumble: keep going straight, but tumble to a random heading
+//   `tumble` times per second, scaled by `bias` while the field under the
+//   agent is above its memory of it. The memory follows the field with a
+//   time constant of `memory` seconds.
+// - levy: where no sensor reads above `threshold`, explore in straight runs
+//   with a random heading and Pareto-distributed lengths of at least `min`
+//   texels and tail exponent `alpha`; elsewhere follow the sensor rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Steering {
+    Sensors,
+    Probabilistic {
+        sharpness: f32,
+    },
+    Gradient {
+        gain: f32,
+        spacing: f32,
+    },
+    RunAndTumble {
+        tumble: f32,
+        bias: f32,
+        memory: f32,
+    },
+    Levy {
+        alpha: f32,
+        min: f32,
+        threshold: f32,
+    },
+}
+
+impl Steering {
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Sensors => STEERING_SENSORS,
+            Self::Probabilistic { .. } => STEERING_PROBABILISTIC,
+            Self::Gradient { .. } => STEERING_GRADIENT,
+            Self::RunAndTumble { .. } => STEERING_RUN_AND_TUMBLE,
+            Self::Levy { .. } => STEERING_LEVY,
+        }
+    }
+
+    // The model's parameters in the order of the table above.
+    pub fn params(self) -> Vec4 {
+        let (x, y, z) = match self {
+            Self::Sensors => (0.0, 0.0, 0.0),
+            Self::Probabilistic { sharpness } => (sharpness, 0.0, 0.0),
+            Self::Gradient { gain, spacing } => (gain, spacing, 0.0),
+            Self::RunAndTumble {
+                tumble,
+                bias,
+                memory,
+            } => (tumble, bias, memory),
+            Self::Levy {
+                alpha,
+                min,
+                threshold,
+            } => (alpha, min, threshold),
+        };
+        Vec4 { x, y, z, w: 0.0 }
+    }
+}
+
+impl FromStr for Steering {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.split(',').map(str::trim).filter(|f| !f.is_empty());
+        let mode = fields.next().unwrap_or("");
+        let mut steering = match mode {
+            "sensors" => Self::Sensors,
+            "probabilistic" => Self::Probabilistic { sharpness: 4.0 },
+            "gradient" => Self::Gradient {
+                gain: 1.0,
+                spacing: 2.0,
+            },
+            "run-and-tumble" => Self::RunAndTumble {
+                tumble: 2.0,
+                bias: 0.1,
+                memory: 0.5,
+            },
+            "levy" => Self::Levy {
+                alpha: 1.5,
+                min: 5.0,
+                threshold: 0.0,
+            },
+            _ => bail![
+                "unknown steering {:?}, expected one of: sensors, probabilistic, gradient, \
+                 run-and-tumble, levy",
+                mode
+            ],
+        };
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["steering field {:?} should be key=value", field])?;
+            let value: f32 = value
+                .parse()
+                .with_context(|| format!["invalid value for steering field {:?}", key])?;
+
+            match (&mut steering, key) {
+                (Self::Probabilistic { sharpness }, "sharpness") => *sharpness = value,
+                (Self::Gradient { gain, .. }, "gain") => *gain = value,
+                (Self::Gradient { spacing, .. }, "spacing") => *spacing = value,
+                (Self::RunAndTumble { tumble, .. }, "tumble") => *tumble = value,
+                (Self::RunAndTumble { bias, .. }, "bias") => *bias = value,
+                (Self::RunAndTumble { memory, .. }, "memory") => *memory = value,
+                (Self::Levy { alpha, .. }, "alpha") => *alpha = value,
+                (Self::Levy { min, .. }, "min") => *min = value,
+                (Self::Levy { threshold, .. }, "threshold") => *threshold = value,
+                _ => bail!["unexpected field {:?} for steering {:?}", field, mode],
+            }
+        }
+
+        match steering {
+            Self::Gradient { spacing, .. } if spacing <= 0.0 => {
+                bail!["steering spacing must be positive"]
+            }
+            Self::RunAndTumble { memory, .. } if memory <= 0.0 => {
+                bail!["steering memory must be positive"]
+            }
+            Self::Levy { alpha, min, .. } if alpha <= 0.0 || min <= 0.0 => {
+                bail!["steering alpha and min must be positive"]
+            }
+            _ => Ok(steering),
+        }
+    }
+}
+
+impl fmt::Display for Steering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sensors => f.write_str("sensors"),
+            Self::Probabilistic { sharpness } => {
+                write!(f, "probabilistic,sharpness={}", sharpness)
+            }
+            Self::Gradient { gain, spacing } => {
+                write!(f, "gradient,gain={},spacing={}", gain, spacing)
+            }
+            Self::RunAndTumble {
+                tumble,
+                bias,
+                memory,
+            } => write!(
+                f,
+                "run-and-tumble,tumble={},bias={},memory={}",
+                tumble, bias, memory
+            ),
+            Self::Levy {
+                alpha,
+                min,
+                threshold,
+            } => write!(
+                f,
+                "levy,alpha={},min={},threshold={}",
+                alpha, min, threshold
+            ),
+        }
+    }
+}
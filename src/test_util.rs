@@ -0,0 +1,32 @@
+// Helpers shared by the unit tests.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+// A file in the temp directory that is removed again when dropped. The name
+// includes the process id so concurrent test runs don't share files.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!["trails-{}-{}", process::id(), name]))
+    }
+
+    pub fn with_contents(name: &str, contents: impl AsRef<[u8]>) -> Self {
+        let file = Self::new(name);
+        fs::write(file.path(), contents).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
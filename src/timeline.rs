@@ -0,0 +1,748 @@
+// A timeline file changes settings over simulated time, e.g.
+//
+//   [formulas]
+//   agent_speed = "40 * (1 + 0.5 * noise(t * 0.3))"
+//
+//   [keyframes]
+//   exponential_decay_rate = [
+//       { time = 0, value = 1 },
+//       { time = 60, value = 0.2, ease = "smooth" },
+//   ]
+//   sensor_angle_deg = [{ time = 20, value = 30 }, { time = 80, value = 60 }]
+//
+//   [[events]]
+//   time = 30
+//   action = "inject,count=2000,x=128,y=128,radius=24,species=1"
+//
+// Keyframes can drive any setting that can change while the simulation runs
+// (see `input::LIVE_SETTINGS`), named like on the command line with `_` or
+// `-`. A keyframe's `ease` is how the value gets there from the previous
+// keyframe: linear (the default), smooth, or step to hold the previous value
+// until the keyframe's time. Before the first keyframe and after the last the
+// value holds. A formula (see formula.rs) computes the value instead. A
+// setting takes either keyframes or a formula. Both are evaluated before
+// every step, so with a fixed step a rendered run is as reproducible as one
+// without a timeline. Formulas go straight into the step's constants, except
+// for `time_scale`, so they don't show up in the settings of a snapshot.
+//
+// Events fire once, before the first step at or after their time:
+//
+// - inject: `inject,count=N,x=X,y=Y[,radius=R,species=S]` adds agents within
+//   the radius, of species S or of random species.
+// - clear: `clear[,x=X,y=Y,radius=R]` clears the trail within the radius, or
+//   everywhere without one.
+// - species: `species,index=S,...` changes the fields of species S, e.g.
+//   `species,index=1,attract=1:-2,speed=40` to switch its weights. Takes the
+//   fields of `--species` except `count`.
+//
+// Times are seconds of simulated time. Resuming from a snapshot skips the
+// events before the snapshot's time.
+use crate::{
+    backend::SimulationBackend,
+    brush::{inject_agents, Brush, BrushMode},
+    channels::check_rows,
+    formula::{Formula, Vars},
+    input::{adjust, constant, is_live, Constant},
+    species::{species_table, SpeciesSpec},
+    Constants, Scene, Settings, Vec2,
+};
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path, str::FromStr};
+use toml::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ease {
+    Linear,
+    Smooth,
+    Step,
+}
+
+impl FromStr for Ease {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "linear" => Self::Linear,
+            "smooth" => Self::Smooth,
+            "step" => Self::Step,
+            _ => bail![
+                "unknown ease {:?}, expected one of: linear, smooth, step",
+                s
+            ],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Keyframe {
+    time: f64,
+    value: f32,
+    ease: Ease,
+}
+
+#[derive(Debug, Clone)]
+enum Driver {
+    // Sorted by time.
+    Keyframes(Vec<Keyframe>),
+    Formula(Formula),
+}
+
+#[derive(Debug, Clone)]
+struct Track {
+    setting: String,
+    driver: Driver,
+    // The value last written, so values set by hand hold until the track
+    // moves.
+    last: Option<f32>,
+    // Where a formula writes its value in the constants of each step, for
+    // settings the constants carry. Formulas change every step, so they
+    // bypass the settings.
+    constant: Option<Constant>,
+}
+
+impl Track {
+    fn value_at(&self, time: f64, vars: &Vars) -> Result<f32> {
+        match &self.driver {
+            Driver::Keyframes(keyframes) => Ok(interpolate(keyframes, time)),
+            Driver::Formula(formula) => {
+                let value = formula.eval(vars) as f32;
+
+                if !value.is_finite() {
+                    bail![
+                        "the formula for {} gave {} at t = {}: {}",
+                        self.setting,
+                        value,
+                        time,
+                        formula.source()
+                    ];
+                }
+
+                Ok(value)
+            }
+        }
+    }
+}
+
+fn interpolate(keyframes: &[Keyframe], time: f64) -> f32 {
+    let next = keyframes.partition_point(|k| k.time <= time);
+
+    if next == 0 {
+        return keyframes[0].value;
+    }
+
+    let from = keyframes[next - 1];
+    let to = match keyframes.get(next) {
+        Some(to) => to,
+        None => return from.value,
+    };
+    let t = ((time - from.time) / (to.time - from.time)) as f32;
+    let t = match to.ease {
+        Ease::Linear => t,
+        Ease::Smooth => t * t * (3.0 - 2.0 * t),
+        Ease::Step => 0.0,
+    };
+
+    from.value + (to.value - from.value) * t
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Inject {
+        count: u32,
+        center: Vec2,
+        radius: f32,
+        species: Option<u32>,
+    },
+    Clear {
+        center: Option<Vec2>,
+        radius: Option<f32>,
+    },
+    Species {
+        index: u32,
+        spec: SpeciesSpec,
+    },
+}
+
+impl Event {
+    // Whether the event refers to species that `settings` don't have or
+    // gives a species rows that don't fit the channels, so a bad event fails
+    // when the timeline loads rather than when it fires.
+    fn check(&self, settings: &Settings) -> Result<()> {
+        let species = species_table(settings).len() as u32;
+
+        match self {
+            Self::Inject {
+                species: Some(index),
+                ..
+            }
+            | Self::Species { index, .. }
+                if *index >= species =>
+            {
+                bail!["species {} out of range, there are {}", index, species]
+            }
+            Self::Species { index, spec } => check_rows(&overlay_species(settings, *index, spec)),
+            _ => Ok(()),
+        }
+    }
+
+    fn apply<B: SimulationBackend>(&self, scene: &mut Scene<B>) -> Result<()> {
+        let (width, height) = (scene.settings.width as f32, scene.settings.height as f32);
+
+        match self {
+            Self::Inject {
+                count,
+                center,
+                radius,
+                species,
+            } => inject_agents(scene, *center, *radius, *count, *species),
+            Self::Clear { center, radius } => Brush {
+                mode: BrushMode::Erase,
+                strength: None,
+                color: None,
+            }
+            .apply(
+                scene,
+                center.unwrap_or(Vec2 {
+                    x: width / 2.0,
+                    y: height / 2.0,
+                }),
+                radius.unwrap_or(width + height),
+                0.0,
+            ),
+            Self::Species { index, spec } => {
+                scene.update_settings(overlay_species(&scene.settings, *index, spec))
+            }
+        }
+    }
+}
+
+// `settings` with `spec` overlaid on species `index`.
+fn overlay_species(settings: &Settings, index: u32, spec: &SpeciesSpec) -> Settings {
+    let mut settings = settings.clone();
+
+    // Without `--species` the one species is made of the global settings.
+    if settings.species.is_empty() {
+        settings.species.push(SpeciesSpec::default());
+    }
+
+    settings.species[index as usize].overlay(spec.clone());
+    settings
+}
+
+fn field<T: FromStr>(fields: &[(&str, &str)], name: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    fields
+        .iter()
+        .find(|(key, _)| *key == name)
+        .map(|(key, value)| {
+            value
+                .parse()
+                .with_context(|| format!["invalid value for event field {:?}", key])
+        })
+        .transpose()
+}
+
+impl FromStr for Event {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (mode, rest) = s.split_once(',').unwrap_or((s, ""));
+        let mode = mode.trim();
+        let mut fields = vec![];
+        let mut species_fields = vec![];
+
+        for field in rest.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .with_context(|| format!["event field {:?} should be key=value", field])?;
+
+            match (mode, key) {
+                ("inject", "count" | "x" | "y" | "radius" | "species")
+                | ("clear", "x" | "y" | "radius")
+                | ("species", "index") => fields.push((key, value)),
+                ("species", "count") => bail!["the species event can't change `count`"],
+                ("species", _) => species_fields.push(field),
+                ("inject" | "clear", _) => {
+                    bail!["unexpected field {:?} for event {:?}", field, mode]
+                }
+                _ => bail![
+                    "unknown event {:?}, expected one of: inject, clear, species",
+                    mode
+                ],
+            }
+        }
+
+        let require = |name: &str| -> Result<f32> {
+            field(&fields, name)?.with_context(|| format!["event {:?} needs `{}`", mode, name])
+        };
+        let center = || -> Result<Vec2> {
+            Ok(Vec2 {
+                x: require("x")?,
+                y: require("y")?,
+            })
+        };
+
+        Ok(match mode {
+            "inject" => Self::Inject {
+                count: field(&fields, "count")?.context("event \"inject\" needs `count`")?,
+                center: center()?,
+                radius: field(&fields, "radius")?.unwrap_or(0.0),
+                species: field(&fields, "species")?,
+            },
+            "clear" => Self::Clear {
+                center: if fields.iter().any(|(key, _)| *key == "x" || *key == "y") {
+                    Some(center()?)
+                } else {
+                    None
+                },
+                radius: field(&fields, "radius")?,
+            },
+            "species" => Self::Species {
+                index: field(&fields, "index")?.context("event \"species\" needs `index`")?,
+                spec: species_fields.join(",").parse()?,
+            },
+            _ => bail![
+                "unknown event {:?}, expected one of: inject, clear, species",
+                mode
+            ],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    tracks: Vec<Track>,
+    // Sorted by time; events with the same time keep the file's order.
+    events: Vec<(f64, Event)>,
+    // The first event that hasn't fired.
+    next_event: usize,
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn keyframe(value: &Value) -> Result<Keyframe> {
+    let table = value
+        .as_table()
+        .context("a keyframe should be a table like { time = 0, value = 1 }")?;
+
+    for key in table.keys() {
+        if !["time", "value", "ease"].contains(&key.as_str()) {
+            bail![
+                "unknown keyframe field {:?}, expected one of: time, value, ease",
+                key
+            ];
+        }
+    }
+
+    Ok(Keyframe {
+        time: table
+            .get("time")
+            .and_then(number)
+            .context("a keyframe needs a number `time`")?,
+        value: table
+            .get("value")
+            .and_then(number)
+            .context("a keyframe needs a number `value`")? as f32,
+        ease: match table.get("ease") {
+            Some(ease) => ease
+                .as_str()
+                .context("a keyframe's `ease` should be a string")?
+                .parse()?,
+            None => Ease::Linear,
+        },
+    })
+}
+
+impl Timeline {
+    // Reads a timeline file and checks it against the settings it will run
+    // with.
+    pub fn load(path: &Path, settings: &Settings) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!["failed to read timeline file {:?}", path])?;
+        let parsed = text
+            .parse::<Value>()
+            .with_context(|| format!["failed to parse timeline file {:?}", path])?;
+
+        Self::from_value(&parsed, settings)
+            .with_context(|| format!["invalid timeline file {:?}", path])
+    }
+
+    fn from_value(value: &Value, settings: &Settings) -> Result<Self> {
+        let table = value.as_table().context("a timeline should be a table")?;
+        let mut timeline = Self::default();
+
+        for key in table.keys() {
+            if !["keyframes", "formulas", "events"].contains(&key.as_str()) {
+                bail![
+                    "unknown section {:?}, expected one of: keyframes, formulas, events",
+                    key
+                ];
+            }
+        }
+
+        if let Some(keyframes) = table.get("keyframes") {
+            let keyframes = keyframes
+                .as_table()
+                .context("`keyframes` should be a table of settings")?;
+
+            for (key, frames) in keyframes {
+                let setting = key.replace('_', "-");
+
+                if !is_live(&setting) {
+                    bail!["{:?} isn't a setting that can change while running", key];
+                }
+
+                let mut keyframes = frames
+                    .as_array()
+                    .with_context(|| format!["keyframes of {:?} should be an array", key])?
+                    .iter()
+                    .map(keyframe)
+                    .collect::<Result<Vec<_>>>()
+                    .with_context(|| format!["in keyframes of {:?}", key])?;
+
+                if keyframes.is_empty() {
+                    bail!["{:?} has no keyframes", key];
+                }
+
+                keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+                timeline.add_track(setting, Driver::Keyframes(keyframes))?;
+            }
+        }
+
+        if let Some(formulas) = table.get("formulas") {
+            let formulas = formulas
+                .as_table()
+                .context("`formulas` should be a table of settings")?;
+
+            for (key, formula) in formulas {
+                let setting = key.replace('_', "-");
+
+                if !is_live(&setting) {
+                    bail!["{:?} isn't a setting that can change while running", key];
+                }
+
+                let formula = formula
+                    .as_str()
+                    .with_context(|| format!["the formula for {:?} should be a string", key])?;
+                let formula = Formula::parse(formula)
+                    .with_context(|| format!["in the formula for {:?}", key])?;
+
+                timeline.add_track(setting, Driver::Formula(formula))?;
+            }
+        }
+
+        if let Some(events) = table.get("events") {
+            let events = events
+                .as_array()
+                .context("`events` should be an array of tables")?;
+
+            for (i, event) in events.iter().enumerate() {
+                let parse = || -> Result<(f64, Event)> {
+                    let time = event
+                        .get("time")
+                        .and_then(number)
+                        .context("an event needs a number `time`")?;
+                    let action = event
+                        .get("action")
+                        .and_then(Value::as_str)
+                        .context("an event needs a string `action`")?;
+                    let event: Event = action.parse()?;
+
+                    event.check(settings)?;
+                    Ok((time, event))
+                };
+
+                timeline
+                    .events
+                    .push(parse().with_context(|| format!["in event {}", i + 1])?);
+            }
+
+            timeline.events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+
+        Ok(timeline)
+    }
+
+    fn add_track(&mut self, setting: String, driver: Driver) -> Result<()> {
+        if self.tracks.iter().any(|track| track.setting == setting) {
+            bail!["{} is set more than once", setting];
+        }
+
+        let constant = match driver {
+            Driver::Formula(_) => constant(&setting),
+            Driver::Keyframes(_) => None,
+        };
+
+        self.tracks.push(Track {
+            setting,
+            driver,
+            last: None,
+            constant,
+        });
+        Ok(())
+    }
+
+    // Starts over at `time`: events before it count as fired and every
+    // track is written again.
+    pub fn seek(&mut self, time: f64) {
+        self.next_event = self.events.partition_point(|(t, _)| *t < time);
+
+        for track in &mut self.tracks {
+            track.last = None;
+        }
+    }
+
+    // `settings` with the tracks' values at `time`, if any changed.
+    fn settings_at(
+        &mut self,
+        time: f64,
+        vars: &Vars,
+        settings: &Settings,
+    ) -> Result<Option<Settings>> {
+        let mut changed = None;
+
+        for track in self.tracks.iter_mut().filter(|t| t.constant.is_none()) {
+            let value = track.value_at(time, vars)?;
+
+            if track.last != Some(value) {
+                let settings = changed.get_or_insert_with(|| settings.clone());
+
+                adjust(settings, &track.setting, |_| value);
+                track.last = Some(value);
+            }
+        }
+
+        Ok(changed)
+    }
+
+    // Writes the tracks that drive the constants directly.
+    fn drive(&self, time: f64, vars: &Vars, constants: &mut Constants) -> Result<()> {
+        for track in &self.tracks {
+            if let Some(constant) = track.constant {
+                constant(constants, track.value_at(time, vars)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Events due at `time` that haven't fired yet.
+    fn take_due(&mut self, time: f64) -> Vec<Event> {
+        let due = self.events[self.next_event..].partition_point(|(t, _)| *t <= time);
+        let events = self.events[self.next_event..][..due]
+            .iter()
+            .map(|(_, event)| event.clone())
+            .collect();
+
+        self.next_event += due;
+        events
+    }
+}
+
+fn vars<B: SimulationBackend>(scene: &Scene<B>, delta_time: f32) -> Vars {
+    Vars {
+        time: scene.clock.time,
+        delta_time: delta_time as f64,
+        step: scene.clock.steps as f64,
+        agents: scene.num_agents as f64,
+        initial_agents: scene.settings.total_agents() as f64,
+        mean_energy: scene.mean_energy as f64,
+        step_ms: scene.step_time.as_secs_f64() * 1000.0,
+        width: scene.settings.width as f64,
+        height: scene.settings.height as f64,
+    }
+}
+
+// Brings `scene` to the timeline's state at its current time, before a step
+// of `delta_time`.
+pub fn apply<B: SimulationBackend>(scene: &mut Scene<B>, delta_time: f32) -> Result<()> {
+    let time = scene.clock.time;
+    let vars = vars(scene, delta_time);
+
+    if let Some(settings) = scene.timeline.settings_at(time, &vars, &scene.settings)? {
+        scene.update_settings(settings)?;
+    }
+
+    for event in scene.timeline.take_due(time) {
+        event.apply(scene)?;
+    }
+
+    Ok(())
+}
+
+// Writes the formulas' values into the constants of the step `apply`
+// prepared.
+pub fn drive<B: SimulationBackend>(
+    scene: &Scene<B>,
+    delta_time: f32,
+    constants: &mut Constants,
+) -> Result<()> {
+    scene
+        .timeline
+        .drive(scene.clock.time, &vars(scene, delta_time), constants)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn settings(args: &[&str]) -> Settings {
+        Settings::from_iter_safe(["trails"].iter().chain(args)).unwrap()
+    }
+
+    fn timeline(text: &str, settings: &Settings) -> Result<Timeline> {
+        Timeline::from_value(&text.parse().unwrap(), settings)
+    }
+
+    fn error(text: &str, settings: &Settings) -> String {
+        format!["{:#}", timeline(text, settings).unwrap_err()]
+    }
+
+    #[test]
+    fn interpolates_keyframes() {
+        let keyframes = [
+            (0.0, 1.0, Ease::Linear),
+            (10.0, 3.0, Ease::Linear),
+            (20.0, 5.0, Ease::Smooth),
+            (30.0, 9.0, Ease::Step),
+        ]
+        .iter()
+        .map(|&(time, value, ease)| Keyframe { time, value, ease })
+        .collect::<Vec<_>>();
+        let at = |time| interpolate(&keyframes, time);
+
+        assert_eq!(at(-5.0), 1.0);
+        assert_eq!(at(0.0), 1.0);
+        assert_eq!(at(5.0), 2.0);
+        assert_eq!(at(15.0), 4.0);
+        assert_eq!(at(17.5), 4.6875);
+        assert_eq!(at(25.0), 5.0);
+        assert_eq!(at(30.0), 9.0);
+        assert_eq!(at(100.0), 9.0);
+    }
+
+    #[test]
+    fn writes_settings_when_a_track_moves() {
+        let settings = settings(&[]);
+        let mut timeline = timeline(
+            r#"
+            [keyframes]
+            sensor_angle_deg = [{ time = 20, value = 60 }, { time = 10, value = 30 }]
+            exponential-decay-rate = [{ time = 0, value = 0.5 }]
+            "#,
+            &settings,
+        )
+        .unwrap();
+        let vars = Vars::default();
+
+        let at_15 = timeline
+            .settings_at(15.0, &vars, &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(at_15.sensor_angle_deg, 45.0);
+        assert_eq!(at_15.exponential_decay_rate, 0.5);
+        assert!(timeline.settings_at(15.0, &vars, &at_15).unwrap().is_none());
+
+        let at_25 = timeline.settings_at(25.0, &vars, &at_15).unwrap().unwrap();
+        assert_eq!(at_25.sensor_angle_deg, 60.0);
+
+        timeline.seek(25.0);
+        assert!(timeline.settings_at(25.0, &vars, &at_25).unwrap().is_some());
+    }
+
+    #[test]
+    fn formulas_drive_the_constants() {
+        let settings = settings(&[]);
+        let timeline = timeline(
+            r#"
+            [formulas]
+            agent_speed = "10 + t"
+            time_scale = "2"
+            "#,
+            &settings,
+        )
+        .unwrap();
+        let mut constants = Constants::new(&settings, 0.0, 0.1);
+
+        timeline
+            .drive(5.0, &Vars::default(), &mut constants)
+            .unwrap();
+        assert_eq!(constants.agent_speed, 10.0);
+
+        // The time scale applies before the step, through the settings.
+        let time_scale = timeline.tracks.iter().find(|t| t.setting == "time-scale");
+        assert!(time_scale.unwrap().constant.is_none());
+    }
+
+    #[test]
+    fn fires_events_in_order_once() {
+        let settings = settings(&[]);
+        let mut timeline = timeline(
+            r#"
+            [[events]]
+            time = 20
+            action = "clear"
+
+            [[events]]
+            time = 5
+            action = "inject,count=10,x=1,y=2"
+
+            [[events]]
+            time = 20
+            action = "species,index=0,speed=40"
+            "#,
+            &settings,
+        )
+        .unwrap();
+
+        assert!(timeline.take_due(4.0).is_empty());
+        assert!(matches!(
+            timeline.take_due(5.0)[..],
+            [Event::Inject { count: 10, .. }]
+        ));
+        assert!(matches!(
+            timeline.take_due(30.0)[..],
+            [Event::Clear { .. }, Event::Species { index: 0, .. }]
+        ));
+        assert!(timeline.take_due(30.0).is_empty());
+
+        timeline.seek(20.0);
+        assert_eq!(timeline.take_due(20.0).len(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_timelines() {
+        let settings = settings(&["--channel=decay=0.5", "--channel=decay=0.2"]);
+
+        assert!(error("[camera]", &settings).contains("unknown section \"camera\""));
+        assert!(
+            error("[keyframes]\nwidth = [{ time = 0, value = 64 }]", &settings)
+                .contains("\"width\" isn't a setting that can change while running")
+        );
+        assert!(error(
+            "[keyframes]\nagent_speed = [{ time = 0, value = 1 }]\n\
+             [formulas]\nagent-speed = \"2\"",
+            &settings
+        )
+        .contains("agent-speed is set more than once"));
+        assert!(error(
+            "[[events]]\ntime = 1\naction = \"inject,count=1,x=0,y=0,species=1\"",
+            &settings
+        )
+        .contains("species 1 out of range, there are 1"));
+        assert!(error(
+            "[[events]]\ntime = 1\naction = \"species,index=0,attract=1:0:1\"",
+            &settings
+        )
+        .contains("species 0 has 3 `attract` values but there are only 2 channels"));
+    }
+}